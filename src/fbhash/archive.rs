@@ -0,0 +1,317 @@
+// Copyright 2021 -- 2023 Erwin van Eijk
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included
+// in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+// TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+// SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use crate::fbhash::metadata::DocumentMetadata;
+
+//
+// Members of an archive are named after the archive they are in, followed by
+// this separator and their path inside the archive, e.g.
+// `evidence.zip!/dir/file.doc`.
+//
+pub const ARCHIVE_SEPARATOR: &str = "!/";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveMember {
+    pub name: String,
+    pub size: u64,
+    pub modified: Option<u64>,
+}
+
+impl ArchiveMember {
+    pub fn metadata(&self) -> DocumentMetadata {
+        DocumentMetadata {
+            size: self.size,
+            modified: self.modified,
+            ..Default::default()
+        }
+    }
+}
+
+pub fn archive_kind(path: &Path) -> Option<ArchiveKind> {
+    let name = path.file_name()?.to_str()?.to_lowercase();
+    if name.ends_with(".zip") {
+        Some(ArchiveKind::Zip)
+    } else if name.ends_with(".tar") {
+        Some(ArchiveKind::Tar)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(ArchiveKind::TarGz)
+    } else {
+        None
+    }
+}
+
+pub fn virtual_path(archive: &Path, member: &str) -> String {
+    format!(
+        "{}{}{}",
+        archive.to_string_lossy(),
+        ARCHIVE_SEPARATOR,
+        member
+    )
+}
+
+// Split a virtual path into the path of the archive and the member name.
+pub fn split_virtual_path(name: &str) -> Option<(&str, &str)> {
+    name.find(ARCHIVE_SEPARATOR)
+        .map(|i| (&name[..i], &name[i + ARCHIVE_SEPARATOR.len()..]))
+}
+
+//
+// A tar archive that is cut short ends in the middle of a member without an
+// error, so a member that ends before its size is reached is an error here.
+//
+struct TarMemberReader<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Read> Read for TarMemberReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 && !buf.is_empty() && self.remaining > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "The archive ends {} bytes before this member does",
+                    self.remaining
+                ),
+            ));
+        }
+        self.remaining = self.remaining.saturating_sub(n as u64);
+        Ok(n)
+    }
+}
+
+fn for_each_tar_member<R, F>(reader: R, f: &mut F) -> io::Result<()>
+where
+    R: Read,
+    F: FnMut(&ArchiveMember, &mut dyn Read) -> io::Result<bool>,
+{
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let member = ArchiveMember {
+            name: entry.path()?.to_string_lossy().to_string(),
+            size: entry.header().size()?,
+            modified: entry.header().mtime().ok(),
+        };
+        let mut reader = TarMemberReader {
+            inner: &mut entry,
+            remaining: member.size,
+        };
+        if !f(&member, &mut reader)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn for_each_zip_member<F>(file: File, f: &mut F) -> io::Result<()>
+where
+    F: FnMut(&ArchiveMember, &mut dyn Read) -> io::Result<bool>,
+{
+    let mut archive = zip::ZipArchive::new(file)?;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        if !entry.is_file() {
+            continue;
+        }
+        // Zip keeps local time without a time zone, so there is no
+        // trustworthy modification time to record.
+        let member = ArchiveMember {
+            name: entry.name().to_string(),
+            size: entry.size(),
+            modified: None,
+        };
+        if !f(&member, &mut entry)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+//
+// Stream every regular file in the archive through f, one after the other.
+// When f returns false, the remaining members are not visited.
+//
+pub fn for_each_member<F>(path: &Path, mut f: F) -> io::Result<()>
+where
+    F: FnMut(&ArchiveMember, &mut dyn Read) -> io::Result<bool>,
+{
+    let file = File::open(path)?;
+    match archive_kind(path) {
+        Some(ArchiveKind::Zip) => for_each_zip_member(file, &mut f),
+        Some(ArchiveKind::Tar) => for_each_tar_member(file, &mut f),
+        Some(ArchiveKind::TarGz) => for_each_tar_member(GzDecoder::new(file), &mut f),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a known archive", path.to_string_lossy()),
+        )),
+    }
+}
+
+//
+// Look up a single member of an archive, and hand it to f.
+//
+pub fn with_member<T, F>(path: &Path, name: &str, f: F) -> io::Result<T>
+where
+    F: FnOnce(&ArchiveMember, &mut dyn Read) -> io::Result<T>,
+{
+    let mut f = Some(f);
+    let mut result = None;
+    for_each_member(path, |member, reader| {
+        if member.name == name {
+            let f = f.take().expect("Only called once");
+            result = Some(f(member, reader)?);
+            Ok(false)
+        } else {
+            Ok(true)
+        }
+    })?;
+    result.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} not found in {}", name, path.to_string_lossy()),
+        )
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    // Create an archive with the test files in it, as `data/<name>`.
+    pub fn create_test_archive(path: &Path) -> io::Result<()> {
+        let names = ["testfile-yes.bin", "testfile-zero.bin"];
+        match archive_kind(path) {
+            Some(ArchiveKind::Zip) => {
+                let mut writer = zip::ZipWriter::new(File::create(path)?);
+                writer.add_directory("data/", zip::write::SimpleFileOptions::default())?;
+                for name in names {
+                    writer.start_file(
+                        format!("data/{}", name),
+                        zip::write::SimpleFileOptions::default(),
+                    )?;
+                    writer.write_all(&fs::read(Path::new("testdata").join(name))?)?;
+                }
+                writer.finish()?;
+            }
+            _ => {
+                let mut builder = tar::Builder::new(File::create(path)?);
+                for name in names {
+                    builder.append_path_with_name(
+                        Path::new("testdata").join(name),
+                        format!("data/{}", name),
+                    )?;
+                }
+                builder.finish()?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_virtual_path() {
+        let name = virtual_path(Path::new("evidence.zip"), "dir/file.doc");
+        assert_eq!(name, "evidence.zip!/dir/file.doc");
+        assert_eq!(
+            split_virtual_path(&name),
+            Some(("evidence.zip", "dir/file.doc"))
+        );
+        assert_eq!(split_virtual_path("evidence.zip"), None);
+    }
+
+    #[test]
+    fn test_archive_kind() {
+        assert_eq!(archive_kind(Path::new("a.ZIP")), Some(ArchiveKind::Zip));
+        assert_eq!(archive_kind(Path::new("a.tar")), Some(ArchiveKind::Tar));
+        assert_eq!(archive_kind(Path::new("a.tgz")), Some(ArchiveKind::TarGz));
+        assert_eq!(archive_kind(Path::new("a.doc")), None);
+    }
+
+    #[test]
+    fn test_members() -> io::Result<()> {
+        let dir = tempdir()?;
+        for name in ["evidence.zip", "evidence.tar"] {
+            let path = dir.path().join(name);
+            create_test_archive(&path)?;
+            let mut members = Vec::new();
+            for_each_member(&path, |member, reader| {
+                let mut content = Vec::new();
+                reader.read_to_end(&mut content)?;
+                assert_eq!(content.len() as u64, member.size);
+                members.push(member.name.clone());
+                Ok(true)
+            })?;
+            assert_eq!(
+                members,
+                vec!["data/testfile-yes.bin", "data/testfile-zero.bin"]
+            );
+
+            let first_byte = with_member(&path, "data/testfile-yes.bin", |_, reader| {
+                let mut buffer = [0; 1];
+                reader.read_exact(&mut buffer)?;
+                Ok(buffer[0])
+            })?;
+            assert_eq!(first_byte, b'y');
+            let missing = with_member(&path, "data/missing", |_, _| Ok(()));
+            assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+        }
+        dir.close()
+    }
+    #[test]
+    fn test_truncated_tar() -> io::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("evidence.tar");
+        create_test_archive(&path)?;
+        // Cut the archive in the middle of the first member, after its header.
+        let content = fs::read(&path)?;
+        fs::write(&path, &content[..512 + 256])?;
+        let mut sizes = Vec::new();
+        let walked = for_each_member(&path, |_, reader| {
+            let mut content = Vec::new();
+            let read = reader.read_to_end(&mut content);
+            sizes.push(content.len());
+            read.map(|_| true)
+        });
+        assert_eq!(walked.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(sizes, vec![256]);
+        dir.close()
+    }
+}
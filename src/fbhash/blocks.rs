@@ -0,0 +1,217 @@
+// Copyright 2021 -- 2023 Erwin van Eijk
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included
+// in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+// TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+// SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use crate::fbhash::chunker::{fill_buffer, Chunk, ChunkContent, CHUNK_SIZE};
+use crate::fbhash::metadata::DocumentMetadata;
+
+//
+// Blocks are named after the input they are taken from, followed by this
+// separator and the offset of the block in bytes, e.g. `disk.dd@40960`.
+//
+pub const BLOCK_SEPARATOR: &str = "@";
+
+pub fn block_name(input: &Path, offset: u64) -> String {
+    format!("{}{}{}", input.to_string_lossy(), BLOCK_SEPARATOR, offset)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub offset: u64,
+    pub chunks: Vec<u64>,
+    pub metadata: DocumentMetadata,
+}
+
+fn hex_digest(hasher: Sha256) -> String {
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl Block {
+    fn new(offset: u64, size: usize, sha256: String) -> Block {
+        Block {
+            offset,
+            chunks: Vec::new(),
+            metadata: DocumentMetadata {
+                size: size as u64,
+                sha256,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn finish(mut self) -> Block {
+        self.metadata.number_of_chunks = self.chunks.len();
+        self
+    }
+}
+
+//
+// Slide over the input in blocks of block_size bytes, and hand every block to
+// f together with the chunks that start in it.
+//
+// The rolling hash is not restarted at the start of a block, so the chunks
+// that start at the end of one block and run into the next are the same as
+// they would be for the input as a whole. A block is handed over once all
+// chunks that start in it are complete, so at most two blocks are kept.
+//
+pub fn for_each_block<R, F>(mut reader: R, block_size: usize, mut f: F) -> io::Result<()>
+where
+    R: Read,
+    F: FnMut(Block) -> io::Result<()>,
+{
+    if block_size < CHUNK_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("The block size must be at least {} bytes", CHUNK_SIZE),
+        ));
+    }
+    let mut chunk_content = ChunkContent::new();
+    let mut window: Vec<u8> = Vec::with_capacity(CHUNK_SIZE);
+    let mut last_chunk: Option<Chunk> = None;
+    let mut open_blocks: VecDeque<Block> = VecDeque::new();
+    let mut buffer = vec![0; block_size];
+    let mut offset: u64 = 0;
+
+    loop {
+        let length = fill_buffer(&mut reader, &mut buffer)?;
+        if length == 0 {
+            break;
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(&buffer[..length]);
+        open_blocks.push_back(Block::new(offset, length, hex_digest(hasher)));
+        for byte in &buffer[..length] {
+            let chunk = match last_chunk {
+                Some(previous) => chunk_content.update(previous.digest, *byte),
+                None => {
+                    window.push(*byte);
+                    if window.len() < CHUNK_SIZE {
+                        continue;
+                    }
+                    chunk_content.setup(&window)
+                }
+            };
+            // The chunk number is its offset in the input.
+            let block_offset = (chunk.number / block_size * block_size) as u64;
+            open_blocks
+                .iter_mut()
+                .find(|block| block.offset == block_offset)
+                .expect("A chunk starts in a block that is still open")
+                .chunks
+                .push(chunk.digest);
+            last_chunk = Some(chunk);
+        }
+        // The first chunk is only known once CHUNK_SIZE bytes are read, after
+        // that every chunk of a block is complete when the next one is read.
+        while open_blocks.len() > 1 {
+            f(open_blocks.pop_front().unwrap().finish())?;
+        }
+        offset += length as u64;
+    }
+
+    // An input shorter than a chunk gets a single zero padded chunk, the same
+    // as the chunk iterator does for a short file.
+    if last_chunk.is_none() && !window.is_empty() {
+        window.resize(CHUNK_SIZE, 0);
+        let chunk = chunk_content.setup(&window);
+        if let Some(block) = open_blocks.front_mut() {
+            block.chunks.push(chunk.digest);
+        }
+    }
+    while let Some(block) = open_blocks.pop_front() {
+        f(block.finish())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fbhash::chunker::ChunkIterator;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use std::io::Cursor;
+
+    fn blocks_of(content: &[u8], block_size: usize) -> io::Result<Vec<Block>> {
+        let mut blocks = Vec::new();
+        for_each_block(Cursor::new(content), block_size, |block| {
+            blocks.push(block);
+            Ok(())
+        })?;
+        Ok(blocks)
+    }
+
+    #[test]
+    fn test_blocks_continue_the_rolling_hash() -> io::Result<()> {
+        let content = fs::read("testdata/testfile-yes.bin")?;
+        let expected: Vec<u64> = ChunkIterator::new(Cursor::new(&content))
+            .map(|c| c.map(|c| c.digest))
+            .collect::<io::Result<_>>()?;
+        for block_size in [7, 100, 512, 4096] {
+            let blocks = blocks_of(&content, block_size)?;
+            assert_eq!(blocks.len(), content.len().div_ceil(block_size));
+            let chunks: Vec<u64> = blocks.iter().flat_map(|b| b.chunks.clone()).collect();
+            assert_eq!(chunks, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_metadata() -> io::Result<()> {
+        let content = fs::read("testdata/testfile-yes.bin")?;
+        let blocks = blocks_of(&content, 500)?;
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].offset, 0);
+        assert_eq!(blocks[0].metadata.size, 500);
+        assert_eq!(blocks[0].metadata.number_of_chunks, 500);
+        assert_eq!(blocks[1].offset, 500);
+        assert_eq!(blocks[1].metadata.size, 12);
+        assert_eq!(blocks[1].metadata.number_of_chunks, 6);
+        assert_eq!(
+            block_name(Path::new("disk.dd"), blocks[1].offset),
+            "disk.dd@500"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_short_and_empty_input() -> io::Result<()> {
+        let blocks = blocks_of(b"yes", 512)?;
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].chunks.len(), 1);
+        assert!(blocks_of(b"", 512)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_size_too_small() {
+        let result = blocks_of(b"yes", CHUNK_SIZE - 1);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
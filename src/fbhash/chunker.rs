@@ -19,9 +19,10 @@
 // SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 use std::fs::File;
+use std::io;
 use std::io::Read;
 
-const CHUNK_SIZE: usize = 7;
+pub const CHUNK_SIZE: usize = 7;
 const A: u64 = 255;
 const MODULUS: u64 = 801385653117583579;
 
@@ -94,38 +95,74 @@ impl ChunkContent {
     }
 }
 
+//
+// Fill as much of the buffer as the file allows, a short file leaves the
+// remainder of the buffer zeroed.
+//
+pub fn fill_buffer<R: Read>(file: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
 #[derive(Debug)]
-pub struct ChunkIterator {
-    file: File,
+pub struct ChunkIterator<R = File> {
+    file: R,
     chunk_content: ChunkContent,
     // The option is None when not yet completed
     last_chunk: Option<Chunk>,
+    // Set after an error, there are no chunks after it.
+    failed: bool,
 }
 
-impl ChunkIterator {
+impl<R: Read> ChunkIterator<R> {
     #[allow(dead_code)]
-    pub fn new(file: File) -> ChunkIterator {
+    pub fn new(file: R) -> ChunkIterator<R> {
         ChunkIterator {
             file,
             chunk_content: ChunkContent::new(),
             last_chunk: None,
+            failed: false,
         }
     }
+
+    //
+    // Hand back the reader, for instance to get at what was computed
+    // while the chunks were read.
+    //
+    pub fn into_inner(self) -> R {
+        self.file
+    }
 }
 
-impl Iterator for ChunkIterator {
-    type Item = Chunk;
+//
+// A chunk for every position in the file. An error reading the file is handed
+// on instead of ending the chunks early, so that a part of a file is never
+// taken for all of it.
+//
+impl<R: Read> Iterator for ChunkIterator<R> {
+    type Item = io::Result<Chunk>;
 
-    fn next(&mut self) -> Option<Chunk> {
-        match self.last_chunk {
+    fn next(&mut self) -> Option<io::Result<Chunk>> {
+        if self.failed {
+            return None;
+        }
+        let next = match self.last_chunk {
             None => {
                 let mut initial_content = vec![0; CHUNK_SIZE];
-                match self.file.read(&mut initial_content) {
-                    Err(_) => None,
+                match fill_buffer(&mut self.file, &mut initial_content) {
+                    Err(e) => Some(Err(e)),
                     Ok(_) => {
                         let chunk = self.chunk_content.setup(&initial_content);
                         self.last_chunk = Some(chunk);
-                        Some(chunk)
+                        Some(Ok(chunk))
                     }
                 }
             }
@@ -138,21 +175,50 @@ impl Iterator for ChunkIterator {
                         let new_value = self.chunk_content.update(previous_digest, b[0]);
                         self.last_chunk = Some(new_value);
                         //let new_value = self.chunk_content.update(0, b[0]);
-                        Some(new_value)
+                        Some(Ok(new_value))
                     }
-                    Err(_) => None,
+                    Err(e) => Some(Err(e)),
                 }
             }
-        }
+        };
+        self.failed = matches!(next, Some(Err(_)));
+        next
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use super::ChunkIterator;
     use pretty_assertions::assert_eq;
     use std::fs::File;
     use std::io;
+    use std::io::Read;
+
+    // Fails every read, as a disk with a bad sector would.
+    pub struct BadSector;
+
+    impl Read for BadSector {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("bad sector"))
+        }
+    }
+
+    // Hands out a byte at a time, and is interrupted once before the first.
+    struct Trickle<R> {
+        inner: R,
+        interrupted: bool,
+    }
+
+    impl<R: Read> Read for Trickle<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if !self.interrupted {
+                self.interrupted = true;
+                return Err(io::Error::from(io::ErrorKind::Interrupted));
+            }
+            let length = buf.len().min(1);
+            self.inner.read(&mut buf[..length])
+        }
+    }
 
     #[test]
     fn test_litmus() {
@@ -168,7 +234,7 @@ mod tests {
     fn test_first_chunk_from_zero_file() -> io::Result<()> {
         let f = File::open("testdata/testfile-zero.bin")?;
         let mut chunk_iterator = ChunkIterator::new(f);
-        let chunk = chunk_iterator.next().unwrap();
+        let chunk = chunk_iterator.next().unwrap()?;
 
         assert_eq!(chunk.number, 0);
         assert_eq!(chunk.digest, 0);
@@ -179,7 +245,7 @@ mod tests {
     fn test_get_all_chunks_from_zero_file() -> io::Result<()> {
         let f = File::open("testdata/testfile-zero.bin")?;
         let chunk_iterator = ChunkIterator::new(f);
-        let chunks: Vec<_> = chunk_iterator.collect();
+        let chunks = chunk_iterator.collect::<io::Result<Vec<_>>>()?;
         assert_eq!(chunks.len(), 512 - 6);
         for (i, chunk) in chunks.iter().enumerate() {
             assert_eq!(chunk.number, i);
@@ -192,7 +258,7 @@ mod tests {
     fn test_zero_length_file() -> io::Result<()> {
         let f = File::open("testdata/testfile-zero-length")?;
         let chunk_iterator = ChunkIterator::new(f);
-        let chunks: Vec<_> = chunk_iterator.collect();
+        let chunks = chunk_iterator.collect::<io::Result<Vec<_>>>()?;
         assert_eq!(chunks.len(), 1);
         for (i, chunk) in chunks.iter().enumerate() {
             assert_eq!(chunk.number, i);
@@ -205,13 +271,13 @@ mod tests {
     fn test_get_three_chunks_from_yes_file() -> io::Result<()> {
         let f = File::open("testdata/testfile-yes.bin")?;
         let mut chunk_iterator = ChunkIterator::new(f);
-        let chunk0 = chunk_iterator.next().unwrap();
+        let chunk0 = chunk_iterator.next().unwrap()?;
         assert_eq!(chunk0.number, 0);
         assert_eq!(chunk0.digest, 33279275454869446);
-        let chunk1 = chunk_iterator.next().unwrap();
+        let chunk1 = chunk_iterator.next().unwrap()?;
         assert_eq!(chunk1.number, 1);
         assert_eq!(chunk1.digest, 2879926931474365);
-        let chunk2 = chunk_iterator.next().unwrap();
+        let chunk2 = chunk_iterator.next().unwrap()?;
         assert_eq!(chunk2.number, 2);
         assert_eq!(chunk2.digest, 33279275454869446);
         Ok(())
@@ -223,7 +289,7 @@ mod tests {
         // thus be alternating.
         let f = File::open("testdata/testfile-yes.bin")?;
         let chunk_iterator = ChunkIterator::new(f);
-        let chunks: Vec<_> = chunk_iterator.collect();
+        let chunks = chunk_iterator.collect::<io::Result<Vec<_>>>()?;
         assert_eq!(chunks.len(), 512 - 6);
         for (i, chunk) in chunks.iter().enumerate() {
             assert_eq!(chunk.number, i);
@@ -235,4 +301,34 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_first_chunk_from_short_reads() -> io::Result<()> {
+        let reader = Trickle {
+            inner: File::open("testdata/testfile-yes.bin")?,
+            interrupted: false,
+        };
+        let chunks = ChunkIterator::new(reader).collect::<io::Result<Vec<_>>>()?;
+        let f = File::open("testdata/testfile-yes.bin")?;
+        let expected = ChunkIterator::new(f).collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(chunks.len(), expected.len());
+        for (chunk, expected_chunk) in chunks.iter().zip(&expected) {
+            assert_eq!(chunk.number, expected_chunk.number);
+            assert_eq!(chunk.digest, expected_chunk.digest);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_error_is_passed_on() -> io::Result<()> {
+        let reader = File::open("testdata/testfile-yes.bin")?.chain(BadSector);
+        let chunks: Vec<_> = ChunkIterator::new(reader).collect();
+        assert_eq!(chunks.len(), 512 - 6 + 1);
+        assert!(chunks[..512 - 6].iter().all(|chunk| chunk.is_ok()));
+        assert!(chunks[512 - 6].is_err());
+        let chunks: Vec<_> = ChunkIterator::new(BadSector).collect();
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0].is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,527 @@
+// Copyright 2021 -- 2023 Erwin van Eijk
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included
+// in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+// TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+// SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use indicatif::ProgressBar;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::fbhash::index::{write_state, IndexWriter};
+use crate::fbhash::metadata::DocumentMetadata;
+use crate::fbhash::metrics::Cosine;
+use crate::fbhash::query::open_state_and_shards;
+use crate::fbhash::similarities::*;
+use crate::fbhash::utils::*;
+
+//
+// How the weights of a compact digest are stored: as 32 bit floats, or in 16
+// or 8 bits, spread evenly between the smallest and the largest weight of the
+// digest.
+//
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Quantization {
+    F32,
+    U16,
+    U8,
+}
+
+impl Quantization {
+    pub const NAMES: [&'static str; 3] = ["f32", "u16", "u8"];
+
+    fn bytes_per_weight(&self) -> usize {
+        match self {
+            Quantization::F32 => 4,
+            Quantization::U16 => 2,
+            Quantization::U8 => 1,
+        }
+    }
+
+    fn levels(&self) -> f64 {
+        match self {
+            Quantization::F32 => 0.,
+            Quantization::U16 => u16::MAX as f64,
+            Quantization::U8 => u8::MAX as f64,
+        }
+    }
+}
+
+impl FromStr for Quantization {
+    type Err = io::Error;
+
+    fn from_str(name: &str) -> io::Result<Quantization> {
+        match name {
+            "f32" => Ok(Quantization::F32),
+            "u16" => Ok(Quantization::U16),
+            "u8" => Ok(Quantization::U8),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown quantization {}", name),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Quantization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Quantization::F32 => "f32",
+            Quantization::U16 => "u16",
+            Quantization::U8 => "u8",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+//
+// The bytes are written as a hexadecimal string in json, and as they are in
+// the binary format.
+//
+mod hex_bytes {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+            serializer.serialize_str(&hex)
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            let hex = String::deserialize(deserializer)?;
+            if hex.len() % 2 != 0 {
+                return Err(D::Error::custom("odd number of hexadecimal digits"));
+            }
+            (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(D::Error::custom))
+                .collect()
+        } else {
+            Vec::<u8>::deserialize(deserializer)
+        }
+    }
+}
+
+fn write_varint(output: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        output.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+fn read_varint(input: &mut impl Iterator<Item = u8>) -> Option<u64> {
+    let mut value = 0_u64;
+    for shift in (0..64).step_by(7) {
+        let byte = input.next()?;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+//
+// A digest in less space: the chunks, which are sorted, as the varint encoded
+// differences between them, and the weights quantized.
+//
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CompactDigest {
+    quantization: Quantization,
+    minimum: f64,
+    maximum: f64,
+    #[serde(with = "hex_bytes")]
+    chunks: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    weights: Vec<u8>,
+}
+
+impl CompactDigest {
+    pub fn encode(digest: &[(u64, f64)], quantization: Quantization) -> CompactDigest {
+        let mut chunks = Vec::new();
+        let mut previous = 0;
+        for (chunk, _) in digest {
+            write_varint(&mut chunks, chunk - previous);
+            previous = *chunk;
+        }
+        let (minimum, maximum) = digest.iter().fold(
+            (f64::INFINITY, f64::NEG_INFINITY),
+            |(minimum, maximum), (_, w)| (minimum.min(*w), maximum.max(*w)),
+        );
+        let (minimum, maximum) = if digest.is_empty() {
+            (0., 0.)
+        } else {
+            (minimum, maximum)
+        };
+        let range = maximum - minimum;
+        let quantize = |weight: f64| -> u64 {
+            if range > 0. {
+                ((weight - minimum) / range * quantization.levels()).round() as u64
+            } else {
+                0
+            }
+        };
+        let mut weights = Vec::new();
+        for (_, weight) in digest {
+            match quantization {
+                Quantization::F32 => weights.extend_from_slice(&(*weight as f32).to_le_bytes()),
+                Quantization::U16 => {
+                    weights.extend_from_slice(&(quantize(*weight) as u16).to_le_bytes())
+                }
+                Quantization::U8 => weights.push(quantize(*weight) as u8),
+            }
+        }
+        CompactDigest {
+            quantization,
+            minimum,
+            maximum,
+            chunks,
+            weights,
+        }
+    }
+
+    //
+    // A damaged digest is an error: one with a chunk that is cut short or
+    // does not fit in 64 bits, or with another number of weights than chunks.
+    //
+    pub fn decode(&self) -> io::Result<Vec<(u64, f64)>> {
+        let invalid = |message: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid compact digest: {}", message),
+            )
+        };
+        let mut chunk_bytes = self.chunks.iter().copied().peekable();
+        let mut chunks = Vec::new();
+        let mut previous = 0_u64;
+        while chunk_bytes.peek().is_some() {
+            let delta = read_varint(&mut chunk_bytes)
+                .ok_or_else(|| invalid("a chunk is cut short".to_string()))?;
+            previous = previous
+                .checked_add(delta)
+                .ok_or_else(|| invalid("a chunk does not fit in 64 bits".to_string()))?;
+            chunks.push(previous);
+        }
+        if self.weights.len() != chunks.len() * self.quantization.bytes_per_weight() {
+            return Err(invalid(format!(
+                "{} chunks, but {} bytes of {} weights",
+                chunks.len(),
+                self.weights.len(),
+                self.quantization
+            )));
+        }
+        let range = self.maximum - self.minimum;
+        let dequantize =
+            |level: f64| -> f64 { self.minimum + level / self.quantization.levels() * range };
+        let weights: Vec<f64> = match self.quantization {
+            Quantization::F32 => self
+                .weights
+                .chunks_exact(4)
+                .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64)
+                .collect(),
+            Quantization::U16 => self
+                .weights
+                .chunks_exact(2)
+                .map(|bytes| dequantize(u16::from_le_bytes([bytes[0], bytes[1]]) as f64))
+                .collect(),
+            Quantization::U8 => self
+                .weights
+                .iter()
+                .map(|byte| dequantize(*byte as f64))
+                .collect(),
+        };
+        Ok(chunks.into_iter().zip(weights).collect())
+    }
+}
+
+//
+// How a document is stored in a compact database.
+//
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CompactDocument {
+    pub file: String,
+    pub digest: CompactDigest,
+    pub metadata: DocumentMetadata,
+}
+
+impl CompactDocument {
+    pub fn new(document: &Document, quantization: Quantization) -> CompactDocument {
+        CompactDocument {
+            file: document.file.clone(),
+            digest: CompactDigest::encode(&document.digest, quantization),
+            metadata: document.metadata.clone(),
+        }
+    }
+
+    pub fn into_document(self) -> io::Result<Document> {
+        Ok(Document {
+            file: self.file,
+            chunks: Vec::new(),
+            digest: self.digest.decode()?,
+            metadata: self.metadata,
+        })
+    }
+}
+
+// The largest difference between a weight and its quantized form.
+pub fn largest_weight_error(full: &[Document], compact: &[Document]) -> f64 {
+    full.iter()
+        .zip(compact)
+        .flat_map(|(a, b)| a.digest.iter().zip(&b.digest))
+        .map(|((_, a), (_, b))| (a - b).abs())
+        .fold(0., f64::max)
+}
+
+//
+// How much the results of the compact digests agree with those of the full
+// ones: for the first sample documents, the share of the k best matches by
+// the full digests that is also found by the compact digests.
+//
+pub fn ranking_agreement(full: &[Document], compact: &[Document], sample: usize, k: usize) -> f64 {
+    let best = |query: &[(u64, f64)], documents: &[Document]| -> Vec<String> {
+        ranked_search(query, documents, k, &ProgressBar::hidden(), &Cosine)
+            .into_iter()
+            .map(|(_, document)| document.file)
+            .collect()
+    };
+    let agreements: Vec<f64> = full
+        .par_iter()
+        .zip(compact)
+        .take(sample)
+        .map(|(full_query, compact_query)| {
+            let expected = best(&full_query.digest, full);
+            let found = best(&compact_query.digest, compact);
+            if expected.is_empty() {
+                1.
+            } else {
+                let shared = expected.iter().filter(|file| found.contains(file)).count();
+                shared as f64 / expected.len() as f64
+            }
+        })
+        .collect();
+    if agreements.is_empty() {
+        1.
+    } else {
+        agreements.iter().fold(0., |sum, agreement| sum + agreement) / agreements.len() as f64
+    }
+}
+
+fn database_size(
+    documents: &[Document],
+    quantization: Option<Quantization>,
+    config: &Configuration,
+) -> usize {
+    let size = |document: &Document| -> usize {
+        match (quantization, config.output_format) {
+            (None, OutputFormat::Json) => serde_json::to_vec(document).unwrap().len() + 1,
+            (None, OutputFormat::Binary) => bincode::serialized_size(document).unwrap() as usize,
+            (Some(quantization), OutputFormat::Json) => {
+                serde_json::to_vec(&CompactDocument::new(document, quantization))
+                    .unwrap()
+                    .len()
+                    + 1
+            }
+            (Some(quantization), OutputFormat::Binary) => {
+                bincode::serialized_size(&CompactDocument::new(document, quantization)).unwrap()
+                    as usize
+            }
+        }
+    };
+    documents.par_iter().map(size).sum()
+}
+
+//
+// Store the digests of an existing index in the compact form, and show how
+// much smaller the database gets, and how well the results agree with those
+// of the digests as they were.
+//
+pub fn compact_for_results(
+    state_path: &PathBuf,
+    database_path: &Path,
+    quantization: Quantization,
+    sample: usize,
+    dry_run: bool,
+    config: &Configuration,
+) -> io::Result<()> {
+    let (mut document_collection, shards) =
+        open_state_and_shards(state_path, database_path, config)?;
+    let sizes: Vec<usize> = shards.iter().map(Vec::len).collect();
+    let documents: Vec<Document> = shards.into_iter().flatten().collect();
+    let compact: Vec<Document> = documents
+        .par_iter()
+        .map(|document| CompactDocument::new(document, quantization).into_document())
+        .collect::<io::Result<Vec<Document>>>()?;
+    println!("Quantization: {}", quantization);
+    println!(
+        "Database size: {} bytes, compact: {} bytes",
+        database_size(
+            &documents,
+            document_collection.get_digest_encoding(),
+            config
+        ),
+        database_size(&documents, Some(quantization), config)
+    );
+    println!(
+        "Largest weight error: {}",
+        largest_weight_error(&documents, &compact)
+    );
+    println!(
+        "Agreement of the top 5 results: {} (over {} documents)",
+        ranking_agreement(&documents, &compact, sample, 5),
+        sample.min(documents.len())
+    );
+    if !dry_run {
+        document_collection.set_digest_encoding(Some(quantization));
+        write_state(&document_collection, state_path, config)?;
+        // Every document goes back to the shard it was read from.
+        let mut writer = IndexWriter::rewrite(database_path, &document_collection, config)?;
+        let mut documents = documents.iter();
+        for (shard, size) in sizes.into_iter().enumerate() {
+            for document in documents.by_ref().take(size) {
+                writer.write(shard, document)?;
+            }
+        }
+        writer.finish(config)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_varint() {
+        for value in [0, 1, 127, 128, 300, u64::MAX] {
+            let mut bytes = Vec::new();
+            write_varint(&mut bytes, value);
+            assert_eq!(read_varint(&mut bytes.into_iter()), Some(value));
+        }
+        assert_eq!(read_varint(&mut vec![0x80].into_iter()), None);
+    }
+
+    #[test]
+    fn test_compact_digest() {
+        let digest = vec![(3, -1.5), (1000, 0.25), (u64::MAX, 2.0)];
+        let f32_digest = CompactDigest::encode(&digest, Quantization::F32);
+        assert_eq!(f32_digest.decode().unwrap(), digest);
+        for (quantization, epsilon) in [(Quantization::U16, 0.0001), (Quantization::U8, 0.01)] {
+            let decoded = CompactDigest::encode(&digest, quantization)
+                .decode()
+                .unwrap();
+            assert_eq!(decoded.len(), digest.len());
+            for ((chunk, weight), (expected_chunk, expected_weight)) in decoded.iter().zip(&digest)
+            {
+                assert_eq!(chunk, expected_chunk);
+                assert!(approx_eq!(
+                    f64,
+                    *weight,
+                    *expected_weight,
+                    epsilon = epsilon
+                ));
+            }
+        }
+        assert!(CompactDigest::encode(&[], Quantization::U8)
+            .decode()
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            CompactDigest::encode(&[(1, 0.5), (2, 0.5)], Quantization::U8)
+                .decode()
+                .unwrap(),
+            vec![(1, 0.5), (2, 0.5)]
+        );
+    }
+
+    #[test]
+    fn test_damaged_compact_digest() {
+        let digest = CompactDigest::encode(&[(u64::MAX, 1.0)], Quantization::U8);
+        let mut overflow = digest.clone();
+        overflow.chunks.push(1);
+        overflow.weights.push(0);
+        let mut cut_short = digest.clone();
+        cut_short.chunks.push(0x80);
+        let mut extra_weight = digest.clone();
+        extra_weight.weights.push(0);
+        let mut missing_weight = CompactDigest::encode(&[(1, 1.0)], Quantization::F32);
+        missing_weight.weights.pop();
+        for damaged in [overflow, cut_short, extra_weight, missing_weight] {
+            assert_eq!(
+                damaged.decode().unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+        }
+    }
+
+    #[test]
+    fn test_compact_document_serialization() {
+        let document = Document {
+            file: "a".to_string(),
+            chunks: Vec::new(),
+            digest: vec![(1, 1.0), (300, 0.5)],
+            metadata: Default::default(),
+        };
+        let compact = CompactDocument::new(&document, Quantization::U8);
+        let json = serde_json::to_string(&compact).unwrap();
+        assert!(json.contains("\"chunks\":\"01ab02\""), "{}", json);
+        assert_eq!(
+            serde_json::from_str::<CompactDocument>(&json).unwrap(),
+            compact
+        );
+        let binary = bincode::serialize(&compact).unwrap();
+        assert_eq!(
+            bincode::deserialize::<CompactDocument>(&binary).unwrap(),
+            compact
+        );
+        assert_eq!(compact.into_document().unwrap().digest, document.digest);
+    }
+
+    #[test]
+    fn test_ranking_agreement() {
+        let documents: Vec<Document> = (0..4)
+            .map(|i| Document {
+                file: i.to_string(),
+                chunks: Vec::new(),
+                digest: vec![(i, 1.0), (i + 1, 0.5)],
+                metadata: Default::default(),
+            })
+            .collect();
+        assert!(approx_eq!(
+            f64,
+            ranking_agreement(&documents, &documents, 10, 2),
+            1.0,
+            ulps = 2
+        ));
+        assert!(approx_eq!(
+            f64,
+            largest_weight_error(&documents, &documents),
+            0.0,
+            ulps = 2
+        ));
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright 2021 -- 2023 Erwin van Eijk
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included
+// in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+// TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+// SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use rayon::prelude::*;
+use std::io;
+use std::path::PathBuf;
+
+use crate::fbhash::metrics::MetricKind;
+use crate::fbhash::query::read_state;
+use crate::fbhash::report::FileOutcome;
+use crate::fbhash::similarities::*;
+use crate::fbhash::utils::*;
+use crate::fbhash::walker::{Walker, WalkerOptions};
+
+//
+// How the chunks of the compared files are weighed. Either by the inverse
+// document frequency of an existing collection, or the same for every chunk,
+// so the score only depends on the two files themselves.
+//
+pub enum Weighting {
+    Uniform,
+    Collection(DocumentCollection),
+}
+
+impl Weighting {
+    pub fn from_state(
+        state_path: Option<&PathBuf>,
+        config: &Configuration,
+    ) -> io::Result<Weighting> {
+        match state_path {
+            Some(state_path) => Ok(Weighting::Collection(read_state(state_path, config)?)),
+            None => Ok(Weighting::Uniform),
+        }
+    }
+
+    pub fn compute_document(&self, name: &str) -> io::Result<Document> {
+        let (document, _) = compute_document(name)?;
+        let digest = match self {
+            Weighting::Uniform => compute_uniform_digest(&document.chunks),
+            Weighting::Collection(document_collection) => {
+                document_collection.compute_document_digest(&document.chunks)
+            }
+        };
+        Ok(Document {
+            digest,
+            chunks: Vec::new(),
+            ..document
+        })
+    }
+}
+
+pub fn compare_files(
+    file_a: &str,
+    file_b: &str,
+    weighting: &Weighting,
+    metric_kind: MetricKind,
+) -> io::Result<f64> {
+    let documents = [
+        weighting.compute_document(file_a)?,
+        weighting.compute_document(file_b)?,
+    ];
+    let metric = metric_kind.build(&documents);
+    Ok(metric.score(&documents[0].digest, &documents[1].digest))
+}
+
+//
+// The documents of the files in the directory, and the outcomes of the
+// entries that could not be compared, e.g. because they could not be read.
+//
+fn documents_in_directory(
+    directory: &PathBuf,
+    weighting: &Weighting,
+) -> io::Result<(Vec<Document>, Vec<FileOutcome>)> {
+    let (mut files, mut failed) =
+        Walker::new(&WalkerOptions::default())?.get_files_from_dir(directory);
+    files.sort();
+    let results: Vec<Result<Document, FileOutcome>> = files
+        .par_iter()
+        .map(|file| {
+            weighting
+                .compute_document(&file.to_string_lossy())
+                .map_err(|e| FileOutcome::from_error(file, &e))
+        })
+        .collect();
+    let mut documents = Vec::new();
+    for result in results {
+        match result {
+            Ok(document) => documents.push(document),
+            Err(outcome) => failed.push(outcome),
+        }
+    }
+    Ok((documents, failed))
+}
+
+//
+// Row i, column j holds the score of the i-th file of the first directory
+// against the j-th file of the second. The files that were left out are
+// listed with the reason why.
+//
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScoreMatrix {
+    pub rows: Vec<String>,
+    pub columns: Vec<String>,
+    pub scores: Vec<Vec<f64>>,
+    pub failed: Vec<FileOutcome>,
+}
+
+// Score every file in the first directory against every file in the second.
+pub fn compare_directories(
+    directory_a: &PathBuf,
+    directory_b: &PathBuf,
+    weighting: &Weighting,
+    metric_kind: MetricKind,
+) -> io::Result<ScoreMatrix> {
+    let (documents_a, mut failed) = documents_in_directory(directory_a, weighting)?;
+    let (documents_b, failed_b) = documents_in_directory(directory_b, weighting)?;
+    failed.extend(failed_b);
+    let metric = metric_kind.build(&documents_b);
+    let scores = documents_a
+        .par_iter()
+        .map(|a| {
+            documents_b
+                .iter()
+                .map(|b| metric.score(&a.digest, &b.digest))
+                .collect()
+        })
+        .collect();
+    Ok(ScoreMatrix {
+        rows: documents_a.into_iter().map(|doc| doc.file).collect(),
+        columns: documents_b.into_iter().map(|doc| doc.file).collect(),
+        scores,
+        failed,
+    })
+}
+
+pub fn compare_for_results(
+    state_path: Option<&PathBuf>,
+    file_a: &str,
+    file_b: &str,
+    metric_kind: MetricKind,
+    config: &Configuration,
+) -> io::Result<()> {
+    let weighting = Weighting::from_state(state_path, config)?;
+    let score = compare_files(file_a, file_b, &weighting, metric_kind)?;
+    println!("{} => ({}) {}", file_a, score, file_b);
+    Ok(())
+}
+
+//
+// The matrix is printed as tab separated values, with the files of the second
+// directory as the header, and a row for every file in the first directory.
+// The files that were left out are listed on stderr, they do not stop the
+// comparison of the others.
+//
+pub fn compare_directories_for_results(
+    state_path: Option<&PathBuf>,
+    directory_a: &PathBuf,
+    directory_b: &PathBuf,
+    metric_kind: MetricKind,
+    config: &Configuration,
+) -> io::Result<()> {
+    let weighting = Weighting::from_state(state_path, config)?;
+    let matrix = compare_directories(directory_a, directory_b, &weighting, metric_kind)?;
+    println!("\t{}", matrix.columns.join("\t"));
+    for (file, row) in matrix.rows.iter().zip(matrix.scores) {
+        let scores: Vec<String> = row.iter().map(|score| score.to_string()).collect();
+        println!("{}\t{}", file, scores.join("\t"));
+    }
+    for outcome in &matrix.failed {
+        match &outcome.message {
+            Some(message) => eprintln!("{}: {} ({})", outcome.outcome, outcome.file, message),
+            None => eprintln!("{}: {}", outcome.outcome, outcome.file),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fbhash::report::Outcome;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_compare_files() -> io::Result<()> {
+        let yes = "testdata/testfile-yes.bin";
+        let zero = "testdata/testfile-zero.bin";
+        let score = compare_files(yes, yes, &Weighting::Uniform, MetricKind::Cosine)?;
+        assert!(approx_eq!(f64, score, 1.0, epsilon = 0.000001));
+        let score = compare_files(yes, zero, &Weighting::Uniform, MetricKind::Cosine)?;
+        assert!(approx_eq!(f64, score, 0.0, ulps = 2));
+        assert!(compare_files(yes, "missing", &Weighting::Uniform, MetricKind::Cosine).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare_directories() -> io::Result<()> {
+        let dir = tempdir()?;
+        let other = dir.path().join("other");
+        fs::create_dir(&other)?;
+        fs::copy("testdata/testfile-yes.bin", other.join("copy.bin"))?;
+        let matrix = compare_directories(
+            &PathBuf::from("testdata"),
+            &other,
+            &Weighting::Uniform,
+            MetricKind::Cosine,
+        )?;
+        assert_eq!(matrix.rows.len(), 3);
+        assert_eq!(
+            matrix.columns,
+            vec![other.join("copy.bin").to_string_lossy().to_string()]
+        );
+        let yes = matrix
+            .rows
+            .iter()
+            .position(|file| file.ends_with("testfile-yes.bin"))
+            .unwrap();
+        assert!(approx_eq!(
+            f64,
+            matrix.scores[yes][0],
+            1.0,
+            epsilon = 0.000001
+        ));
+        assert!(matrix.failed.is_empty());
+        dir.close()
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_compare_directories_with_unreadable_file() -> io::Result<()> {
+        let dir = tempdir()?;
+        fs::copy("testdata/testfile-yes.bin", dir.path().join("copy.bin"))?;
+        // Reading the memory of a process from the start fails, even as root.
+        let unreadable = dir.path().join("unreadable.bin");
+        std::os::unix::fs::symlink("/proc/self/mem", &unreadable)?;
+        let matrix = compare_directories(
+            &PathBuf::from("testdata"),
+            &dir.path().to_path_buf(),
+            &Weighting::Uniform,
+            MetricKind::Cosine,
+        )?;
+        assert_eq!(
+            matrix.columns,
+            vec![dir.path().join("copy.bin").to_string_lossy().to_string()]
+        );
+        assert_eq!(matrix.failed.len(), 1);
+        assert_eq!(matrix.failed[0].file, unreadable.to_string_lossy());
+        assert_eq!(matrix.failed[0].outcome, Outcome::IoError);
+        dir.close()
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_compare_directories_with_socket() -> io::Result<()> {
+        let dir = tempdir()?;
+        fs::copy("testdata/testfile-yes.bin", dir.path().join("copy.bin"))?;
+        let _listener = std::os::unix::net::UnixListener::bind(dir.path().join("socket"))?;
+        let matrix = compare_directories(
+            &PathBuf::from("testdata"),
+            &dir.path().to_path_buf(),
+            &Weighting::Uniform,
+            MetricKind::Cosine,
+        )?;
+        assert_eq!(matrix.rows.len(), 3);
+        assert_eq!(matrix.columns.len(), 1);
+        assert_eq!(matrix.failed.len(), 1);
+        assert_eq!(matrix.failed[0].outcome, Outcome::NotAFile);
+        dir.close()
+    }
+}
@@ -0,0 +1,358 @@
+// Copyright 2021 -- 2023 Erwin van Eijk
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included
+// in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+// TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+// SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use hashbrown::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use crate::fbhash::archive::{split_virtual_path, with_member};
+use crate::fbhash::chunker::{Chunk, ChunkIterator, CHUNK_SIZE};
+use crate::fbhash::query::read_state;
+use crate::fbhash::similarities::{cosine_similarity, DocumentCollection};
+use crate::fbhash::utils::*;
+
+//
+// In highly repetitive content, such as a file full of zeroes, a chunk occurs
+// at a great many offsets. Only this many are used to line up the regions.
+//
+const MAX_OFFSETS_PER_CHUNK: usize = 64;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SharedChunk {
+    pub chunk: u64,
+    // What this chunk adds to the cosine similarity of the two digests.
+    pub contribution: f64,
+    pub offsets_a: Vec<usize>,
+    pub offsets_b: Vec<usize>,
+}
+
+//
+// A range of bytes that the two files have in common, at start_a in the
+// first and at start_b in the second file.
+//
+#[derive(Clone, Debug, PartialEq)]
+pub struct Region {
+    pub start_a: usize,
+    pub start_b: usize,
+    pub length: usize,
+    pub contribution: f64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Explanation {
+    pub similarity: f64,
+    pub number_of_chunks_a: usize,
+    pub number_of_chunks_b: usize,
+    // Both sorted by contribution, the largest first.
+    pub shared_chunks: Vec<SharedChunk>,
+    pub regions: Vec<Region>,
+}
+
+// Re-chunk a file or a member of an archive, keeping the offsets.
+fn read_chunks(name: &str) -> io::Result<Vec<Chunk>> {
+    fn chunks_of<R: Read>(reader: R) -> io::Result<Vec<Chunk>> {
+        ChunkIterator::new(BufReader::new(reader)).collect()
+    }
+    if let Some((archive_name, member_name)) = split_virtual_path(name) {
+        if !Path::new(name).exists() {
+            return with_member(Path::new(archive_name), member_name, |_, reader| {
+                chunks_of(reader)
+            });
+        }
+    }
+    chunks_of(File::open(name)?)
+}
+
+fn offsets_by_chunk(chunks: &[Chunk]) -> HashMap<u64, Vec<usize>> {
+    let mut offsets: HashMap<u64, Vec<usize>> = HashMap::new();
+    for chunk in chunks {
+        offsets.entry(chunk.digest).or_default().push(chunk.number);
+    }
+    offsets
+}
+
+//
+// Line up the shared chunks into regions that are the same in both files.
+// Going through the first file, a shared chunk is extended for as long as the
+// chunks that follow it are the same in both files. The longest extension is
+// kept, and the search goes on after it. The chunks are numbered by their
+// offset, so the n-th chunk of a file starts at byte n.
+//
+fn find_regions(
+    chunks_a: &[Chunk],
+    chunks_b: &[Chunk],
+    offsets_b: &HashMap<u64, Vec<usize>>,
+    contributions: &HashMap<u64, f64>,
+) -> Vec<Region> {
+    let mut regions = Vec::new();
+    let mut i = 0;
+    while i < chunks_a.len() {
+        let longest = offsets_b
+            .get(&chunks_a[i].digest)
+            .into_iter()
+            .flatten()
+            .take(MAX_OFFSETS_PER_CHUNK)
+            .map(|j| {
+                let length = chunks_a[i..]
+                    .iter()
+                    .zip(&chunks_b[*j..])
+                    .take_while(|(a, b)| a.digest == b.digest)
+                    .count();
+                (length, *j)
+            })
+            .max_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+        match longest {
+            Some((length, j)) => {
+                let seen: HashSet<u64> = chunks_a[i..i + length].iter().map(|c| c.digest).collect();
+                regions.push(Region {
+                    start_a: i,
+                    start_b: j,
+                    length: length - 1 + CHUNK_SIZE,
+                    contribution: seen
+                        .iter()
+                        .map(|chunk| contributions.get(chunk).copied().unwrap_or(0.))
+                        .fold(0., |sum, contribution| sum + contribution),
+                });
+                i += length;
+            }
+            None => i += 1,
+        }
+    }
+    regions.sort_by(|a, b| {
+        b.contribution
+            .total_cmp(&a.contribution)
+            .then(b.length.cmp(&a.length))
+            .then(a.start_a.cmp(&b.start_a))
+    });
+    regions
+}
+
+pub fn explain(
+    document_collection: &DocumentCollection,
+    chunks_a: &[Chunk],
+    chunks_b: &[Chunk],
+) -> Explanation {
+    let digest = |chunks: &[Chunk]| -> Vec<(u64, f64)> {
+        let digests: Vec<u64> = chunks.iter().map(|c| c.digest).collect();
+        document_collection.compute_document_digest(&digests)
+    };
+    let digest_a = digest(chunks_a);
+    let digest_b = digest(chunks_b);
+    let norm = |digest: &[(u64, f64)]| digest.iter().map(|(_, v)| v * v).sum::<f64>().sqrt();
+    let norms = norm(&digest_a) * norm(&digest_b);
+
+    // Every shared chunk adds its share of the dot product to the similarity.
+    let weights_b: HashMap<u64, f64> = digest_b.iter().copied().collect();
+    let contributions: HashMap<u64, f64> = digest_a
+        .iter()
+        .filter_map(|(chunk, weight_a)| {
+            weights_b
+                .get(chunk)
+                .map(|weight_b| (*chunk, weight_a * weight_b / norms))
+        })
+        .collect();
+
+    let offsets_a = offsets_by_chunk(chunks_a);
+    let offsets_b = offsets_by_chunk(chunks_b);
+    let mut shared_chunks: Vec<SharedChunk> = contributions
+        .iter()
+        .map(|(chunk, contribution)| SharedChunk {
+            chunk: *chunk,
+            contribution: *contribution,
+            offsets_a: offsets_a[chunk].clone(),
+            offsets_b: offsets_b[chunk].clone(),
+        })
+        .collect();
+    shared_chunks.sort_by(|a, b| {
+        b.contribution
+            .total_cmp(&a.contribution)
+            .then(a.chunk.cmp(&b.chunk))
+    });
+
+    Explanation {
+        similarity: cosine_similarity(&digest_a, &digest_b),
+        number_of_chunks_a: chunks_a.len(),
+        number_of_chunks_b: chunks_b.len(),
+        shared_chunks,
+        regions: find_regions(chunks_a, chunks_b, &offsets_b, &contributions),
+    }
+}
+
+// Show a few offsets, and how many more there are.
+fn format_offsets(offsets: &[usize]) -> String {
+    let shown: Vec<String> = offsets.iter().take(5).map(|o| o.to_string()).collect();
+    if offsets.len() > shown.len() {
+        format!("{}, ... ({} in total)", shown.join(", "), offsets.len())
+    } else {
+        shown.join(", ")
+    }
+}
+
+pub fn explain_match(
+    state_path: &PathBuf,
+    file_a: &str,
+    file_b: &str,
+    number_of_results: usize,
+    config: &Configuration,
+) -> io::Result<()> {
+    let document_collection = read_state(state_path, config)?;
+    let explanation = explain(
+        &document_collection,
+        &read_chunks(file_a)?,
+        &read_chunks(file_b)?,
+    );
+
+    println!("Explanation for {} => {}", file_a, file_b);
+    println!("Similarity: {}", explanation.similarity);
+    println!(
+        "Shared chunks: {} (of {} chunks in {} and {} chunks in {})",
+        explanation.shared_chunks.len(),
+        explanation.number_of_chunks_a,
+        file_a,
+        explanation.number_of_chunks_b,
+        file_b
+    );
+    for shared in explanation.shared_chunks.iter().take(number_of_results) {
+        println!(
+            "  {:016x} ({}) at {} <=> {}",
+            shared.chunk,
+            shared.contribution,
+            format_offsets(&shared.offsets_a),
+            format_offsets(&shared.offsets_b)
+        );
+    }
+    println!("Regions: {}", explanation.regions.len());
+    for region in explanation.regions.iter().take(number_of_results) {
+        println!(
+            "  {}..{} <=> {}..{} ({} bytes, {})",
+            region.start_a,
+            region.start_a + region.length,
+            region.start_b,
+            region.start_b + region.length,
+            region.length,
+            region.contribution
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use std::io::Cursor;
+
+    fn chunks_of(content: &[u8]) -> Vec<Chunk> {
+        ChunkIterator::new(Cursor::new(content))
+            .collect::<io::Result<_>>()
+            .unwrap()
+    }
+
+    fn collection_of(documents: &[&[Chunk]]) -> DocumentCollection {
+        let mut document_collection = DocumentCollection::new();
+        for (i, chunks) in documents.iter().enumerate() {
+            let digests: Vec<u64> = chunks.iter().map(|c| c.digest).collect();
+            document_collection.update_collection(
+                &crate::fbhash::similarities::chunk_frequencies(&digests),
+                &[i.to_string()],
+            );
+        }
+        document_collection
+    }
+
+    #[test]
+    fn test_explain_finds_the_shared_region() -> io::Result<()> {
+        let shared = fs::read("testdata/testfile-yes.bin")?;
+        let mut content_a: Vec<u8> = (0..100_u32).map(|i| (i * 7 % 251) as u8).collect();
+        content_a.extend(&shared);
+        let mut content_b: Vec<u8> = (0..300_u32).map(|i| (i * 13 % 241) as u8).collect();
+        content_b.extend(&shared);
+        let chunks_a = chunks_of(&content_a);
+        let chunks_b = chunks_of(&content_b);
+        let document_collection = collection_of(&[&chunks_a, &chunks_b]);
+
+        let explanation = explain(&document_collection, &chunks_a, &chunks_b);
+        assert_eq!(
+            explanation.number_of_chunks_a,
+            content_a.len() - CHUNK_SIZE + 1
+        );
+        assert_eq!(explanation.shared_chunks.len(), 2);
+        let total: f64 = explanation
+            .shared_chunks
+            .iter()
+            .map(|shared| shared.contribution)
+            .sum();
+        assert!(approx_eq!(
+            f64,
+            total,
+            explanation.similarity,
+            epsilon = 1e-9
+        ));
+
+        // The region with the most weight is the whole of the shared content.
+        let region = &explanation.regions[0];
+        assert_eq!(region.start_a, 100);
+        assert_eq!(region.start_b, 300);
+        assert_eq!(region.length, shared.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_regions_stops_at_differences() {
+        let chunks = |offsets: &[(usize, u64)]| -> Vec<Chunk> {
+            offsets
+                .iter()
+                .map(|(number, digest)| Chunk {
+                    number: *number,
+                    digest: *digest,
+                })
+                .collect()
+        };
+        let chunks_a = chunks(&[(0, 1), (1, 2), (2, 3), (3, 9), (4, 4)]);
+        let chunks_b = chunks(&[(0, 7), (1, 1), (2, 2), (3, 3), (4, 8), (5, 4)]);
+        let contributions: HashMap<u64, f64> = [(1, 0.1), (2, 0.1), (3, 0.1), (4, 0.2)]
+            .iter()
+            .copied()
+            .collect();
+        let regions = find_regions(
+            &chunks_a,
+            &chunks_b,
+            &offsets_by_chunk(&chunks_b),
+            &contributions,
+        );
+        assert_eq!(regions.len(), 2);
+        assert_eq!((regions[0].start_a, regions[0].start_b), (0, 1));
+        assert_eq!(regions[0].length, 2 + CHUNK_SIZE);
+        assert_eq!((regions[1].start_a, regions[1].start_b), (4, 5));
+        assert_eq!(regions[1].length, CHUNK_SIZE);
+    }
+
+    #[test]
+    fn test_format_offsets() {
+        assert_eq!(format_offsets(&[1, 2]), "1, 2");
+        assert_eq!(
+            format_offsets(&[1, 2, 3, 4, 5, 6, 7]),
+            "1, 2, 3, 4, 5, ... (7 in total)"
+        );
+    }
+}
@@ -0,0 +1,229 @@
+// Copyright 2021 -- 2023 Erwin van Eijk
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included
+// in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+// TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+// SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::io::{Cursor, Read, Write};
+
+use crate::fbhash::metadata::DocumentMetadata;
+use crate::fbhash::similarities::{Document, DocumentCollection};
+
+//
+// The binary files start with a header: these bytes, and the version of the
+// layout that follows. A state or database from before there was a header has
+// the layout of the first release, it is read and converted. The other files,
+// such as the signatures, have always had a header.
+//
+const MAGIC: [u8; 6] = *b"FBHASH";
+pub const FORMAT_VERSION: u16 = 1;
+pub const HEADER_LENGTH: u64 = 8;
+
+pub enum Layout {
+    Current,
+    // Without a header, the file starts with these bytes of its contents.
+    Legacy([u8; 8]),
+}
+
+pub fn write_header<W: Write>(output: &mut W) -> io::Result<()> {
+    output.write_all(&MAGIC)?;
+    output.write_all(&FORMAT_VERSION.to_le_bytes())
+}
+
+pub fn read_header<R: Read>(input: &mut R) -> io::Result<Layout> {
+    let mut start = [0_u8; 8];
+    input.read_exact(&mut start)?;
+    if start[..6] != MAGIC {
+        return Ok(Layout::Legacy(start));
+    }
+    match u16::from_le_bytes([start[6], start[7]]) {
+        FORMAT_VERSION => Ok(Layout::Current),
+        version => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("version {} of the binary format is not supported", version),
+        )),
+    }
+}
+
+// The header of a file that has always had one.
+pub fn expect_header<R: Read>(input: &mut R) -> io::Result<()> {
+    match read_header(input)? {
+        Layout::Current => Ok(()),
+        Layout::Legacy(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the header of the binary format is missing",
+        )),
+    }
+}
+
+//
+// Read what bincode wrote, without reading more than the size of the file:
+// a file that is not what it should be, e.g. a JSON file, is an error rather
+// than a huge allocation.
+//
+pub fn deserialize_from<T: DeserializeOwned, R: Read>(input: R, limit: u64) -> io::Result<T> {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit)
+        .deserialize_from(input)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+// A document as the first release wrote it, without metadata.
+#[derive(Deserialize)]
+struct LegacyDocument {
+    file: String,
+    chunks: Vec<u64>,
+    digest: Vec<(u64, f64)>,
+}
+
+// The state as the first release wrote it.
+#[derive(Deserialize)]
+struct LegacyCollection {
+    files: BTreeSet<String>,
+    collection_digests: BTreeMap<u64, usize>,
+}
+
+pub fn read_legacy_documents<R: Read>(
+    start: [u8; 8],
+    input: R,
+    limit: u64,
+) -> io::Result<Vec<Document>> {
+    let documents: Vec<LegacyDocument> = deserialize_from(Cursor::new(start).chain(input), limit)?;
+    Ok(documents
+        .into_iter()
+        .map(|document| Document {
+            file: document.file,
+            chunks: document.chunks,
+            digest: document.digest,
+            metadata: DocumentMetadata::default(),
+        })
+        .collect())
+}
+
+pub fn read_legacy_state<R: Read>(
+    start: [u8; 8],
+    input: R,
+    limit: u64,
+) -> io::Result<DocumentCollection> {
+    let collection: LegacyCollection = deserialize_from(Cursor::new(start).chain(input), limit)?;
+    Ok(DocumentCollection::from_counts(
+        collection.files,
+        collection.collection_digests,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    struct FirstReleaseDocument {
+        file: String,
+        chunks: Vec<u64>,
+        digest: Vec<(u64, f64)>,
+    }
+
+    #[derive(Serialize)]
+    struct FirstReleaseCollection {
+        files: BTreeSet<String>,
+        collection_digests: BTreeMap<u64, usize>,
+    }
+
+    #[test]
+    fn test_read_header() -> io::Result<()> {
+        let mut output = Vec::new();
+        write_header(&mut output)?;
+        assert_eq!(output.len() as u64, HEADER_LENGTH);
+        assert!(matches!(
+            read_header(&mut output.as_slice())?,
+            Layout::Current
+        ));
+
+        let legacy = 3_u64.to_le_bytes();
+        assert!(matches!(
+            read_header(&mut legacy.as_slice())?,
+            Layout::Legacy(start) if start == legacy
+        ));
+
+        let newer = *b"FBHASH\x09\x00";
+        assert_eq!(
+            read_header(&mut newer.as_slice()).err().map(|e| e.kind()),
+            Some(io::ErrorKind::InvalidData)
+        );
+
+        expect_header(&mut output.as_slice())?;
+        assert_eq!(
+            expect_header(&mut legacy.as_slice())
+                .err()
+                .map(|e| e.kind()),
+            Some(io::ErrorKind::InvalidData)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_legacy_files() -> io::Result<()> {
+        let documents = vec![FirstReleaseDocument {
+            file: "a".to_string(),
+            chunks: vec![],
+            digest: vec![(1, 0.5)],
+        }];
+        let bytes = bincode::serialize(&documents).unwrap();
+        let mut input = bytes.as_slice();
+        let Layout::Legacy(start) = read_header(&mut input)? else {
+            panic!("A file of the first release has no header");
+        };
+        let documents = read_legacy_documents(start, input, bytes.len() as u64)?;
+        assert_eq!(documents.len(), 1);
+        assert_eq!(documents[0].file, "a");
+        assert_eq!(documents[0].digest, vec![(1, 0.5)]);
+        assert_eq!(documents[0].metadata, DocumentMetadata::default());
+
+        let collection = FirstReleaseCollection {
+            files: ["a".to_string()].iter().cloned().collect(),
+            collection_digests: [(1, 2)].iter().copied().collect(),
+        };
+        let bytes = bincode::serialize(&collection).unwrap();
+        let mut input = bytes.as_slice();
+        let Layout::Legacy(start) = read_header(&mut input)? else {
+            panic!("A file of the first release has no header");
+        };
+        let collection = read_legacy_state(start, input, bytes.len() as u64)?;
+        assert!(collection.exists_file("a"));
+        assert_eq!(collection.chunk_count(1), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_deserialize_from_is_limited() {
+        let json = b"{\"files\":[\"a\"]}";
+        let result: io::Result<Vec<String>> = deserialize_from(&json[..], json.len() as u64);
+        assert_eq!(
+            result.err().map(|e| e.kind()),
+            Some(io::ErrorKind::InvalidData)
+        );
+    }
+}
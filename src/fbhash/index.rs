@@ -19,248 +19,870 @@
 // SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 use console::style;
-use hashbrown::HashMap;
-use std::cell::RefCell;
+use hashbrown::HashSet;
 use std::convert::TryInto;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, SeekFrom};
 use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::thread;
 
-use indicatif::{ProgressBar, ProgressIterator};
+use indicatif::ProgressBar;
 use rayon::prelude::*;
-use std::path::PathBuf;
-use std::sync::RwLock;
-use walkdir::WalkDir;
+use std::path::{Path, PathBuf};
 
+use crate::fbhash::archive::{for_each_member, virtual_path};
+use crate::fbhash::blocks::{block_name, for_each_block};
+use crate::fbhash::compact::{CompactDocument, Quantization};
+use crate::fbhash::format::{write_header, HEADER_LENGTH};
+use crate::fbhash::known::{filter_known, KnownFiles, KnownOptions};
+use crate::fbhash::lsh::{lsh_path, LshIndex, LshOptions};
+use crate::fbhash::metadata::DocumentMetadata;
+use crate::fbhash::query::open_state_and_shards;
+use crate::fbhash::report::{write_report, FileOutcome, IndexReport, Outcome};
+use crate::fbhash::shard::{database_paths, shard_path, ShardOptions};
 use crate::fbhash::similarities::*;
+use crate::fbhash::spill::{PendingDocument, SpillBuffer};
 use crate::fbhash::utils::*;
+use crate::fbhash::walker::{Walker, WalkerOptions};
 
-fn get_files_from_dir(start_path: &PathBuf) -> Vec<PathBuf> {
-    WalkDir::new(start_path)
-        .follow_links(false)
-        .into_iter()
-        .map(|e| e.ok().unwrap().path().to_owned())
-        .filter(|path_name| path_name.is_file())
-        .collect()
+//
+// Everything that determines what ends up in the index, and how.
+//
+#[derive(Clone, Debug, Default)]
+pub struct IndexOptions {
+    pub walker: WalkerOptions,
+    // The evidence item or source label that is recorded with every document.
+    pub source: Option<String>,
+    // Leave out the files that are known to be of no interest.
+    pub known: Option<KnownOptions>,
+    // Chunks with a lower inverse document frequency, or that occur more
+    // often, are left out of the digests.
+    pub min_idf: Option<f64>,
+    pub max_chunk_count: Option<usize>,
+    // Only keep the chunks with the largest weights in the digests.
+    pub digest_cap: Option<DigestCap>,
+    // Also write the signatures for approximate queries.
+    pub lsh: Option<LshOptions>,
+    // Write the digests in the compact form.
+    pub digest_encoding: Option<Quantization>,
+    // Split the database in shards.
+    pub shards: Option<ShardOptions>,
+    // How many bytes the documents waiting for their digests may take, before
+    // they are moved to temporary files. Unlimited without a budget.
+    pub memory_budget: Option<usize>,
 }
 
-// Write the results to a binary file.
-fn write_database_state_binary(results: &[Document], results_file: &PathBuf) -> io::Result<()> {
-    let output = File::create(results_file)?;
-    bincode::serialize_into(output, &results).unwrap();
-    Ok(())
+//
+// Writes the documents to the database one at a time, so they never all have
+// to be in memory. The binary database starts with a header and the number
+// of documents, which is filled in when the writer is finished. With an
+// encoding, the digests are written in the compact form.
+//
+pub struct DatabaseWriter {
+    output: BufWriter<File>,
+    encoding: Option<Quantization>,
+    format: OutputFormat,
+    count: u64,
+}
+
+impl DatabaseWriter {
+    pub fn create(
+        results_file: &Path,
+        encoding: Option<Quantization>,
+        config: &Configuration,
+    ) -> io::Result<DatabaseWriter> {
+        let mut output = BufWriter::new(File::create(results_file)?);
+        if let OutputFormat::Binary = config.output_format {
+            write_header(&mut output)?;
+            output.write_all(&0_u64.to_le_bytes())?;
+        }
+        Ok(DatabaseWriter {
+            output,
+            encoding,
+            format: config.output_format,
+            count: 0,
+        })
+    }
+
+    pub fn write(&mut self, document: &Document) -> io::Result<()> {
+        match self.format {
+            OutputFormat::Json => {
+                let line = match self.encoding {
+                    Some(quantization) => {
+                        serde_json::to_string(&CompactDocument::new(document, quantization))
+                    }
+                    None => serde_json::to_string(document),
+                }
+                .map_err(io::Error::other)?;
+                self.output.write_all(line.as_bytes())?;
+                self.output.write_all(b"\n")?;
+            }
+            OutputFormat::Binary => match self.encoding {
+                Some(quantization) => bincode::serialize_into(
+                    &mut self.output,
+                    &CompactDocument::new(document, quantization),
+                ),
+                None => bincode::serialize_into(&mut self.output, document),
+            }
+            .map_err(io::Error::other)?,
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        let mut output = self.output.into_inner().map_err(|e| e.into_error())?;
+        if let OutputFormat::Binary = self.format {
+            output.seek(SeekFrom::Start(HEADER_LENGTH))?;
+            output.write_all(&self.count.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+//
+// Writes the documents of an index to the database, or to its shards, and
+// the signatures of each for approximate queries when asked for. Files left
+// by an earlier index that no longer match are removed.
+//
+pub struct IndexWriter {
+    database_path: PathBuf,
+    shards: Option<ShardOptions>,
+    databases: Vec<DatabaseWriter>,
+    signatures: Option<Vec<LshIndex>>,
+}
+
+impl IndexWriter {
+    pub fn create(
+        database_path: &Path,
+        encoding: Option<Quantization>,
+        shards: Option<ShardOptions>,
+        lsh: Option<LshOptions>,
+        config: &Configuration,
+    ) -> io::Result<IndexWriter> {
+        let paths = database_paths(database_path, shards);
+        let databases = paths
+            .iter()
+            .map(|path| DatabaseWriter::create(path, encoding, config))
+            .collect::<io::Result<Vec<DatabaseWriter>>>()?;
+        Ok(IndexWriter {
+            database_path: database_path.to_path_buf(),
+            shards,
+            databases,
+            signatures: lsh.map(|lsh| paths.iter().map(|_| LshIndex::new(&lsh)).collect()),
+        })
+    }
+
+    //
+    // Write the documents of an existing index anew, in the same shards, and
+    // with signatures if it has them.
+    //
+    pub fn rewrite(
+        database_path: &Path,
+        document_collection: &DocumentCollection,
+        config: &Configuration,
+    ) -> io::Result<IndexWriter> {
+        let shards = document_collection.get_shards();
+        let path = lsh_path(&database_paths(database_path, shards)[0]);
+        let lsh = if path.exists() {
+            Some(LshIndex::read(&path, config)?.options())
+        } else {
+            None
+        };
+        IndexWriter::create(
+            database_path,
+            document_collection.get_digest_encoding(),
+            shards,
+            lsh,
+            config,
+        )
+    }
+
+    pub fn number_of_shards(&self) -> usize {
+        self.databases.len()
+    }
+
+    pub fn shard_of(&self, file: &str, item: usize) -> usize {
+        match self.shards {
+            Some(shards) => shards.shard_of(file, item),
+            None => 0,
+        }
+    }
+
+    pub fn write(&mut self, shard: usize, document: &Document) -> io::Result<()> {
+        self.databases[shard].write(document)?;
+        if let Some(signatures) = self.signatures.as_mut() {
+            signatures[shard].add(document);
+        }
+        Ok(())
+    }
+
+    pub fn finish(self, config: &Configuration) -> io::Result<()> {
+        let paths = database_paths(&self.database_path, self.shards);
+        for database in self.databases {
+            database.finish()?;
+        }
+        match self.signatures {
+            Some(signatures) => {
+                for (path, lsh) in paths.iter().zip(signatures) {
+                    lsh.write(&lsh_path(path), config)?;
+                }
+            }
+            None => {
+                for path in &paths {
+                    remove_stale(&lsh_path(path))?;
+                }
+            }
+        }
+        // The database or the shards of an index with another layout.
+        let mut stale = Vec::new();
+        let mut shard = match self.shards {
+            Some(_) => {
+                stale.push(self.database_path.clone());
+                paths.len()
+            }
+            None => 0,
+        };
+        while shard_path(&self.database_path, shard).exists() {
+            stale.push(shard_path(&self.database_path, shard));
+            shard += 1;
+        }
+        for path in stale {
+            remove_stale(&path)?;
+            remove_stale(&lsh_path(&path))?;
+        }
+        Ok(())
+    }
 }
 
-fn write_database_state(
-    updated_results: &[Document],
-    results_file: &PathBuf,
+fn remove_stale(path: &Path) -> io::Result<()> {
+    if path.exists() {
+        std::fs::remove_file(path)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn write_state(
+    document_collection: &DocumentCollection,
+    output_state_file: &PathBuf,
     config: &Configuration,
 ) -> io::Result<()> {
+    let mut state_output = File::create(output_state_file)?;
     match config.output_format {
-        OutputFormat::Binary => write_database_state_binary(updated_results, results_file),
-        OutputFormat::Json => {
-            let final_progress =
-                create_progress_bar(updated_results.len().try_into().unwrap(), config);
-            let mut output = File::create(results_file)?;
-            let errors: Vec<io::Result<()>> = updated_results
-                .iter()
-                .progress_with(final_progress)
-                .map(|doc| {
-                    output.write_all(serde_json::to_string(&doc).unwrap().as_bytes())?;
-                    output.write_all(b"\n")?;
-                    Ok(())
-                })
-                .filter(|e| e.is_err())
-                .collect();
-            if errors.is_empty() {
-                Ok(())
-            } else {
-                Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    errors[0].as_ref().err().unwrap().to_string(),
-                ))
+        OutputFormat::Json => state_output.write_all(
+            serde_json::to_string_pretty(document_collection)
+                .unwrap()
+                .as_bytes(),
+        )?,
+        OutputFormat::Binary => {
+            let mut state_output = BufWriter::new(state_output);
+            write_header(&mut state_output)?;
+            bincode::serialize_into(&mut state_output, document_collection)
+                .map_err(io::Error::other)?;
+            state_output.flush()?;
+        }
+    }
+    Ok(())
+}
+
+pub type IndexResult = Result<PendingDocument, FileOutcome>;
+
+// What is sent for an input: the result of one of its documents, the outcome
+// of the input as a whole, or that it is done.
+enum InputMessage {
+    Result(IndexResult),
+    Outcome(FileOutcome),
+    Finished,
+}
+// The position of the input, and a message about it.
+type TaggedMessage = (usize, InputMessage);
+
+// How many documents may wait for the dictionary to be updated.
+const PENDING_CAPACITY: usize = 64;
+
+//
+// Sends the results of one input, tagged with its position, so they can be
+// put back in the order of the inputs.
+//
+struct InputSender<'a> {
+    sender: &'a SyncSender<TaggedMessage>,
+    input: usize,
+}
+
+impl InputSender<'_> {
+    fn send(&self, result: IndexResult) -> Option<()> {
+        self.sender
+            .send((self.input, InputMessage::Result(result)))
+            .ok()
+    }
+
+    fn record(&self, outcome: FileOutcome) -> Option<()> {
+        self.sender
+            .send((self.input, InputMessage::Outcome(outcome)))
+            .ok()
+    }
+
+    fn finish(&self) -> Option<()> {
+        self.sender.send((self.input, InputMessage::Finished)).ok()
+    }
+}
+
+//
+// The document of whatever the reader produces. When reading fails partway
+// through, the error is recorded rather than a document of the part that was
+// read.
+//
+fn index_reader<R: Read>(name: String, reader: R, metadata: DocumentMetadata) -> IndexResult {
+    match compute_frequencies_from_reader(reader, metadata) {
+        Ok((frequencies, metadata)) => Ok(PendingDocument::new(name, metadata, frequencies)),
+        Err(e) => Err(FileOutcome::from_error(Path::new(&name), &e)),
+    }
+}
+
+fn index_file(file_path: &Path) -> IndexResult {
+    let opened = File::open(file_path).and_then(|file| {
+        let metadata = DocumentMetadata::from_file_metadata(&file.metadata()?);
+        Ok((file, metadata))
+    });
+    match opened {
+        Ok((file, metadata)) => {
+            index_reader(file_path.to_string_lossy().to_string(), file, metadata)
+        }
+        Err(e) => Err(FileOutcome::from_error(file_path, &e)),
+    }
+}
+
+//
+// Every member of the archive becomes a document of its own, named by its
+// virtual path. The members are streamed through the chunker, the archive is
+// never unpacked.
+//
+fn index_archive(archive_path: &Path, walker: &Walker, sender: &InputSender) -> Option<()> {
+    let walked = for_each_member(archive_path, |member, reader| {
+        let name = virtual_path(archive_path, &member.name);
+        let result = match walker.check_member(&name, member.size) {
+            Ok(()) => index_reader(name, reader, member.metadata()),
+            Err(outcome) => Err(outcome),
+        };
+        Ok(sender.send(result).is_some())
+    });
+    match walked {
+        Ok(()) => Some(()),
+        Err(e) => sender.send(Err(FileOutcome::from_error(archive_path, &e))),
+    }
+}
+
+//
+// Add every document that comes in to the dictionary, and keep its
+// frequencies until the dictionary is complete and the digests can be
+// computed. Only the frequencies are kept, not the chunks themselves.
+//
+// The inputs are processed in parallel, and their results are handled as
+// they come in. The spill buffer hands the documents back in the order of the
+// inputs, and the outcomes are put in that order too, so the database does
+// not depend on the scheduling. The documents are all part of the given
+// evidence item, or without one, every input is an item of its own.
+//
+fn collect_pending(
+    receiver: Receiver<TaggedMessage>,
+    item: Option<usize>,
+    document_collection: &mut DocumentCollection,
+    spill: &mut SpillBuffer,
+    outcomes: &mut Vec<FileOutcome>,
+    record_indexed: bool,
+) -> io::Result<()> {
+    let mut tagged_outcomes: Vec<(usize, FileOutcome)> = Vec::new();
+    for (input, message) in receiver {
+        match message {
+            InputMessage::Result(Ok(mut pending)) => {
+                pending.item = item.unwrap_or(input);
+                pending.input = input;
+                document_collection.update_collection(
+                    pending
+                        .frequencies
+                        .iter()
+                        .map(|(chunk, count)| (chunk, count)),
+                    std::slice::from_ref(&pending.file),
+                );
+                if record_indexed {
+                    tagged_outcomes.push((
+                        input,
+                        FileOutcome::new(Path::new(&pending.file), Outcome::Indexed),
+                    ));
+                }
+                spill.push(pending)?;
+            }
+            InputMessage::Result(Err(outcome)) | InputMessage::Outcome(outcome) => {
+                tagged_outcomes.push((input, outcome))
             }
+            InputMessage::Finished => (),
         }
     }
+    tagged_outcomes.sort_by_key(|(input, _)| *input);
+    outcomes.extend(tagged_outcomes.into_iter().map(|(_, outcome)| outcome));
+    Ok(())
+}
+
+//
+// The documents of a single input, a file or an archive, all at once.
+//
+pub fn index_input(input_path: &Path, walker: &Walker) -> Vec<IndexResult> {
+    if !walker.expands(input_path) {
+        return vec![index_file(input_path)];
+    }
+    let (sender, receiver) = mpsc::sync_channel(PENDING_CAPACITY);
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            let sender = InputSender {
+                sender: &sender,
+                input: 0,
+            };
+            index_archive(input_path, walker, &sender)
+        });
+        receiver
+            .into_iter()
+            .filter_map(|(_, message)| match message {
+                InputMessage::Result(result) => Some(result),
+                _ => None,
+            })
+            .collect()
+    })
 }
 
 fn index_directory(
     start_path: &PathBuf,
-    document_collection: &RefCell<DocumentCollection>,
+    item: usize,
+    walker: &Walker,
+    document_collection: &mut DocumentCollection,
+    spill: &mut SpillBuffer,
     config: &Configuration,
-) -> Vec<Document> {
-    let files: Vec<PathBuf> = get_files_from_dir(start_path);
+) -> io::Result<Vec<FileOutcome>> {
+    let (files, mut outcomes) = walker.get_files_from_dir(start_path);
     let number_of_files: u64 = files.len().try_into().unwrap();
 
     let pb = create_progress_bar(number_of_files, config);
-    type HashSender = Sender<(HashMap<u64, usize>, String)>;
-    type HashReceiver = Receiver<(HashMap<u64, usize>, String)>;
-    let (sender, receiver): (HashSender, HashReceiver) = mpsc::channel();
-    let results: Vec<Document> = files
-        .par_iter()
-        .map_with(sender, |s, file_path| {
-            match compute_document(&file_path.to_string_lossy()) {
-                Ok((document, file_frequencies)) => {
+    let (sender, receiver) = mpsc::sync_channel(PENDING_CAPACITY);
+    let collected = thread::scope(|scope| {
+        scope.spawn(|| {
+            files
+                .par_iter()
+                .enumerate()
+                .try_for_each_with(sender, |s, (input, file_path)| {
                     pb.inc(1);
-                    s.send((file_frequencies, document.file.clone())).unwrap();
-                    Some(document)
-                }
-                Err(_) => None,
-            }
-        })
-        .fold(Vec::new, |mut left, right: Option<Document>| {
-            if let Some(value) = right {
-                left.push(value);
-            }
-            left
-        })
-        .reduce(Vec::new, |mut left, right| {
-            left.extend(right);
-            left
+                    let sender = InputSender { sender: s, input };
+                    if walker.expands(file_path) {
+                        index_archive(file_path, walker, &sender)?;
+                    } else {
+                        sender.send(index_file(file_path))?;
+                    }
+                    sender.finish()
+                })
         });
+        collect_pending(
+            receiver,
+            Some(item),
+            document_collection,
+            spill,
+            &mut outcomes,
+            true,
+        )
+    });
     pb.finish_and_clear();
+    collected?;
+    Ok(outcomes)
+}
 
+//
+// Everything after the documents are chunked is shared between indexing
+// directories and indexing blocks: weigh the chunks of every document, leave
+// out the known files, and write the database and the state. The pending
+// documents are handled a batch at a time. The outcomes of the documents that
+// were left out are returned.
+//
+pub fn write_index(
+    mut document_collection: DocumentCollection,
+    spill: SpillBuffer,
+    output_state_file: &PathBuf,
+    results_file: &Path,
+    options: &IndexOptions,
+    config: &Configuration,
+) -> io::Result<Vec<FileOutcome>> {
     if !config.quiet {
         println!(
-            "{} Updating the internal dictionary...",
-            style("[2/5]").bold().dim()
+            "{} Output file database to {}",
+            style("[2/3]").bold().dim(),
+            results_file.display()
         );
+        if spill.number_of_spilled_files() > 0 {
+            println!(
+                "      The pending documents were moved to {} temporary files",
+                spill.number_of_spilled_files()
+            );
+        }
     }
 
-    let new_pb = create_progress_bar(number_of_files, config);
-    let mut dc = document_collection.borrow_mut();
-    receiver
-        .iter()
-        .progress_with(new_pb)
-        .for_each(|(hash, name)| {
-            dc.update_collection(&hash, &[name]);
+    document_collection.set_cutoff(options.min_idf, options.max_chunk_count);
+    document_collection.set_digest_cap(options.digest_cap);
+    document_collection.set_digest_encoding(options.digest_encoding);
+    document_collection.set_shards(options.shards);
+    // The digests are weighed by the complete collection, also after known
+    // files are removed from it.
+    let reference_collection = document_collection.copy();
+    let known = match &options.known {
+        Some(known) => Some(KnownFiles::open(known, &reference_collection, config)?),
+        None => None,
+    };
+    let progress_bar: ProgressBar = create_progress_bar(spill.len().try_into().unwrap(), config);
+    let mut writer = IndexWriter::create(
+        results_file,
+        options.digest_encoding,
+        options.shards,
+        options.lsh,
+        config,
+    )?;
+    let mut dropped = Vec::new();
+    spill.for_each_batch(|batch| {
+        let mut shards: Vec<Vec<PendingDocument>> = vec![Vec::new(); writer.number_of_shards()];
+        for pending in batch {
+            shards[writer.shard_of(&pending.file, pending.item)].push(pending);
+        }
+        for (shard, batch) in shards.into_iter().enumerate() {
+            let documents: Vec<Document> = batch
+                .into_par_iter()
+                .map(|pending| {
+                    progress_bar.inc(1);
+                    Document {
+                        digest: reference_collection
+                            .compute_frequencies_digest(&pending.frequencies),
+                        file: pending.file,
+                        chunks: Vec::new(),
+                        metadata: DocumentMetadata {
+                            source: options.source.clone(),
+                            ..pending.metadata
+                        },
+                    }
+                })
+                .collect();
+            let documents = match &known {
+                Some(known) => {
+                    let (kept, mut left_out) =
+                        filter_known(known, &mut document_collection, documents);
+                    dropped.append(&mut left_out);
+                    kept
+                }
+                None => documents,
+            };
+            for document in &documents {
+                writer.write(shard, document)?;
+            }
+        }
+        Ok(())
+    })?;
+    writer.finish(config)?;
+    progress_bar.finish_and_clear();
+
+    if !config.quiet {
+        println!(
+            "{} Output the frequencies state...",
+            style("[3/3]").bold().dim()
+        );
+    }
+
+    write_state(&document_collection, output_state_file, config)?;
+    Ok(dropped)
+}
+
+//
+// The documents that were left out as known files are reported as such,
+// instead of as indexed.
+//
+fn record_dropped(outcomes: &mut Vec<FileOutcome>, dropped: Vec<FileOutcome>) {
+    if !dropped.is_empty() {
+        let names: HashSet<&str> = dropped
+            .iter()
+            .map(|outcome| outcome.file.as_str())
+            .collect();
+        outcomes.retain(|outcome| {
+            outcome.outcome != Outcome::Indexed || !names.contains(outcome.file.as_str())
         });
-    pb.finish_and_clear();
-    results
+    }
+    outcomes.extend(dropped);
 }
 
 pub fn index_paths(
     paths: &[&PathBuf],
     output_state_file: &PathBuf,
-    results_file: &PathBuf,
+    results_file: &Path,
+    log_file: &PathBuf,
+    options: &IndexOptions,
     config: &Configuration,
-) -> io::Result<()> {
-    let document_collection = RefCell::new(DocumentCollection::new());
+) -> io::Result<IndexReport> {
+    let walker = Walker::new(&options.walker)?;
+    let mut document_collection = DocumentCollection::new();
+    let mut spill = SpillBuffer::new(options.memory_budget);
 
     if !config.quiet {
         println!(
             "{} Processing paths to process...",
-            style("[1/5]").bold().dim()
+            style("[1/3]").bold().dim()
         );
     }
 
-    let mut results: Vec<_> = Vec::new();
-    for path in paths.iter() {
-        let mut intermediate_results = index_directory(path, &document_collection, config);
-        results.append(&mut intermediate_results);
+    let mut outcomes: Vec<_> = Vec::new();
+    for (item, path) in paths.iter().enumerate() {
+        outcomes.append(&mut index_directory(
+            path,
+            item,
+            &walker,
+            &mut document_collection,
+            &mut spill,
+            config,
+        )?);
     }
 
+    let dropped = write_index(
+        document_collection,
+        spill,
+        output_state_file,
+        results_file,
+        options,
+        config,
+    )?;
+    record_dropped(&mut outcomes, dropped);
+
+    let report = IndexReport::new(outcomes);
+    write_report(&report, log_file, config)?;
+    Ok(report)
+}
+
+//
+// Every block of the input becomes a document of its own, named by the input
+// and the offset of the block, e.g. `disk.dd@40960`. The outcome is recorded
+// per input, not per block.
+//
+fn index_image(image_path: &Path, block_size: usize, sender: &InputSender) -> Option<()> {
+    let indexed = File::open(image_path).and_then(|file| {
+        for_each_block(BufReader::new(file), block_size, |block| {
+            let name = block_name(image_path, block.offset);
+            let frequencies = chunk_frequencies(&block.chunks);
+            sender
+                .send(Ok(PendingDocument::new(name, block.metadata, frequencies)))
+                .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))
+        })
+    });
+    sender.record(match indexed {
+        Ok(()) => FileOutcome::new(image_path, Outcome::Indexed),
+        Err(e) => FileOutcome::from_error(image_path, &e),
+    })
+}
+
+//
+// Index raw disk images, unallocated space and the like in fixed size blocks,
+// so a query finds where fragments of a known file are on the disk.
+//
+pub fn index_blocks(
+    paths: &[&PathBuf],
+    output_state_file: &PathBuf,
+    results_file: &Path,
+    log_file: &PathBuf,
+    block_size: usize,
+    options: &IndexOptions,
+    config: &Configuration,
+) -> io::Result<IndexReport> {
     if !config.quiet {
         println!(
-            "{} Output the frequencies state...",
-            style("[3/5]").bold().dim()
+            "{} Processing the blocks of the inputs...",
+            style("[1/3]").bold().dim()
         );
     }
 
-    let mut state_output = File::create(output_state_file)?;
-    let doc_ref: &DocumentCollection = &(document_collection.borrow());
-    match config.output_format {
-        OutputFormat::Json => {
-            state_output.write_all(serde_json::to_string_pretty(doc_ref).unwrap().as_bytes())?
-        }
-        OutputFormat::Binary => bincode::serialize_into(state_output, doc_ref).unwrap(),
-    }
+    let mut document_collection = DocumentCollection::new();
+    let mut spill = SpillBuffer::new(options.memory_budget);
+    let mut outcomes = Vec::new();
+    let pb = create_progress_bar(paths.len().try_into().unwrap(), config);
+    let (sender, receiver) = mpsc::sync_channel(PENDING_CAPACITY);
+    let collected = thread::scope(|scope| {
+        scope.spawn(|| {
+            paths
+                .par_iter()
+                .enumerate()
+                .try_for_each_with(sender, |s, (input, path)| {
+                    pb.inc(1);
+                    let sender = InputSender { sender: s, input };
+                    index_image(path, block_size, &sender)?;
+                    sender.finish()
+                })
+        });
+        collect_pending(
+            receiver,
+            None,
+            &mut document_collection,
+            &mut spill,
+            &mut outcomes,
+            false,
+        )
+    });
+    pb.finish_and_clear();
+    collected?;
 
-    if !config.quiet {
-        println!("{} Updating statistics...", style("[4/5]").bold().dim());
-    }
+    let dropped = write_index(
+        document_collection,
+        spill,
+        output_state_file,
+        results_file,
+        options,
+        config,
+    )?;
+    record_dropped(&mut outcomes, dropped);
 
-    let progress_bar: ProgressBar = create_progress_bar(results.len().try_into().unwrap(), config);
-    let reference_collection = document_collection.borrow().copy();
-    let document_collection_mutex = RwLock::new(reference_collection);
-    let updated_results: Vec<Document> = results
-        .into_par_iter()
-        .map(|doc| {
-            let the_collection = document_collection_mutex.read().unwrap();
-            progress_bar.inc(1);
-            Document {
-                file: doc.file.to_string(),
-                chunks: Vec::new(), // Remove the old chunks, we don't need them anymore
-                digest: the_collection.compute_document_digest(&doc.chunks),
-            }
-        })
-        .collect();
-    progress_bar.finish_and_clear();
+    let report = IndexReport::new(outcomes);
+    write_report(&report, log_file, config)?;
+    Ok(report)
+}
 
-    if !config.quiet {
-        println!(
-            "{} Output file database to {}",
-            console::style("[5/5]").bold().dim(),
-            results_file.to_str().expect("Valid filename")
-        );
+//
+// Add stop chunks to an existing index: the given chunks, and the chunks that
+// occur most often in the collection. They are left out of the digests in the
+// database as well, so the files do not have to be indexed again. The chunks
+// that were added are returned, with how often they occur.
+//
+pub fn add_stop_chunks(
+    state_path: &PathBuf,
+    database_path: &Path,
+    chunks: &[u64],
+    most_frequent: usize,
+    dry_run: bool,
+    config: &Configuration,
+) -> io::Result<Vec<(u64, usize)>> {
+    let (mut document_collection, shards) =
+        open_state_and_shards(state_path, database_path, config)?;
+    let mut added: Vec<(u64, usize)> = chunks
+        .iter()
+        .map(|chunk| (*chunk, document_collection.chunk_count(*chunk)))
+        .collect();
+    added.extend(document_collection.most_frequent_chunks(most_frequent));
+    added.retain(|(chunk, _)| !document_collection.is_stop_chunk(*chunk));
+    added.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    added.dedup();
+    if !dry_run {
+        document_collection.add_stop_chunks(added.iter().map(|(chunk, _)| *chunk));
+        write_state(&document_collection, state_path, config)?;
+        let mut writer = IndexWriter::rewrite(database_path, &document_collection, config)?;
+        for (shard, documents) in shards.into_iter().enumerate() {
+            let documents: Vec<Document> = documents
+                .into_par_iter()
+                .map(|document| Document {
+                    digest: document_collection.prune_digest(&document.digest),
+                    ..document
+                })
+                .collect();
+            for document in &documents {
+                writer.write(shard, document)?;
+            }
+        }
+        writer.finish(config)?;
     }
+    Ok(added)
+}
 
-    let res = write_database_state(&updated_results, results_file, config);
-    progress_bar.finish_and_clear();
-    res
+pub fn stop_chunks_for_results(
+    state_path: &PathBuf,
+    database_path: &Path,
+    chunks: &[u64],
+    most_frequent: usize,
+    dry_run: bool,
+    config: &Configuration,
+) -> io::Result<()> {
+    let added = add_stop_chunks(
+        state_path,
+        database_path,
+        chunks,
+        most_frequent,
+        dry_run,
+        config,
+    )?;
+    println!(
+        "{}: {}",
+        if dry_run {
+            "Stop chunks to add"
+        } else {
+            "Stop chunks added"
+        },
+        added.len()
+    );
+    for (chunk, count) in added {
+        println!("  {:016x} ({} times)", chunk, count);
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fbhash::chunker::tests::BadSector;
     use pretty_assertions::assert_eq;
-    use std::path::Path;
-
-    // TODO:
-    //   Move this to a separate testing toolkit?
-    fn eq_lists<T>(a: &[T], b: &[T]) -> bool
-    where
-        T: PartialEq + Ord,
-    {
-        let mut a: Vec<_> = a.iter().collect();
-        let mut b: Vec<_> = b.iter().collect();
-        a.sort();
-        b.sort();
-
-        a == b
-    }
 
     #[test]
-    #[cfg(not(target_os = "windows"))]
-    fn test_get_files_from_path() {
-        let test_path = PathBuf::from("testdata");
-        let result = get_files_from_dir(&test_path);
-        assert!(eq_lists(
-            &[
-                Path::new("testdata/testfile-zero-length").to_owned(),
-                Path::new("testdata/testfile-yes.bin").to_owned(),
-                Path::new("testdata/testfile-zero.bin").to_owned(),
-            ],
-            &result[..]
-        ));
-        assert_eq!(result.len(), 3);
+    fn test_read_error_is_not_indexed() -> io::Result<()> {
+        let name = "testdata/testfile-yes.bin";
+        let reader = File::open(name)?.chain(BadSector);
+        match index_reader(name.to_string(), reader, DocumentMetadata::default()) {
+            Ok(pending) => panic!("{} was indexed", pending.file),
+            Err(outcome) => {
+                assert_eq!(outcome.file, name);
+                assert_eq!(outcome.outcome, Outcome::IoError);
+                assert_eq!(outcome.message.as_deref(), Some("bad sector"));
+            }
+        }
+        let pending = index_file(Path::new(name)).expect("Indexed");
+        assert_eq!(pending.metadata.size, 512);
+        Ok(())
     }
 
     #[test]
-    #[cfg(target_os = "windows")]
-    fn test_get_files_from_path() {
-        let test_path = PathBuf::from("testdata");
-        let result = get_files_from_dir(&test_path);
-        assert!(eq_lists(
-            &[
-                Path::new("testdata\\testfile-yes.bin").to_owned(),
-                Path::new("testdata\\testfile-zero-length").to_owned(),
-                Path::new("testdata\\testfile-zero.bin").to_owned(),
-            ],
-            &result[..]
+    fn test_truncated_archive_member_is_not_indexed() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("evidence.tar.gz");
+        // Content that does not compress, so that cutting the archive in half
+        // cuts the member as well.
+        let mut state: u64 = 1;
+        let content: Vec<u8> = (0..200000)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (state >> 56) as u8
+            })
+            .collect();
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            File::create(&path)?,
+            flate2::Compression::default(),
         ));
-        assert_eq!(result.len(), 3);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, "big.bin", content.as_slice())?;
+        builder.into_inner()?.finish()?;
+        let length = std::fs::metadata(&path)?.len();
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_len(length / 2)?;
+
+        let walker = Walker::new(&WalkerOptions {
+            expand_archives: true,
+            ..Default::default()
+        })?;
+        let results = index_input(&path, &walker);
+        assert!(results.iter().all(|result| result.is_err()));
+        let member = virtual_path(&path, "big.bin");
+        assert!(results.iter().any(|result| match result {
+            Err(outcome) => outcome.file == member && outcome.outcome == Outcome::IoError,
+            Ok(_) => false,
+        }));
+        dir.close()
     }
 }
@@ -0,0 +1,348 @@
+// Copyright 2021 -- 2023 Erwin van Eijk
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included
+// in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+// TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+// SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use hashbrown::HashMap;
+use rayon::prelude::*;
+use std::collections::BTreeSet;
+use std::io;
+use std::path::Path;
+
+use crate::fbhash::query::{open_state_and_database, IndexFiles};
+use crate::fbhash::report::{FileOutcome, Outcome};
+use crate::fbhash::similarities::*;
+use crate::fbhash::utils::*;
+
+//
+// What to do with the files that are known to be of no interest, e.g. the
+// files of a standard operating system install.
+//
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KnownFilter {
+    // Drop the documents that score at least this against a known file.
+    Drop { threshold: f64 },
+    // Give no weight to the chunks that are in at least this many known files.
+    Suppress { min_files: usize },
+}
+
+#[derive(Clone, Debug)]
+pub struct KnownOptions {
+    pub index: IndexFiles,
+    pub filter: KnownFilter,
+}
+
+//
+// An index of known files, used as a whitelist for another index. Only what
+// the filter needs is kept, and it is prepared once when the index is opened
+// rather than for every batch of documents that is filtered.
+//
+pub struct KnownFiles {
+    filter: KnownFilter,
+    // The names of the known files, by their position.
+    files: Vec<String>,
+    // For every chunk, the known files that have it in their digest and its
+    // weight there. The digests are weighed anew with the collection the
+    // documents are weighed with, and put in an inverted index, so a document
+    // is only scored against the known files it shares a chunk with.
+    postings: HashMap<u64, Vec<(usize, f64)>>,
+    norms: Vec<f64>,
+    // The chunks that are in enough known files to be given no weight.
+    common_chunks: BTreeSet<u64>,
+}
+
+impl KnownFiles {
+    pub fn new(
+        collection: DocumentCollection,
+        documents: Vec<Document>,
+        filter: KnownFilter,
+        document_collection: &DocumentCollection,
+    ) -> KnownFiles {
+        let mut known = KnownFiles {
+            filter,
+            files: Vec::new(),
+            postings: HashMap::new(),
+            norms: Vec::new(),
+            common_chunks: BTreeSet::new(),
+        };
+        match filter {
+            KnownFilter::Drop { .. } => {
+                let known_digests: Vec<Vec<(u64, f64)>> = documents
+                    .par_iter()
+                    .map(|known| document_collection.reweigh_digest(&known.digest, &collection))
+                    .collect();
+                for (position, digest) in known_digests.iter().enumerate() {
+                    for (chunk, weight) in digest {
+                        known
+                            .postings
+                            .entry(*chunk)
+                            .or_default()
+                            .push((position, *weight));
+                    }
+                }
+                known.norms = known_digests.iter().map(|digest| norm(digest)).collect();
+                known.files = documents
+                    .into_iter()
+                    .map(|document| document.file)
+                    .collect();
+            }
+            KnownFilter::Suppress { min_files } => {
+                known.common_chunks = common_chunks(&documents, min_files);
+            }
+        }
+        known
+    }
+
+    //
+    // The documents the known files are matched against are weighed with the
+    // document collection.
+    //
+    pub fn open(
+        options: &KnownOptions,
+        document_collection: &DocumentCollection,
+        config: &Configuration,
+    ) -> io::Result<KnownFiles> {
+        let (collection, documents) =
+            open_state_and_database(&options.index.state, &options.index.database, config)?;
+        Ok(KnownFiles::new(
+            collection,
+            documents,
+            options.filter,
+            document_collection,
+        ))
+    }
+
+    //
+    // The known file that is most similar to each of the documents, by the
+    // cosine similarity. A document that shares no chunk with any has no
+    // match.
+    //
+    pub fn best_matches(&self, documents: &[Document]) -> Vec<Option<(f64, String)>> {
+        documents
+            .par_iter()
+            .map(|document| {
+                let mut products: HashMap<usize, f64> = HashMap::new();
+                for (chunk, weight) in &document.digest {
+                    for (known, known_weight) in self.postings.get(chunk).into_iter().flatten() {
+                        *products.entry(*known).or_insert(0.) += weight * known_weight;
+                    }
+                }
+                let document_norm = norm(&document.digest);
+                products
+                    .into_iter()
+                    .map(|(known, product)| {
+                        (
+                            product / (document_norm * self.norms[known]),
+                            self.files[known].as_str(),
+                        )
+                    })
+                    .filter(|(score, _)| !score.is_nan())
+                    .max_by(|a, b| a.0.total_cmp(&b.0).then_with(|| b.1.cmp(a.1)))
+                    .map(|(score, file)| (score, file.to_string()))
+            })
+            .collect()
+    }
+}
+
+// The chunks that are in the digests of at least min_files known files.
+fn common_chunks(documents: &[Document], min_files: usize) -> BTreeSet<u64> {
+    let mut counts: HashMap<u64, usize> = HashMap::new();
+    for document in documents {
+        for (chunk, _) in &document.digest {
+            *counts.entry(*chunk).or_insert(0) += 1;
+        }
+    }
+    counts
+        .into_iter()
+        .filter(|(_, count)| *count >= min_files.max(1))
+        .map(|(chunk, _)| chunk)
+        .collect()
+}
+
+fn norm(digest: &[(u64, f64)]) -> f64 {
+    digest
+        .iter()
+        .map(|(_, weight)| weight * weight)
+        .sum::<f64>()
+        .sqrt()
+}
+
+//
+// Apply the filter to documents that were weighed with the collection. The
+// documents that are kept are returned, together with an outcome for every
+// document that was dropped. Dropped files are removed from the collection,
+// suppressed chunks are recorded in it, so the digests of the files that are
+// queried against it later on leave them out as well.
+//
+pub fn filter_known(
+    known: &KnownFiles,
+    document_collection: &mut DocumentCollection,
+    documents: Vec<Document>,
+) -> (Vec<Document>, Vec<FileOutcome>) {
+    match known.filter {
+        KnownFilter::Drop { threshold } => {
+            let matches = known.best_matches(&documents);
+            let mut kept = Vec::new();
+            let mut dropped = Vec::new();
+            for (document, best) in documents.into_iter().zip(matches) {
+                match best {
+                    Some((score, file)) if score >= threshold => {
+                        let mut outcome =
+                            FileOutcome::new(Path::new(&document.file), Outcome::KnownFile);
+                        outcome.message = Some(format!("matches {} ({})", file, score));
+                        dropped.push(outcome);
+                    }
+                    _ => kept.push(document),
+                }
+            }
+            let names: Vec<String> = dropped.iter().map(|outcome| outcome.file.clone()).collect();
+            document_collection.remove_files(&names);
+            (kept, dropped)
+        }
+        KnownFilter::Suppress { .. } => {
+            document_collection.add_stop_chunks(known.common_chunks.iter().copied());
+            let kept = documents
+                .into_par_iter()
+                .map(|document| Document {
+                    digest: document_collection.prune_digest(&document.digest),
+                    ..document
+                })
+                .collect();
+            (kept, Vec::new())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn index(names: &[&str]) -> io::Result<(DocumentCollection, Vec<Document>)> {
+        let mut collection = DocumentCollection::new();
+        let mut documents = Vec::new();
+        for name in names {
+            documents.push(collection.add_file(name)?.unwrap());
+        }
+        let documents = documents
+            .into_iter()
+            .map(|document| Document {
+                digest: collection.compute_document_digest(&document.chunks),
+                chunks: Vec::new(),
+                ..document
+            })
+            .collect();
+        Ok((collection, documents))
+    }
+
+    #[test]
+    fn test_common_chunks() -> io::Result<()> {
+        let (_, documents) = index(&["testdata/testfile-yes.bin", "testdata/testfile-zero.bin"])?;
+        assert_eq!(common_chunks(&documents, 1).len(), 3);
+        assert_eq!(common_chunks(&documents, 2).len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_best_matches() -> io::Result<()> {
+        let (known_collection, known_documents) =
+            index(&["testdata/testfile-yes.bin", "testdata/testfile-zero.bin"])?;
+        let (collection, documents) = index(&[
+            "testdata/testfile-zero.bin",
+            "testdata/testfile-zero-length",
+            "testdata/testfile-yes.bin",
+        ])?;
+        let known = KnownFiles::new(
+            known_collection.copy(),
+            known_documents.clone(),
+            KnownFilter::Drop { threshold: 0.9 },
+            &collection,
+        );
+        // The same as scoring every document against every known file.
+        let expected: Vec<Option<(f64, String)>> = documents
+            .iter()
+            .map(|document| {
+                known_documents
+                    .iter()
+                    .map(|other| {
+                        let digest = collection.reweigh_digest(&other.digest, &known_collection);
+                        (
+                            cosine_similarity(&document.digest, &digest),
+                            other.file.clone(),
+                        )
+                    })
+                    .filter(|(score, _)| *score > 0.)
+                    .max_by(|a, b| a.0.total_cmp(&b.0).then_with(|| b.1.cmp(&a.1)))
+            })
+            .collect();
+        let matches = known.best_matches(&documents);
+        assert_eq!(matches.len(), expected.len());
+        for (found, expected) in matches.iter().zip(&expected) {
+            let ((score, file), (expected_score, expected_file)) =
+                (found.clone().unwrap(), expected.clone().unwrap());
+            assert_eq!(file, expected_file);
+            assert!((score - expected_score).abs() < 1e-9);
+        }
+        assert_eq!(matches[2].clone().unwrap().1, "testdata/testfile-yes.bin");
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_known_drop() -> io::Result<()> {
+        let (known_collection, known_documents) = index(&["testdata/testfile-yes.bin"])?;
+        let (mut collection, documents) =
+            index(&["testdata/testfile-yes.bin", "testdata/testfile-zero.bin"])?;
+        let known = KnownFiles::new(
+            known_collection,
+            known_documents,
+            KnownFilter::Drop { threshold: 0.9 },
+            &collection,
+        );
+        let (kept, dropped) = filter_known(&known, &mut collection, documents);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].file, "testdata/testfile-zero.bin");
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].file, "testdata/testfile-yes.bin");
+        assert_eq!(dropped[0].outcome, Outcome::KnownFile);
+        assert!(!collection.exists_file("testdata/testfile-yes.bin"));
+        assert!(collection.exists_file("testdata/testfile-zero.bin"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_known_suppress() -> io::Result<()> {
+        let (known_collection, known_documents) = index(&["testdata/testfile-yes.bin"])?;
+        let (mut collection, documents) =
+            index(&["testdata/testfile-yes.bin", "testdata/testfile-zero.bin"])?;
+        let known = KnownFiles::new(
+            known_collection,
+            known_documents,
+            KnownFilter::Suppress { min_files: 1 },
+            &collection,
+        );
+        let (kept, dropped) = filter_known(&known, &mut collection, documents);
+        assert!(dropped.is_empty());
+        assert_eq!(kept.len(), 2);
+        assert!(kept[0].digest.is_empty());
+        assert_eq!(kept[1].digest.len(), 1);
+        assert!(!collection.is_stop_chunk(kept[1].digest[0].0));
+        let digest = collection.compute_digest("testdata/testfile-yes.bin")?;
+        assert!(digest.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+// Copyright 2021 -- 2023 Erwin van Eijk
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included
+// in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+// TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+// SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::fbhash::format::{deserialize_from, expect_header, write_header};
+use crate::fbhash::similarities::Document;
+use crate::fbhash::utils::*;
+
+//
+// The shape of the locality-sensitive hashing: the MinHash signature of every
+// digest is cut into bands of rows. Documents that have a band in common are
+// the candidates for a query. More bands find more of the similar documents,
+// more rows per band make the candidates fewer, but more similar.
+//
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LshOptions {
+    pub bands: usize,
+    pub rows: usize,
+}
+
+// The signatures are kept next to the database, e.g. `database.json.lsh`.
+pub fn lsh_path(database_path: &Path) -> PathBuf {
+    let mut path = database_path.as_os_str().to_os_string();
+    path.push(".lsh");
+    PathBuf::from(path)
+}
+
+fn mix(value: u64) -> u64 {
+    // The finalizer of SplitMix64.
+    let mut z = value.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+//
+// The MinHash signature of the chunks in a digest: for every one of the hash
+// functions, the smallest hash of any of the chunks. Two digests agree on a
+// row with a chance equal to the Jaccard similarity of their sets of chunks.
+//
+pub fn signature(digest: &[(u64, f64)], length: usize) -> Vec<u64> {
+    let mut signature = vec![u64::MAX; length];
+    for (chunk, _) in digest {
+        for (row, minimum) in signature.iter_mut().enumerate() {
+            let hash = mix(chunk ^ mix(row as u64));
+            if hash < *minimum {
+                *minimum = hash;
+            }
+        }
+    }
+    signature
+}
+
+fn band_keys(signature: &[u64], rows: usize) -> impl Iterator<Item = u64> + '_ {
+    signature
+        .chunks(rows)
+        .map(|band| band.iter().fold(0, |key, value| mix(key ^ value)))
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LshIndex {
+    bands: usize,
+    rows: usize,
+    files: Vec<String>,
+    // For every band, the positions in files of the documents by band key.
+    buckets: Vec<BTreeMap<u64, Vec<usize>>>,
+}
+
+impl LshIndex {
+    pub fn new(options: &LshOptions) -> LshIndex {
+        LshIndex {
+            bands: options.bands,
+            rows: options.rows,
+            files: Vec::new(),
+            buckets: vec![BTreeMap::new(); options.bands],
+        }
+    }
+
+    //
+    // Documents with an empty digest are left out, they would all end up in
+    // the same buckets, and do not score against anything anyway.
+    //
+    pub fn add(&mut self, document: &Document) {
+        if document.digest.is_empty() {
+            return;
+        }
+        let position = self.files.len();
+        let signature = signature(&document.digest, self.bands * self.rows);
+        for (band, key) in band_keys(&signature, self.rows).enumerate() {
+            self.buckets[band].entry(key).or_default().push(position);
+        }
+        self.files.push(document.file.clone());
+    }
+
+    pub fn options(&self) -> LshOptions {
+        LshOptions {
+            bands: self.bands,
+            rows: self.rows,
+        }
+    }
+
+    //
+    // The files that share at least min_band_matches bands with the digest,
+    // in the order they were indexed in.
+    //
+    pub fn candidates(&self, digest: &[(u64, f64)], min_band_matches: usize) -> Vec<&str> {
+        let signature = signature(digest, self.bands * self.rows);
+        let mut matches: BTreeMap<usize, usize> = BTreeMap::new();
+        for (band, key) in band_keys(&signature, self.rows).enumerate() {
+            if let Some(positions) = self.buckets[band].get(&key) {
+                for position in positions {
+                    *matches.entry(*position).or_insert(0) += 1;
+                }
+            }
+        }
+        matches
+            .into_iter()
+            .filter(|(_, count)| *count >= min_band_matches.max(1))
+            .map(|(position, _)| self.files[position].as_str())
+            .collect()
+    }
+
+    //
+    // The binary signatures start with the same header as the state and the
+    // database.
+    //
+    pub fn read(path: &Path, config: &Configuration) -> io::Result<LshIndex> {
+        let file = File::open(path)?;
+        let length = file.metadata()?.len();
+        let mut file = BufReader::new(file);
+        match config.output_format {
+            OutputFormat::Json => Ok(serde_json::from_reader(file)?),
+            OutputFormat::Binary => {
+                expect_header(&mut file)?;
+                deserialize_from(file, length)
+            }
+        }
+    }
+
+    pub fn write(&self, path: &Path, config: &Configuration) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        match config.output_format {
+            OutputFormat::Json => serde_json::to_writer(&mut file, self)?,
+            OutputFormat::Binary => {
+                write_header(&mut file)?;
+                bincode::serialize_into(&mut file, self).map_err(io::Error::other)?;
+            }
+        }
+        file.flush()
+    }
+}
+
+//
+// Picks the candidates for a query from the documents that are loaded, which
+// can be fewer than were indexed, e.g. when known files are left out.
+//
+pub struct LshSearch {
+    index: LshIndex,
+    positions: HashMap<String, usize>,
+    min_band_matches: usize,
+}
+
+impl LshSearch {
+    pub fn new(index: LshIndex, documents: &[Document], min_band_matches: usize) -> LshSearch {
+        LshSearch {
+            index,
+            positions: documents
+                .iter()
+                .enumerate()
+                .map(|(position, document)| (document.file.clone(), position))
+                .collect(),
+            min_band_matches,
+        }
+    }
+
+    pub fn candidates<'a>(
+        &self,
+        digest: &[(u64, f64)],
+        documents: &'a [Document],
+    ) -> Vec<&'a Document> {
+        self.index
+            .candidates(digest, self.min_band_matches)
+            .into_iter()
+            .filter_map(|file| self.positions.get(file))
+            .map(|position| &documents[*position])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    fn build(documents: &[Document], options: &LshOptions) -> LshIndex {
+        let mut index = LshIndex::new(options);
+        for document in documents {
+            index.add(document);
+        }
+        index
+    }
+
+    fn document(file: &str, chunks: std::ops::Range<u64>) -> Document {
+        Document {
+            file: file.to_string(),
+            chunks: Vec::new(),
+            digest: chunks.map(|chunk| (chunk, 1.0)).collect(),
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_signature() {
+        let a = signature(&document("a", 0..100).digest, 64);
+        assert_eq!(a.len(), 64);
+        assert_eq!(a, signature(&document("a", 0..100).digest, 64));
+        // Half of the chunks in common, a third of the union.
+        let b = signature(&document("b", 50..150).digest, 64);
+        let agree = a.iter().zip(&b).filter(|(x, y)| x == y).count();
+        assert!(agree > 10 && agree < 35, "{}", agree);
+        assert!(signature(&[], 4).iter().all(|value| *value == u64::MAX));
+    }
+
+    #[test]
+    fn test_candidates() {
+        let documents = vec![
+            document("a", 0..100),
+            document("b", 1..101),
+            document("c", 1000..1100),
+            document("empty", 0..0),
+        ];
+        let index = build(&documents, &LshOptions { bands: 16, rows: 2 });
+        assert_eq!(index.candidates(&documents[0].digest, 1), vec!["a", "b"]);
+        assert_eq!(index.candidates(&documents[2].digest, 1), vec!["c"]);
+        assert!(index.candidates(&[], 1).is_empty());
+        assert!(index.candidates(&documents[0].digest, 17).is_empty());
+
+        let search = LshSearch::new(index, &documents[1..], 1);
+        let candidates = search.candidates(&documents[0].digest, &documents[1..]);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].file, "b");
+    }
+
+    #[test]
+    fn test_read_write() -> io::Result<()> {
+        let dir = tempdir()?;
+        let index = build(&[document("a", 0..10)], &LshOptions { bands: 2, rows: 2 });
+        for output_format in [OutputFormat::Json, OutputFormat::Binary] {
+            let config = Configuration::new(output_format, true);
+            let path = lsh_path(&dir.path().join("database"));
+            index.write(&path, &config)?;
+            assert_eq!(LshIndex::read(&path, &config)?, index);
+        }
+
+        // Signatures without the header, as they were first written.
+        let config = Configuration::new(OutputFormat::Binary, true);
+        let path = lsh_path(&dir.path().join("database"));
+        bincode::serialize_into(File::create(&path)?, &index).map_err(io::Error::other)?;
+        assert_eq!(
+            LshIndex::read(&path, &config).err().map(|e| e.kind()),
+            Some(io::ErrorKind::InvalidData)
+        );
+        dir.close()
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright 2021 -- 2023 Erwin van Eijk
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included
+// in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+// TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+// SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::Metadata;
+use std::io;
+use std::io::Read;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+//
+// What we know about a document, apart from its contents.
+// Times are in seconds since the epoch, when the platform provides them.
+//
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DocumentMetadata {
+    pub size: u64,
+    pub modified: Option<u64>,
+    pub created: Option<u64>,
+    pub sha256: String,
+    pub number_of_chunks: usize,
+    // The evidence item or source the document was taken from.
+    pub source: Option<String>,
+}
+
+fn seconds_since_epoch(time: io::Result<SystemTime>) -> Option<u64> {
+    time.ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+}
+
+fn format_time(seconds: Option<u64>) -> String {
+    match seconds {
+        Some(seconds) => {
+            humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(seconds)).to_string()
+        }
+        None => "-".to_string(),
+    }
+}
+
+impl DocumentMetadata {
+    pub fn from_file_metadata(metadata: &Metadata) -> DocumentMetadata {
+        DocumentMetadata {
+            size: metadata.len(),
+            modified: seconds_since_epoch(metadata.modified()),
+            created: seconds_since_epoch(metadata.created()),
+            ..Default::default()
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "size: {}, chunks: {}, sha256: {}, modified: {}, created: {}, source: {}",
+            self.size,
+            self.number_of_chunks,
+            self.sha256,
+            format_time(self.modified),
+            format_time(self.created),
+            self.source.as_deref().unwrap_or("-")
+        )
+    }
+}
+
+//
+// Passes everything that is read through untouched, while counting and
+// hashing it on the way. This way the cryptographic hash is computed in the
+// same pass as the chunks.
+//
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> HashingReader<R> {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    // Returns the number of bytes read and the hex encoded SHA-256 of them.
+    pub fn finish(self) -> (u64, String) {
+        let hash = self
+            .hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        (self.size, hash)
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::fs::File;
+
+    #[test]
+    fn test_hashing_reader() -> io::Result<()> {
+        let mut reader = HashingReader::new(File::open("testdata/testfile-yes.bin")?);
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+        let (size, hash) = reader.finish();
+        assert_eq!(content.len(), 512);
+        assert_eq!(size, 512);
+        assert_eq!(
+            hash,
+            "2d683c15749fb8b9734e214afb78e40b6d90214f92a2c96bf655635a67daf70d"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_hash() {
+        let (size, hash) = HashingReader::new(io::empty()).finish();
+        assert_eq!(size, 0);
+        assert_eq!(
+            hash,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn test_describe() {
+        let metadata = DocumentMetadata {
+            size: 512,
+            modified: Some(0),
+            created: None,
+            sha256: "abc".to_string(),
+            number_of_chunks: 506,
+            source: Some("laptop".to_string()),
+        };
+        assert_eq!(
+            metadata.describe(),
+            "size: 512, chunks: 506, sha256: abc, modified: 1970-01-01T00:00:00Z, created: -, source: laptop"
+        );
+    }
+}
@@ -0,0 +1,294 @@
+// Copyright 2021 -- 2023 Erwin van Eijk
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included
+// in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+// TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+// SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use crate::fbhash::similarities::{containment, cosine_similarity, Document};
+
+//
+// A way to score how similar a document is to the query, from their digests.
+// Both digests are sorted by chunk. A higher score means more similar.
+//
+pub trait SimilarityMetric: Send + Sync {
+    fn score(&self, query: &[(u64, f64)], document: &[(u64, f64)]) -> f64;
+}
+
+pub struct Cosine;
+
+impl SimilarityMetric for Cosine {
+    fn score(&self, query: &[(u64, f64)], document: &[(u64, f64)]) -> f64 {
+        cosine_similarity(document, query)
+    }
+}
+
+pub struct WeightedJaccard;
+
+impl SimilarityMetric for WeightedJaccard {
+    fn score(&self, query: &[(u64, f64)], document: &[(u64, f64)]) -> f64 {
+        weighted_jaccard(query, document)
+    }
+}
+
+// How much of the query is contained in the document.
+pub struct Containment;
+
+impl SimilarityMetric for Containment {
+    fn score(&self, query: &[(u64, f64)], document: &[(u64, f64)]) -> f64 {
+        containment(query, document)
+    }
+}
+
+//
+// Okapi BM25, with the weights of the chunks in the query taking the place of
+// the inverse document frequency, and the weights in the document that of the
+// term frequency. Long documents are penalised relative to the average length
+// of the digests in the database.
+//
+pub struct Bm25 {
+    k1: f64,
+    b: f64,
+    average_length: f64,
+}
+
+impl Bm25 {
+    pub fn new<'a, I: IntoIterator<Item = &'a Document>>(documents: I) -> Bm25 {
+        let (total, count) = documents.into_iter().fold((0, 0), |(total, count), doc| {
+            (total + doc.digest.len(), count + 1)
+        });
+        Bm25 {
+            k1: 1.2,
+            b: 0.75,
+            average_length: if count == 0 {
+                0.
+            } else {
+                total as f64 / count as f64
+            },
+        }
+    }
+}
+
+impl SimilarityMetric for Bm25 {
+    fn score(&self, query: &[(u64, f64)], document: &[(u64, f64)]) -> f64 {
+        if self.average_length == 0. {
+            return 0.;
+        }
+        let length_norm =
+            self.k1 * (1. - self.b + self.b * document.len() as f64 / self.average_length);
+        merge_digests(query, document)
+            .filter_map(|(q, d)| match (q, d) {
+                (Some(q), Some(d)) => {
+                    let d = d.abs();
+                    Some(q.abs() * d * (self.k1 + 1.) / (d + length_norm))
+                }
+                _ => None,
+            })
+            .fold(0., |sum, score| sum + score)
+    }
+}
+
+//
+// Walk two digests that are sorted by chunk side by side, pairing the weights
+// of the chunks they have in common.
+//
+fn merge_digests<'a>(
+    vec1: &'a [(u64, f64)],
+    vec2: &'a [(u64, f64)],
+) -> impl Iterator<Item = (Option<f64>, Option<f64>)> + 'a {
+    let mut left = vec1.iter().peekable();
+    let mut right = vec2.iter().peekable();
+    std::iter::from_fn(move || match (left.peek(), right.peek()) {
+        (Some((k1, v1)), Some((k2, v2))) => {
+            if k1 < k2 {
+                left.next();
+                Some((Some(*v1), None))
+            } else if k2 < k1 {
+                right.next();
+                Some((None, Some(*v2)))
+            } else {
+                let pair = (Some(*v1), Some(*v2));
+                left.next();
+                right.next();
+                Some(pair)
+            }
+        }
+        (Some((_, v1)), None) => {
+            let v1 = *v1;
+            left.next();
+            Some((Some(v1), None))
+        }
+        (None, Some((_, v2))) => {
+            let v2 = *v2;
+            right.next();
+            Some((None, Some(v2)))
+        }
+        (None, None) => None,
+    })
+}
+
+//
+// The sum of the smallest over the sum of the largest weight of every chunk,
+// between 0 and 1. As with containment, the magnitude of the weights is used.
+//
+pub fn weighted_jaccard(vec1: &[(u64, f64)], vec2: &[(u64, f64)]) -> f64 {
+    let (minimum, maximum) =
+        merge_digests(vec1, vec2).fold((0_f64, 0_f64), |(minimum, maximum), pair| {
+            let a = pair.0.map_or(0., f64::abs);
+            let b = pair.1.map_or(0., f64::abs);
+            (minimum + a.min(b), maximum + a.max(b))
+        });
+    if maximum > 0. {
+        minimum / maximum
+    } else {
+        0.
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricKind {
+    Cosine,
+    WeightedJaccard,
+    Containment,
+    Bm25,
+}
+
+impl MetricKind {
+    pub const NAMES: [&'static str; 4] = ["cosine", "jaccard", "containment", "bm25"];
+
+    // Some metrics need to know about the whole database they score against.
+    pub fn build<'a, I: IntoIterator<Item = &'a Document>>(
+        &self,
+        documents: I,
+    ) -> Box<dyn SimilarityMetric> {
+        match self {
+            MetricKind::Cosine => Box::new(Cosine),
+            MetricKind::WeightedJaccard => Box::new(WeightedJaccard),
+            MetricKind::Containment => Box::new(Containment),
+            MetricKind::Bm25 => Box::new(Bm25::new(documents)),
+        }
+    }
+}
+
+impl FromStr for MetricKind {
+    type Err = io::Error;
+
+    fn from_str(name: &str) -> io::Result<MetricKind> {
+        match name {
+            "cosine" => Ok(MetricKind::Cosine),
+            "jaccard" => Ok(MetricKind::WeightedJaccard),
+            "containment" => Ok(MetricKind::Containment),
+            "bm25" => Ok(MetricKind::Bm25),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown metric {}", name),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for MetricKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MetricKind::Cosine => "cosine",
+            MetricKind::WeightedJaccard => "jaccard",
+            MetricKind::Containment => "containment",
+            MetricKind::Bm25 => "bm25",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn document(digest: Vec<(u64, f64)>) -> Document {
+        Document {
+            file: String::new(),
+            chunks: Vec::new(),
+            digest,
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_merge_digests() {
+        let vec1 = vec![(1, 1.0), (3, 3.0)];
+        let vec2 = vec![(2, 2.0), (3, 4.0), (5, 5.0)];
+        assert_eq!(
+            merge_digests(&vec1, &vec2).collect::<Vec<_>>(),
+            vec![
+                (Some(1.0), None),
+                (None, Some(2.0)),
+                (Some(3.0), Some(4.0)),
+                (None, Some(5.0))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_weighted_jaccard() {
+        let vec1 = vec![(0, 1.0), (1, -0.5)];
+        let vec2 = vec![(1, 0.25), (2, 1.0)];
+        assert!(approx_eq!(
+            f64,
+            weighted_jaccard(&vec1, &vec1),
+            1.0,
+            ulps = 2
+        ));
+        assert!(approx_eq!(
+            f64,
+            weighted_jaccard(&vec1, &vec2),
+            0.25 / 2.5,
+            ulps = 2
+        ));
+        assert!(approx_eq!(f64, weighted_jaccard(&[], &[]), 0.0, ulps = 2));
+    }
+
+    #[test]
+    fn test_bm25_prefers_shorter_documents() {
+        let query = vec![(1, 1.0)];
+        let short = vec![(1, 0.5)];
+        let long = vec![(1, 0.5), (2, 0.5), (3, 0.5)];
+        let metric = Bm25::new(&[document(short.clone()), document(long.clone())]);
+        assert!(metric.score(&query, &short) > metric.score(&query, &long));
+        assert!(approx_eq!(
+            f64,
+            metric.score(&query, &[(2, 0.5)]),
+            0.0,
+            ulps = 2
+        ));
+    }
+
+    #[test]
+    fn test_metric_kind() -> io::Result<()> {
+        for name in MetricKind::NAMES {
+            assert_eq!(name.parse::<MetricKind>()?.to_string(), name);
+        }
+        assert!("euclid".parse::<MetricKind>().is_err());
+        let query = vec![(1, 1.0)];
+        let whole = vec![(1, 1.0), (2, 1.0)];
+        let metric = MetricKind::Containment.build(&[]);
+        assert!(approx_eq!(f64, metric.score(&query, &whole), 1.0, ulps = 2));
+        Ok(())
+    }
+}
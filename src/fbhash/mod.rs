@@ -1,9 +1,43 @@
+pub mod archive;
+
+pub mod blocks;
+
 pub mod chunker;
 
+pub mod compact;
+
+pub mod compare;
+
+pub mod explain;
+
+pub mod format;
+
+pub mod shard;
+
 pub mod similarities;
 
+pub mod spill;
+
 pub mod index;
 
+pub mod known;
+
+pub mod lsh;
+
+pub mod metadata;
+
+pub mod metrics;
+
 pub mod query;
 
-pub mod utils;
\ No newline at end of file
+pub mod report;
+
+pub mod serve;
+
+pub mod settings;
+
+pub mod utils;
+
+pub mod walker;
+
+pub mod watch;
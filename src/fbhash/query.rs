@@ -18,157 +18,876 @@
 // TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
 // SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+use base64::Engine;
 use hashbrown::HashSet;
-use std::cmp::Ordering;
+use indicatif::ProgressBar;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs::File;
+use std::io;
 use std::io::BufRead;
 use std::io::BufReader;
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
 
+use crate::fbhash::compact::{CompactDocument, Quantization};
+use crate::fbhash::format::*;
+use crate::fbhash::known::{filter_known, KnownFiles, KnownOptions};
+use crate::fbhash::lsh::{lsh_path, LshIndex, LshSearch};
+use crate::fbhash::metadata::DocumentMetadata;
+use crate::fbhash::metrics::*;
+use crate::fbhash::shard::database_paths;
 use crate::fbhash::similarities::*;
 use crate::fbhash::utils::*;
+use crate::fbhash::walker::{Walker, WalkerOptions};
 
 fn read_database_in_json<R: BufRead>(
     file: &mut R,
     expected_files: usize,
+    encoding: Option<Quantization>,
     config: &Configuration,
 ) -> Result<Vec<Document>, std::io::Error> {
     let progress_bar = create_progress_bar(expected_files as u64, config);
     let mut documents: Vec<Document> = Vec::new();
     for line in file.lines() {
-        match line {
-            Ok(ok_line) => {
-                let doc: Document = serde_json::from_str(ok_line.as_str()).unwrap();
-                documents.push(doc.clone());
-                progress_bar.inc(1);
-                progress_bar.set_message(format!("{:?}", doc.file.as_str()));
-            }
-            Err(v) => panic!("{}", v),
-        }
+        let line = line?;
+        let doc: Document = match encoding {
+            Some(_) => serde_json::from_str::<CompactDocument>(line.as_str())?.into_document()?,
+            None => serde_json::from_str(line.as_str())?,
+        };
+        progress_bar.inc(1);
+        progress_bar.set_message(format!("{:?}", doc.file.as_str()));
+        documents.push(doc);
     }
     progress_bar.finish_and_clear();
     Ok(documents)
 }
 
+//
+// The binary database starts with a header, without one it has the layout of
+// the first release and is converted.
+//
 fn read_database_binary<R: BufRead>(
     file: &mut R,
-    expected_files: usize,
+    length: u64,
+    encoding: Option<Quantization>,
     config: &Configuration,
 ) -> Result<Vec<Document>, std::io::Error> {
-    let progress_bar = create_progress_bar(expected_files as u64, config);
-    let documents: Vec<Document> = bincode::deserialize_from(progress_bar.wrap_read(file)).unwrap();
+    let progress_bar = create_progress_bar(length, config);
+    let mut input = progress_bar.wrap_read(file);
+    let documents: Vec<Document> = match (read_header(&mut input)?, encoding) {
+        (Layout::Legacy(start), _) => read_legacy_documents(start, input, length)?,
+        (Layout::Current, Some(_)) => {
+            let compact: Vec<CompactDocument> = deserialize_from(input, length)?;
+            compact
+                .into_par_iter()
+                .map(CompactDocument::into_document)
+                .collect::<io::Result<Vec<Document>>>()?
+        }
+        (Layout::Current, None) => deserialize_from(input, length)?,
+    };
     progress_bar.finish_and_clear();
     Ok(documents)
 }
 
-fn verify_consistency(_document_collection: &DocumentCollection, _documents: &[Document]) -> bool {
-    let document_name_set: HashSet<String> = _documents.iter().map(|d| d.file.clone()).collect();
+fn verify_consistency(
+    _document_collection: &DocumentCollection,
+    _shards: &[Vec<Document>],
+) -> bool {
+    let document_name_set: HashSet<String> =
+        _shards.iter().flatten().map(|d| d.file.clone()).collect();
     let all_collection_in_documents = _document_collection
         .get_files()
         .iter()
         .all(|f| document_name_set.contains(f));
-    let all_documents_in_collection = _documents
+    let all_documents_in_collection = _shards
         .iter()
+        .flatten()
         .all(|d| _document_collection.exists_file(&d.file));
     all_collection_in_documents && all_documents_in_collection
 }
 
-fn open_state_and_database(
+pub fn read_state(
     state_path: &PathBuf,
-    database_path: &PathBuf,
     config: &Configuration,
-) -> Result<(DocumentCollection, Vec<Document>), std::io::Error> {
+) -> Result<DocumentCollection, std::io::Error> {
     let state_file = File::open(state_path)?;
-    let progress_bar = create_progress_bar(state_file.metadata()?.len(), config);
-    progress_bar.println(format!(
-        "Reading state from path: {}",
-        state_path.to_str().expect("Valid filename")
-    ));
+    let length = state_file.metadata()?.len();
+    let progress_bar = create_progress_bar(length, config);
+    progress_bar.println(format!("Reading state from path: {}", state_path.display()));
     let document_collection: DocumentCollection = match config.output_format {
         OutputFormat::Json => serde_json::from_reader(&mut progress_bar.wrap_read(state_file))?,
         OutputFormat::Binary => {
-            bincode::deserialize_from(&mut progress_bar.wrap_read(state_file)).unwrap()
+            let mut input = BufReader::new(progress_bar.wrap_read(state_file));
+            match read_header(&mut input) {
+                Ok(Layout::Current) => deserialize_from(input, length),
+                Ok(Layout::Legacy(start)) => read_legacy_state(start, input, length),
+                Err(e) => Err(e),
+            }
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid state {}: {}", state_path.display(), e),
+                )
+            })?
         }
     };
+    progress_bar.finish_and_clear();
+    Ok(document_collection)
+}
 
+fn read_database(
+    database_path: &Path,
+    document_collection: &DocumentCollection,
+    config: &Configuration,
+) -> io::Result<Vec<Document>> {
+    let inner_file = File::open(database_path)?;
+    let expected_length = inner_file.metadata()?.len();
+    let mut file = BufReader::new(inner_file);
+    match config.output_format {
+        OutputFormat::Json => read_database_in_json(
+            &mut file,
+            document_collection.number_of_files(),
+            document_collection.get_digest_encoding(),
+            config,
+        ),
+        OutputFormat::Binary => read_database_binary(
+            &mut file,
+            expected_length,
+            document_collection.get_digest_encoding(),
+            config,
+        )
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid database {}: {}", database_path.display(), e),
+            )
+        }),
+    }
+}
+
+//
+// The documents are read per shard of the database, the shards in parallel.
+// A database without shards is read as a single shard.
+//
+pub fn open_state_and_shards(
+    state_path: &PathBuf,
+    database_path: &Path,
+    config: &Configuration,
+) -> Result<(DocumentCollection, Vec<Vec<Document>>), std::io::Error> {
+    let document_collection = read_state(state_path, config)?;
+    let progress_bar = create_progress_bar(0, config);
     progress_bar.println(format!(
         "Reading the database with the files: {}",
-        database_path.to_str().expect("Valid filename")
+        database_path.display()
     ));
     progress_bar.finish_and_clear();
-    let inner_file = File::open(database_path)?;
-    let expected_length = inner_file.metadata()?.len();
-    let mut file = BufReader::new(inner_file);
-    let documents: Vec<Document> = match config.output_format {
-        OutputFormat::Json => {
-            read_database_in_json(&mut file, document_collection.number_of_files(), config)?
-        }
-        OutputFormat::Binary => read_database_binary(&mut file, expected_length as usize, config)?,
-    };
-    if !verify_consistency(&document_collection, &documents) {
+    let shards = database_paths(database_path, document_collection.get_shards())
+        .par_iter()
+        .map(|path| read_database(path, &document_collection, config))
+        .collect::<io::Result<Vec<Vec<Document>>>>()?;
+    if !verify_consistency(&document_collection, &shards) {
         Err(std::io::Error::new(
             std::io::ErrorKind::InvalidInput,
             format!(
                 "{} and {} are not consistent",
-                state_path.to_str().expect("Valid filename"),
-                database_path.to_str().expect("Valid filename")
+                state_path.display(),
+                database_path.display()
             ),
         ))
     } else {
-        Ok((document_collection, documents))
+        Ok((document_collection, shards))
+    }
+}
+
+pub fn open_state_and_database(
+    state_path: &PathBuf,
+    database_path: &Path,
+    config: &Configuration,
+) -> Result<(DocumentCollection, Vec<Document>), std::io::Error> {
+    let (document_collection, shards) = open_state_and_shards(state_path, database_path, config)?;
+    Ok((document_collection, shards.into_iter().flatten().collect()))
+}
+
+//
+// How the queries are answered.
+//
+#[derive(Clone, Debug)]
+pub struct QueryOptions {
+    pub number_of_results: usize,
+    pub show_metadata: bool,
+    pub metric_kind: MetricKind,
+    // Leave the documents that are known files out of the results.
+    pub known: Option<KnownOptions>,
+    // Only score the candidates that the signatures of the index pick.
+    pub approximate: Option<ApproximateOptions>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ApproximateOptions {
+    // How many bands a document needs to share with the query to be scored,
+    // more is faster, but finds fewer of the similar documents.
+    pub min_band_matches: usize,
+    // Also search all documents, and show how many of those results were found.
+    pub verify: bool,
+}
+
+impl Default for QueryOptions {
+    fn default() -> QueryOptions {
+        QueryOptions {
+            number_of_results: 5,
+            show_metadata: false,
+            metric_kind: MetricKind::Cosine,
+            known: None,
+            approximate: None,
+        }
+    }
+}
+
+//
+// The files to query: the given files, the files in the given directories,
+// and the files listed, one per line, in list_file and on standard input.
+//
+pub fn collect_query_files(
+    paths: &[PathBuf],
+    list_file: Option<&PathBuf>,
+    from_stdin: bool,
+) -> io::Result<Vec<PathBuf>> {
+    let walker = Walker::new(&WalkerOptions::default())?;
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let (mut found, _) = walker.get_files_from_dir(path);
+            found.sort();
+            files.append(&mut found);
+        } else {
+            files.push(path.clone());
+        }
+    }
+    let mut add_lines = |reader: &mut dyn BufRead| -> io::Result<()> {
+        for line in reader.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                files.push(PathBuf::from(line.trim()));
+            }
+        }
+        Ok(())
+    };
+    if let Some(list_file) = list_file {
+        add_lines(&mut BufReader::new(File::open(list_file)?))?;
+    }
+    if from_stdin {
+        add_lines(&mut io::stdin().lock())?;
+    }
+    Ok(files)
+}
+
+fn format_results(
+    file_name: &str,
+    query_document: &Document,
+    results: &[(f64, Document)],
+    options: &QueryOptions,
+) -> String {
+    let mut output = format!(
+        "Similarities for {}\nResults: {}\n",
+        file_name,
+        results.len()
+    );
+    for result in results {
+        // With another metric, the cosine similarity is shown next to it.
+        // For containment, so is how much of the match is in turn
+        // contained in the query.
+        let cosine = cosine_similarity(&result.1.digest, &query_document.digest);
+        let scores = match options.metric_kind {
+            MetricKind::Cosine => String::new(),
+            MetricKind::Containment => format!(
+                " (cosine: {}, reverse containment: {})",
+                cosine,
+                containment(&result.1.digest, &query_document.digest)
+            ),
+            _ => format!(" (cosine: {})", cosine),
+        };
+        output.push_str(&format!(
+            "{} => ({}) {}{}{}\n",
+            file_name,
+            result.0,
+            result.1.file,
+            scores,
+            if result.1.is_identical_to(query_document) {
+                " [identical]"
+            } else {
+                ""
+            }
+        ));
+        if options.show_metadata {
+            output.push_str(&format!("    {}\n", result.1.metadata.describe()));
+        }
     }
+    output
+}
+
+//
+// The results of a query in a form that serializes to JSON, for the programs
+// that keep an index loaded and answer queries for other tools.
+//
+#[derive(Clone, Debug, Serialize)]
+pub struct QueryResults {
+    pub query: String,
+    pub results: Vec<QueryMatch>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct QueryMatch {
+    pub file: String,
+    pub score: f64,
+    pub identical: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<DocumentMetadata>,
+}
+
+impl QueryResults {
+    pub fn new(
+        name: &str,
+        query_document: &Document,
+        results: &[(f64, Document)],
+        options: &QueryOptions,
+    ) -> QueryResults {
+        QueryResults {
+            query: name.to_string(),
+            results: results
+                .iter()
+                .map(|(score, document)| QueryMatch {
+                    file: document.file.clone(),
+                    score: *score,
+                    identical: document.is_identical_to(query_document),
+                    metadata: if options.show_metadata {
+                        Some(document.metadata.clone())
+                    } else {
+                        None
+                    },
+                })
+                .collect(),
+        }
+    }
+}
+
+//
+// The documents of a shard of the database, with their signatures when the
+// queries are approximate.
+//
+struct Shard {
+    documents: Vec<Document>,
+    lsh: Option<LshSearch>,
 }
 
+//
+// An index that is loaded to answer queries: the collection, and the
+// documents of every shard. The known files are left out, and the signatures
+// are read when the queries are approximate.
+//
+pub struct QueryIndex {
+    document_collection: DocumentCollection,
+    shards: Vec<Shard>,
+}
+
+impl QueryIndex {
+    pub fn open(
+        state_path: &PathBuf,
+        database_path: &Path,
+        options: &QueryOptions,
+        config: &Configuration,
+    ) -> io::Result<QueryIndex> {
+        let (mut document_collection, shards) =
+            open_state_and_shards(state_path, database_path, config)?;
+        let known_files = match &options.known {
+            Some(known) => Some(KnownFiles::open(known, &document_collection, config)?),
+            None => None,
+        };
+        let paths = database_paths(database_path, document_collection.get_shards());
+        let mut number_of_dropped = 0;
+        let shards = paths
+            .iter()
+            .zip(shards)
+            .map(|(path, documents)| {
+                let documents = match &known_files {
+                    Some(known_files) => {
+                        let (kept, dropped) =
+                            filter_known(known_files, &mut document_collection, documents);
+                        number_of_dropped += dropped.len();
+                        kept
+                    }
+                    None => documents,
+                };
+                let lsh = match options.approximate {
+                    Some(approximate) => Some(LshSearch::new(
+                        read_lsh_index(path, config)?,
+                        &documents,
+                        approximate.min_band_matches,
+                    )),
+                    None => None,
+                };
+                Ok(Shard { documents, lsh })
+            })
+            .collect::<io::Result<Vec<Shard>>>()?;
+        if known_files.is_some() && !config.quiet {
+            println!("Known files left out of the results: {}", number_of_dropped);
+        }
+        Ok(QueryIndex {
+            document_collection,
+            shards,
+        })
+    }
+
+    pub fn document_collection(&self) -> &DocumentCollection {
+        &self.document_collection
+    }
+
+    pub fn number_of_documents(&self) -> usize {
+        self.shards.iter().map(|shard| shard.documents.len()).sum()
+    }
+
+    pub fn number_of_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn metric(&self, metric_kind: MetricKind) -> Box<dyn SimilarityMetric> {
+        metric_kind.build(self.shards.iter().flat_map(|shard| &shard.documents))
+    }
+
+    //
+    // The best matches of the query in all shards. The search is approximate
+    // when the signatures were read for it.
+    //
+    pub fn search(
+        &self,
+        query_document: &Document,
+        metric: &dyn SimilarityMetric,
+        options: &QueryOptions,
+    ) -> Vec<(f64, Document)> {
+        let approximate = options.approximate.is_some();
+        sorted_search(query_document, &self.shards, metric, approximate, options)
+    }
+}
+
+fn query_one(
+    index: &QueryIndex,
+    metric: &dyn SimilarityMetric,
+    file_name: &str,
+    options: &QueryOptions,
+) -> io::Result<String> {
+    let query_document = index
+        .document_collection
+        .compute_query_document(file_name)?;
+    let results = index.search(&query_document, metric, options);
+    let mut output = format_results(file_name, &query_document, &results, options);
+    if matches!(options.approximate, Some(approximate) if approximate.verify) {
+        let exact = sorted_search(&query_document, &index.shards, metric, false, options);
+        let found = exact
+            .iter()
+            .filter(|(_, document)| results.iter().any(|(_, other)| other.file == document.file))
+            .count();
+        output.push_str(&format!(
+            "Recall against the exact search: {}/{}\n",
+            found,
+            exact.len()
+        ));
+    }
+    Ok(output)
+}
+
+//
+// The shards are searched in parallel, and the best results of all shards
+// are merged. Approximate searches only score the candidates of every shard.
+//
+fn sorted_search(
+    query_document: &Document,
+    shards: &[Shard],
+    metric: &dyn SimilarityMetric,
+    approximate: bool,
+    options: &QueryOptions,
+) -> Vec<(f64, Document)> {
+    let mut results: Vec<(f64, Document)> = shards
+        .par_iter()
+        .flat_map_iter(|shard| match shard.lsh.as_ref().filter(|_| approximate) {
+            Some(lsh) => ranked_search(
+                &query_document.digest,
+                lsh.candidates(&query_document.digest, &shard.documents),
+                options.number_of_results,
+                &ProgressBar::hidden(),
+                metric,
+            ),
+            None => ranked_search(
+                &query_document.digest,
+                &shard.documents,
+                options.number_of_results,
+                &ProgressBar::hidden(),
+                metric,
+            ),
+        })
+        .collect();
+    // The sort is stable, so the shards keep the order they were searched in.
+    results.sort_by(compare_results);
+    results.truncate(options.number_of_results);
+    results
+}
+
+fn read_lsh_index(database_path: &Path, config: &Configuration) -> io::Result<LshIndex> {
+    let path = lsh_path(database_path);
+    LshIndex::read(&path, config).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "Cannot read the signatures in {}, index with --lsh-bands: {}",
+                path.to_string_lossy(),
+                e
+            ),
+        )
+    })
+}
+
+//
+// The queries are answered in parallel, against the same loaded database.
+// The results of every query are printed as soon as they are known, so with
+// more than one query they come out in the order they are done in. A query
+// that fails does not stop the others, the first error is returned at the end.
+// When the output is closed, e.g. when piped into head, the queries stop.
+//
 pub fn query_for_results(
     state_path: &PathBuf,
-    database_path: &PathBuf,
-    files: &[&PathBuf],
-    number_of_results: usize,
+    database_path: &Path,
+    files: &[PathBuf],
+    options: &QueryOptions,
     config: &Configuration,
 ) -> std::result::Result<(), std::io::Error> {
-    let (document_collection, documents) =
-        open_state_and_database(state_path, database_path, config)?;
-    for file_name in files {
-        let document = document_collection
-            .compute_digest(file_name.to_str().expect("Valid filename"))
-            .ok()
-            .unwrap();
-        let progress_bar =
-            create_progress_bar(document_collection.number_of_files() as u64, config);
-        if !config.quiet {
-            progress_bar.println("Compute the files that are most similar in the set");
-        }
-        let mut results = ranked_search(&document, &documents, number_of_results, &progress_bar);
-        // For better testing purposes, the result is sorted by priority, file,
-        // so the output can be predictable.
-        results.sort_by(|a, b| {
-            if a.0 < b.0 {
-                Ordering::Less
-            } else if a.0 > b.0 {
-                Ordering::Greater
-            } else {
-                a.1.file.cmp(&b.1.file)
+    let index = QueryIndex::open(state_path, database_path, options, config)?;
+    let metric = index.metric(options.metric_kind);
+    let progress_bar = create_progress_bar(files.len() as u64, config);
+    if !config.quiet {
+        if let Some(digest_cap) = index.document_collection.get_digest_cap() {
+            progress_bar.println(format!("The digests only keep {}", digest_cap));
+        }
+        progress_bar.println("Compute the files that are most similar in the set");
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let mut first_error = None;
+    let mut output_error = None;
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            files.par_iter().try_for_each_with(sender, |s, file_name| {
+                let name = file_name.to_string_lossy();
+                let result = query_one(&index, metric.as_ref(), &name, options)
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", name, e)));
+                s.send(result)
+            })
+        });
+        let stdout = io::stdout();
+        for result in receiver {
+            progress_bar.inc(1);
+            let written = match result {
+                Ok(output) => writeln!(stdout.lock(), "{}", output),
+                Err(e) => {
+                    progress_bar.suspend(|| eprintln!("{}", e));
+                    first_error.get_or_insert(e);
+                    Ok(())
+                }
+            };
+            if let Err(e) = written {
+                if e.kind() != io::ErrorKind::BrokenPipe {
+                    output_error = Some(e);
+                }
+                break;
             }
+        }
+    });
+    progress_bar.finish_and_clear();
+
+    match output_error.or(first_error) {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+//
+// A request of the interactive mode: the file to query, or its contents in
+// base64 with a name to show in the results, and what to change of the
+// options the queries are answered with.
+//
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InteractiveRequest {
+    path: Option<String>,
+    data: Option<String>,
+    name: Option<String>,
+    n: Option<usize>,
+    metric: Option<String>,
+    metadata: Option<bool>,
+}
+
+impl InteractiveRequest {
+    //
+    // A line is either a request in JSON, or just the path of a file.
+    //
+    fn parse(line: &str) -> io::Result<InteractiveRequest> {
+        if line.starts_with('{') {
+            Ok(serde_json::from_str(line)?)
+        } else {
+            Ok(InteractiveRequest {
+                path: Some(line.to_string()),
+                ..Default::default()
+            })
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorMessage {
+    pub error: String,
+}
+
+fn answer_request(
+    index: &QueryIndex,
+    metric: &dyn SimilarityMetric,
+    request: &InteractiveRequest,
+    options: &QueryOptions,
+) -> io::Result<QueryResults> {
+    let mut request_options = options.clone();
+    if let Some(number_of_results) = request.n {
+        request_options.number_of_results = number_of_results;
+    }
+    if let Some(metric) = &request.metric {
+        request_options.metric_kind = metric.parse()?;
+    }
+    if let Some(show_metadata) = request.metadata {
+        request_options.show_metadata = show_metadata;
+    }
+    let (name, query_document) = match (&request.path, &request.data) {
+        (Some(path), None) => {
+            let query_document = index
+                .document_collection
+                .compute_query_document(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+            (path.as_str(), query_document)
+        }
+        (None, Some(data)) => {
+            let contents = base64::engine::general_purpose::STANDARD
+                .decode(data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let name = request.name.as_deref().unwrap_or("data");
+            let query_document = index
+                .document_collection
+                .compute_query_document_from_reader(name, contents.as_slice())?;
+            (name, query_document)
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A request has either a path or data",
+            ))
+        }
+    };
+    let other_metric;
+    let metric = if request_options.metric_kind == options.metric_kind {
+        metric
+    } else {
+        other_metric = index.metric(request_options.metric_kind);
+        other_metric.as_ref()
+    };
+    let results = index.search(&query_document, metric, &request_options);
+    Ok(QueryResults::new(
+        name,
+        &query_document,
+        &results,
+        &request_options,
+    ))
+}
+
+//
+// Load the index once, and answer the requests on standard input, one per
+// line, with a line of JSON each on standard output until the input ends. A
+// request that fails is answered with the error, and the next is read.
+//
+pub fn interactive_query(
+    state_path: &PathBuf,
+    database_path: &Path,
+    options: &QueryOptions,
+    config: &Configuration,
+) -> io::Result<()> {
+    // Only the answers go to standard output.
+    let config = Configuration {
+        quiet: true,
+        ..*config
+    };
+    let index = QueryIndex::open(state_path, database_path, options, &config)?;
+    let metric = index.metric(options.metric_kind);
+    let stdout = io::stdout();
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let answer = InteractiveRequest::parse(line)
+            .and_then(|request| answer_request(&index, metric.as_ref(), &request, options))
+            .and_then(|results| Ok(serde_json::to_string(&results)?))
+            .unwrap_or_else(|e| {
+                let message = ErrorMessage {
+                    error: e.to_string(),
+                };
+                serde_json::to_string(&message).unwrap()
+            });
+        let mut out = stdout.lock();
+        writeln!(out, "{}", answer)?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+//
+// An index on disk: the state with the collection, and the database with
+// the documents.
+//
+#[derive(Clone, Debug)]
+pub struct IndexFiles {
+    pub state: PathBuf,
+    pub database: PathBuf,
+}
+
+//
+// Match every document of the target index against the documents of the
+// reference index, without reading the original files again. The digests of
+// both are weighed anew with a common collection: the given one, or both
+// collections merged. The results are printed while the others are searched,
+// and the search stops when the output is closed.
+//
+pub fn cross_query_for_results(
+    reference: &IndexFiles,
+    target: &IndexFiles,
+    weights_path: Option<&PathBuf>,
+    options: &QueryOptions,
+    config: &Configuration,
+) -> io::Result<()> {
+    // The signatures and the known files are of the digests as they were
+    // indexed, not as they are weighed here.
+    if options.known.is_some() || options.approximate.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "A cross query cannot leave out known files or be approximate",
+        ));
+    }
+    let (reference_collection, reference_documents) =
+        open_state_and_database(&reference.state, &reference.database, config)?;
+    let (target_collection, target_documents) =
+        open_state_and_database(&target.state, &target.database, config)?;
+    let common_collection = match weights_path {
+        Some(weights_path) => read_state(weights_path, config)?,
+        None => {
+            let mut merged = reference_collection.copy();
+            merged.extend(&target_collection);
+            merged
+        }
+    };
+    let reweigh = |documents: Vec<Document>, weighed_by: &DocumentCollection| -> Vec<Document> {
+        documents
+            .into_par_iter()
+            .map(|document| Document {
+                digest: common_collection.reweigh_digest(&document.digest, weighed_by),
+                ..document
+            })
+            .collect()
+    };
+    let reference = [Shard {
+        documents: reweigh(reference_documents, &reference_collection),
+        lsh: None,
+    }];
+    let mut target_documents = reweigh(target_documents, &target_collection);
+    target_documents.sort_by(|a, b| a.file.cmp(&b.file));
+
+    let metric = options.metric_kind.build(&reference[0].documents);
+    let progress_bar = create_progress_bar(target_documents.len() as u64, config);
+    let (sender, receiver) = mpsc::channel();
+    let mut output_error = None;
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            target_documents.par_iter().enumerate().try_for_each_with(
+                sender,
+                |s, (position, target_document)| {
+                    let results =
+                        sorted_search(target_document, &reference, metric.as_ref(), false, options);
+                    let output =
+                        format_results(&target_document.file, target_document, &results, options);
+                    s.send((position, output))
+                },
+            )
         });
-        // Get the best results first.
-        results.reverse();
-        progress_bar.finish_and_clear();
-        println!(
-            "Similarities for {}",
-            file_name.to_str().expect("Valid filename")
-        );
-        println!("Results: {}", results.len());
-        for result in &results {
-            println!(
-                "{} => ({}) {}",
-                file_name.to_str().expect("Valid filename"),
-                result.0,
-                result.1.file
-            );
+        // The results are printed in the order of the target documents, as
+        // soon as those before them are.
+        let stdout = io::stdout();
+        let mut waiting = BTreeMap::new();
+        let mut next = 0;
+        for (position, output) in receiver {
+            progress_bar.inc(1);
+            waiting.insert(position, output);
+            while let Some(output) = waiting.remove(&next) {
+                next += 1;
+                if let Err(e) = writeln!(stdout.lock(), "{}", output) {
+                    if e.kind() != io::ErrorKind::BrokenPipe {
+                        output_error = Some(e);
+                    }
+                    return;
+                }
+            }
         }
-        println!();
+    });
+    progress_bar.finish_and_clear();
+
+    match output_error {
+        Some(e) => Err(e),
+        None => Ok(()),
     }
+}
 
+//
+// Group the documents that have exactly the same contents, by their hash.
+// Only groups with more than one document are returned, sorted by hash.
+//
+pub fn group_duplicates(documents: &[Document]) -> Vec<(String, Vec<String>)> {
+    let mut groups: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for document in documents {
+        if !document.metadata.sha256.is_empty() {
+            groups
+                .entry(document.metadata.sha256.as_str())
+                .or_default()
+                .push(document.file.clone());
+        }
+    }
+    groups
+        .into_iter()
+        .filter(|(_, files)| files.len() > 1)
+        .map(|(hash, mut files)| {
+            files.sort();
+            (hash.to_string(), files)
+        })
+        .collect()
+}
+
+pub fn duplicates_for_results(
+    state_path: &PathBuf,
+    database_path: &Path,
+    config: &Configuration,
+) -> std::result::Result<(), std::io::Error> {
+    let (_, documents) = open_state_and_database(state_path, database_path, config)?;
+    let groups = group_duplicates(&documents);
+    println!("Groups of identical files: {}", groups.len());
+    for (hash, files) in groups {
+        println!("{}", hash);
+        for file in files {
+            println!("    {}", file);
+        }
+    }
     Ok(())
 }
 
@@ -176,11 +895,31 @@ pub fn query_for_results(
 mod tests {
     use super::*;
     use crate::fbhash::index::index_paths;
+    use crate::fbhash::index::IndexOptions;
     use float_cmp::approx_eq;
     use pretty_assertions::assert_eq;
     use std::path::Path;
     use tempfile::tempdir;
 
+    #[test]
+    fn test_parse_interactive_request() -> std::io::Result<()> {
+        let by_path = InteractiveRequest::parse("testdata/testfile-yes.bin")?;
+        assert_eq!(by_path.path.as_deref(), Some("testdata/testfile-yes.bin"));
+        assert_eq!(by_path.data, None);
+
+        let by_data = InteractiveRequest::parse(
+            "{\"data\":\"AAEC\",\"name\":\"x\",\"n\":2,\"metric\":\"bm25\"}",
+        )?;
+        assert_eq!(by_data.path, None);
+        assert_eq!(by_data.data.as_deref(), Some("AAEC"));
+        assert_eq!(by_data.name.as_deref(), Some("x"));
+        assert_eq!(by_data.n, Some(2));
+        assert_eq!(by_data.metric.as_deref(), Some("bm25"));
+
+        assert!(InteractiveRequest::parse("{\"file\":\"x\"}").is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_deserialization_from_string() {
         let s = "{\"file\":\"testdata/testfile-zero.bin\",\"chunks\":[],\"digest\":[[0, -8.252427688355256]]}";
@@ -194,6 +933,27 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_cross_query_options() {
+        let index = IndexFiles {
+            state: PathBuf::from("state.json"),
+            database: PathBuf::from("database.json"),
+        };
+        let options = QueryOptions {
+            number_of_results: 5,
+            show_metadata: false,
+            metric_kind: MetricKind::Cosine,
+            known: None,
+            approximate: Some(ApproximateOptions {
+                min_band_matches: 1,
+                verify: false,
+            }),
+        };
+        let config = Configuration::new(OutputFormat::Json, true);
+        let error = cross_query_for_results(&index, &index, None, &options, &config).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
     #[test]
     fn test_full_query() -> Result<(), Box<dyn std::error::Error>> {
         let dir = tempdir()?;
@@ -203,9 +963,16 @@ mod tests {
         let test_data_path = PathBuf::from("testdata");
         let paths: Vec<&PathBuf> = vec![&test_data_path];
         let file_name = Path::new("testdata").join("testfile-yes.bin").to_path_buf();
-        let files = vec![file_name];
+        let files = [file_name];
         // First index everything
-        index_paths(paths.as_slice(), &state_path, &database_file, &config)?;
+        index_paths(
+            paths.as_slice(),
+            &state_path,
+            &database_file,
+            &dir.path().join("index.log"),
+            &IndexOptions::default(),
+            &config,
+        )?;
         // Try and open the resulting file
         let (document_collection, documents) =
             open_state_and_database(&state_path, &database_file, &config)?;
@@ -222,6 +989,7 @@ mod tests {
             &documents,
             document_collection.number_of_files(),
             &progress_bar,
+            &Cosine,
         );
         assert_eq!(
             results[0].1.file,
@@ -232,4 +1000,58 @@ mod tests {
         dir.close()?;
         Ok(())
     }
+
+    #[test]
+    fn test_collect_query_files() -> std::io::Result<()> {
+        let dir = tempdir()?;
+        let list_file = dir.path().join("list.txt");
+        std::fs::write(&list_file, "testdata/testfile-zero.bin\n\n  other.bin \n")?;
+        let files = collect_query_files(
+            &[
+                PathBuf::from("testdata"),
+                PathBuf::from("testdata/testfile-yes.bin"),
+            ],
+            Some(&list_file),
+            false,
+        )?;
+        assert_eq!(
+            files,
+            vec![
+                PathBuf::from("testdata/testfile-yes.bin"),
+                PathBuf::from("testdata/testfile-zero-length"),
+                PathBuf::from("testdata/testfile-zero.bin"),
+                PathBuf::from("testdata/testfile-yes.bin"),
+                PathBuf::from("testdata/testfile-zero.bin"),
+                PathBuf::from("other.bin"),
+            ]
+        );
+        dir.close()
+    }
+
+    #[test]
+    fn test_group_duplicates() -> std::io::Result<()> {
+        let (yes, _) = compute_document("testdata/testfile-yes.bin")?;
+        let (zero, _) = compute_document("testdata/testfile-zero.bin")?;
+        let copy = Document {
+            file: "copy-of-yes.bin".to_string(),
+            ..yes.clone()
+        };
+        let legacy = Document {
+            file: "legacy".to_string(),
+            metadata: Default::default(),
+            ..zero.clone()
+        };
+        let groups = group_duplicates(&[yes, zero, copy, legacy.clone(), legacy]);
+        assert_eq!(
+            groups,
+            vec![(
+                "2d683c15749fb8b9734e214afb78e40b6d90214f92a2c96bf655635a67daf70d".to_string(),
+                vec![
+                    "copy-of-yes.bin".to_string(),
+                    "testdata/testfile-yes.bin".to_string()
+                ]
+            )]
+        );
+        Ok(())
+    }
 }
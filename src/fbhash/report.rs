@@ -0,0 +1,218 @@
+// Copyright 2021 -- 2023 Erwin van Eijk
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included
+// in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+// TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+// SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use crate::fbhash::utils::*;
+
+//
+// What happened to a single file that the walker came across.
+//
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Outcome {
+    Indexed,
+    SkippedByFilter,
+    TooSmall,
+    TooLarge,
+    PermissionDenied,
+    IoError,
+    KnownFile,
+    // Sockets, named pipes, devices and links that lead nowhere to index.
+    NotAFile,
+}
+
+impl Outcome {
+    pub fn from_error(error: &io::Error) -> Outcome {
+        match error.kind() {
+            io::ErrorKind::PermissionDenied => Outcome::PermissionDenied,
+            _ => Outcome::IoError,
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Outcome::Indexed => "indexed",
+            Outcome::SkippedByFilter => "skipped by filter",
+            Outcome::TooSmall => "too small",
+            Outcome::TooLarge => "too large",
+            Outcome::PermissionDenied => "permission denied",
+            Outcome::IoError => "I/O error",
+            Outcome::KnownFile => "known file",
+            Outcome::NotAFile => "not a regular file",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileOutcome {
+    pub file: String,
+    pub outcome: Outcome,
+    pub message: Option<String>,
+}
+
+impl FileOutcome {
+    pub fn new(path: &Path, outcome: Outcome) -> FileOutcome {
+        FileOutcome {
+            file: path.to_string_lossy().to_string(),
+            outcome,
+            message: None,
+        }
+    }
+
+    pub fn with_message(path: &Path, outcome: Outcome, message: &str) -> FileOutcome {
+        FileOutcome {
+            file: path.to_string_lossy().to_string(),
+            outcome,
+            message: Some(message.to_string()),
+        }
+    }
+
+    pub fn from_error(path: &Path, error: &io::Error) -> FileOutcome {
+        FileOutcome {
+            file: path.to_string_lossy().to_string(),
+            outcome: Outcome::from_error(error),
+            message: Some(error.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IndexReport {
+    pub entries: Vec<FileOutcome>,
+}
+
+impl IndexReport {
+    pub fn new(mut entries: Vec<FileOutcome>) -> IndexReport {
+        // Keep the report stable between runs, regardless of thread scheduling.
+        entries.sort_by(|a, b| a.file.cmp(&b.file));
+        IndexReport { entries }
+    }
+
+    pub fn summary(&self) -> BTreeMap<Outcome, usize> {
+        let mut summary = BTreeMap::new();
+        for entry in &self.entries {
+            *summary.entry(entry.outcome).or_insert(0) += 1;
+        }
+        summary
+    }
+
+    pub fn print(&self, all_entries: bool) {
+        println!("Files seen: {}", self.entries.len());
+        for (outcome, count) in self.summary() {
+            println!("  {}: {}", outcome, count);
+        }
+        for entry in &self.entries {
+            if all_entries || entry.outcome != Outcome::Indexed {
+                match &entry.message {
+                    Some(message) => println!("{}: {} ({})", entry.outcome, entry.file, message),
+                    None => println!("{}: {}", entry.outcome, entry.file),
+                }
+            }
+        }
+    }
+}
+
+pub fn write_report(
+    report: &IndexReport,
+    log_file: &PathBuf,
+    config: &Configuration,
+) -> io::Result<()> {
+    let mut output = File::create(log_file)?;
+    match config.output_format {
+        OutputFormat::Json => {
+            for entry in &report.entries {
+                output.write_all(serde_json::to_string(entry)?.as_bytes())?;
+                output.write_all(b"\n")?;
+            }
+            Ok(())
+        }
+        OutputFormat::Binary => bincode::serialize_into(output, &report.entries)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+pub fn read_report(log_file: &PathBuf, config: &Configuration) -> io::Result<IndexReport> {
+    let file = BufReader::new(File::open(log_file)?);
+    let entries: Vec<FileOutcome> = match config.output_format {
+        OutputFormat::Json => {
+            let mut entries = Vec::new();
+            for line in file.lines() {
+                entries.push(serde_json::from_str(line?.as_str())?);
+            }
+            entries
+        }
+        OutputFormat::Binary => bincode::deserialize_from(file)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+    };
+    Ok(IndexReport { entries })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    fn sample_report() -> IndexReport {
+        IndexReport::new(vec![
+            FileOutcome::new(Path::new("b"), Outcome::Indexed),
+            FileOutcome::new(Path::new("a"), Outcome::TooSmall),
+            FileOutcome::from_error(
+                Path::new("c"),
+                &io::Error::new(io::ErrorKind::PermissionDenied, "denied"),
+            ),
+            FileOutcome::new(Path::new("d"), Outcome::Indexed),
+        ])
+    }
+
+    #[test]
+    fn test_summary() {
+        let report = sample_report();
+        assert_eq!(report.entries[0].file, "a");
+        let summary = report.summary();
+        assert_eq!(summary.get(&Outcome::Indexed), Some(&2));
+        assert_eq!(summary.get(&Outcome::TooSmall), Some(&1));
+        assert_eq!(summary.get(&Outcome::PermissionDenied), Some(&1));
+        assert_eq!(summary.get(&Outcome::IoError), None);
+    }
+
+    #[test]
+    fn test_write_and_read_report() -> io::Result<()> {
+        let dir = tempdir()?;
+        let report = sample_report();
+        for format in [OutputFormat::Json, OutputFormat::Binary] {
+            let config = Configuration::new(format, true);
+            let log_file = dir.path().join("index.log");
+            write_report(&report, &log_file, &config)?;
+            assert_eq!(read_report(&log_file, &config)?, report);
+        }
+        dir.close()
+    }
+}
@@ -0,0 +1,461 @@
+// Copyright 2021 -- 2023 Erwin van Eijk
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included
+// in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+// TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+// SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::fbhash::compact::Quantization;
+use crate::fbhash::metrics::SimilarityMetric;
+use crate::fbhash::query::*;
+use crate::fbhash::shard::database_paths;
+use crate::fbhash::similarities::DigestCap;
+use crate::fbhash::utils::*;
+
+//
+// How the index is served: where to listen, how many requests are answered
+// at the same time, how often the files of the index are checked for
+// changes, and how the queries are answered unless a request says otherwise.
+//
+#[derive(Clone, Debug)]
+pub struct ServeOptions {
+    pub address: String,
+    pub workers: usize,
+    pub reload_interval: Duration,
+    pub query: QueryOptions,
+    // Only the files below this directory can be queried by their path.
+    // Without it, a query has to send the contents of the file.
+    pub root: Option<PathBuf>,
+}
+
+//
+// The index as it was loaded. A reload replaces all of it at once, so a
+// request that is being answered keeps using the index it started with.
+//
+struct LoadedIndex {
+    index: QueryIndex,
+    metric: Box<dyn SimilarityMetric>,
+    loaded: SystemTime,
+    version: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct IndexStats {
+    state: String,
+    database: String,
+    documents: usize,
+    files: usize,
+    shards: usize,
+    digest_cap: Option<DigestCap>,
+    digest_encoding: Option<Quantization>,
+    approximate: bool,
+    loaded: String,
+    version: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct Health {
+    status: &'static str,
+}
+
+struct Service<'a> {
+    state_path: &'a PathBuf,
+    database_path: &'a Path,
+    options: &'a ServeOptions,
+    config: &'a Configuration,
+    current: RwLock<Arc<LoadedIndex>>,
+    // The root directory, with the links in it resolved.
+    root: Option<PathBuf>,
+}
+
+impl LoadedIndex {
+    fn load(
+        state_path: &PathBuf,
+        database_path: &Path,
+        options: &QueryOptions,
+        config: &Configuration,
+        version: usize,
+    ) -> io::Result<LoadedIndex> {
+        let index = QueryIndex::open(state_path, database_path, options, config)?;
+        let metric = index.metric(options.metric_kind);
+        Ok(LoadedIndex {
+            index,
+            metric,
+            loaded: SystemTime::now(),
+            version,
+        })
+    }
+}
+
+impl<'a> Service<'a> {
+    fn current(&self) -> Arc<LoadedIndex> {
+        self.current.read().unwrap().clone()
+    }
+
+    //
+    // The files that make up the loaded index; the state is written last
+    // by the indexer, but any of them changing starts a reload.
+    //
+    fn watched_paths(&self) -> Vec<PathBuf> {
+        let shards = self.current().index.document_collection().get_shards();
+        let mut paths = vec![self.state_path.clone()];
+        paths.extend(database_paths(self.database_path, shards));
+        paths
+    }
+
+    //
+    // Check the files of the index every interval, and load it anew when
+    // they changed and then stayed the same for an interval, so a reload
+    // does not read an index that is still being written. When loading
+    // fails the old index is kept, and it is tried again on the next change.
+    //
+    fn watch(&self) {
+        let mut paths = self.watched_paths();
+        let mut last_seen = file_versions(&paths);
+        loop {
+            thread::sleep(self.options.reload_interval);
+            let seen = file_versions(&paths);
+            if seen == last_seen {
+                continue;
+            }
+            thread::sleep(self.options.reload_interval);
+            if file_versions(&paths) != seen {
+                continue;
+            }
+            last_seen = seen;
+            let loaded = LoadedIndex::load(
+                self.state_path,
+                self.database_path,
+                &self.options.query,
+                self.config,
+                self.current().version + 1,
+            );
+            match loaded {
+                Ok(loaded) => {
+                    *self.current.write().unwrap() = Arc::new(loaded);
+                    paths = self.watched_paths();
+                    last_seen = file_versions(&paths);
+                    // Nobody may be reading the log any more, which is no reason to stop.
+                    let _ = writeln!(
+                        io::stdout(),
+                        "Reloaded the index from {}",
+                        self.database_path.display()
+                    );
+                }
+                Err(e) => eprintln!("Keeping the loaded index, reloading failed: {}", e),
+            }
+        }
+    }
+
+    fn stats(&self) -> IndexStats {
+        let current = self.current();
+        let collection = current.index.document_collection();
+        IndexStats {
+            state: self.state_path.display().to_string(),
+            database: self.database_path.display().to_string(),
+            documents: current.index.number_of_documents(),
+            files: collection.number_of_files(),
+            shards: current.index.number_of_shards(),
+            digest_cap: collection.get_digest_cap(),
+            digest_encoding: collection.get_digest_encoding(),
+            approximate: self.options.query.approximate.is_some(),
+            loaded: humantime::format_rfc3339_seconds(current.loaded).to_string(),
+            version: current.version,
+        }
+    }
+
+    fn query(&self, request: &mut Request, parameters: &[(String, String)]) -> io::Result<String> {
+        let mut options = self.options.query.clone();
+        if let Some(number) = parameter(parameters, "n") {
+            options.number_of_results = number.parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Not a number of results: {}", number),
+                )
+            })?;
+        }
+        if let Some(metric) = parameter(parameters, "metric") {
+            options.metric_kind = metric.parse()?;
+        }
+        options.show_metadata =
+            matches!(parameter(parameters, "metadata"), Some("" | "true" | "1"));
+
+        let current = self.current();
+        let collection = current.index.document_collection();
+        let (name, query_document) = match request.method() {
+            Method::Get => {
+                let path = parameter(parameters, "path").ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "Missing the path to query")
+                })?;
+                let root = self.root.as_ref().ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "Querying by path needs the server to be started with --root",
+                    )
+                })?;
+                let query_document = collection
+                    .compute_query_document(&path_below(root, path)?.to_string_lossy())
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+                (path.to_string(), query_document)
+            }
+            _ => {
+                let name = parameter(parameters, "name").unwrap_or("upload");
+                let query_document =
+                    collection.compute_query_document_from_reader(name, request.as_reader())?;
+                (name.to_string(), query_document)
+            }
+        };
+        let other_metric;
+        let metric = if options.metric_kind == self.options.query.metric_kind {
+            current.metric.as_ref()
+        } else {
+            other_metric = current.index.metric(options.metric_kind);
+            other_metric.as_ref()
+        };
+        let results = current.index.search(&query_document, metric, &options);
+        to_json(&QueryResults::new(
+            &name,
+            &query_document,
+            &results,
+            &options,
+        ))
+    }
+
+    fn answer(&self, mut request: Request) {
+        let (path, query_string) = match request.url().split_once('?') {
+            Some((path, query_string)) => (path.to_string(), query_string.to_string()),
+            None => (request.url().to_string(), String::new()),
+        };
+        let body = match (request.method(), path.as_str()) {
+            (Method::Get, "/health") => to_json(&Health { status: "ok" }),
+            (Method::Get, "/stats") => to_json(&self.stats()),
+            (Method::Get | Method::Post, "/query") => parse_parameters(&query_string)
+                .and_then(|parameters| self.query(&mut request, &parameters)),
+            (method, path) => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No such route: {} {}", method, path),
+            )),
+        };
+        let (status, body) = match body {
+            Ok(body) => (200, body),
+            Err(e) => {
+                let status = match e.kind() {
+                    io::ErrorKind::InvalidInput => 400,
+                    io::ErrorKind::PermissionDenied => 403,
+                    io::ErrorKind::NotFound => 404,
+                    _ => 500,
+                };
+                let message = ErrorMessage {
+                    error: e.to_string(),
+                };
+                (status, serde_json::to_string(&message).unwrap())
+            }
+        };
+        let content_type =
+            Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+        let response = Response::from_string(body)
+            .with_status_code(status)
+            .with_header(content_type);
+        // The client may have gone away, which is not the problem of the server.
+        let _ = request.respond(response);
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> io::Result<String> {
+    Ok(serde_json::to_string(value)?)
+}
+
+fn file_versions(paths: &[PathBuf]) -> Vec<Option<(SystemTime, u64)>> {
+    paths
+        .iter()
+        .map(|path| {
+            let metadata = fs::metadata(path).ok()?;
+            Some((metadata.modified().ok()?, metadata.len()))
+        })
+        .collect()
+}
+
+//
+// The file a path names, relative to the root or not, as long as it is below
+// the root once the links are resolved. Files elsewhere are as good as
+// missing, so a request can not find out whether they exist.
+//
+fn path_below(root: &Path, path: &str) -> io::Result<PathBuf> {
+    root.join(path)
+        .canonicalize()
+        .ok()
+        .filter(|resolved| resolved.starts_with(root))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No such file below the root directory: {}", path),
+            )
+        })
+}
+
+fn parameter<'p>(parameters: &'p [(String, String)], name: &str) -> Option<&'p str> {
+    parameters
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
+//
+// The name and value pairs of a query string, with the percent escapes and
+// the plus signs for spaces decoded.
+//
+fn parse_parameters(query_string: &str) -> io::Result<Vec<(String, String)>> {
+    query_string
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((decode_component(key)?, decode_component(value)?))
+        })
+        .collect()
+}
+
+fn decode_component(component: &str) -> io::Result<String> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Badly encoded parameter: {}", component),
+        )
+    };
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = component.get(i + 1..i + 3).ok_or_else(invalid)?;
+                decoded.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+                i += 2;
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8(decoded).map_err(|_| invalid())
+}
+
+//
+// Keep the index loaded and answer queries over HTTP with JSON, until the
+// process is stopped. The index is loaded again when its files change.
+//
+pub fn serve(
+    state_path: &PathBuf,
+    database_path: &Path,
+    options: &ServeOptions,
+    config: &Configuration,
+) -> io::Result<()> {
+    let root = match &options.root {
+        Some(root) => Some(
+            root.canonicalize()
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", root.display(), e)))?,
+        ),
+        None => None,
+    };
+    let loaded = LoadedIndex::load(state_path, database_path, &options.query, config, 0)?;
+    let service = Service {
+        state_path,
+        database_path,
+        options,
+        config,
+        current: RwLock::new(Arc::new(loaded)),
+        root,
+    };
+    let server = Server::http(options.address.as_str()).map_err(io::Error::other)?;
+    let address = server.server_addr();
+    // There is no authentication, anyone who can reach the server can query.
+    if !address
+        .clone()
+        .to_ip()
+        .is_some_and(|ip| ip.ip().is_loopback())
+    {
+        eprintln!(
+            "Warning: {} is not a loopback address, anyone who can reach it can query the index",
+            address
+        );
+    }
+    println!("Listening on http://{}", address);
+    io::stdout().flush()?;
+    thread::scope(|scope| {
+        scope.spawn(|| service.watch());
+        for _ in 0..options.workers {
+            scope.spawn(|| {
+                for request in server.incoming_requests() {
+                    service.answer(request);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_parameters() -> io::Result<()> {
+        assert_eq!(
+            parse_parameters("path=dir%2Fa+file.bin&n=3&metadata")?,
+            vec![
+                ("path".to_string(), "dir/a file.bin".to_string()),
+                ("n".to_string(), "3".to_string()),
+                ("metadata".to_string(), String::new()),
+            ]
+        );
+        assert_eq!(parse_parameters("")?, vec![]);
+        assert_eq!(
+            parse_parameters("path=%2").unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            parse_parameters("path=%ff").unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_below() -> io::Result<()> {
+        let root = Path::new("testdata").canonicalize()?;
+        let file = root.join("testfile-yes.bin");
+        assert_eq!(path_below(&root, "testfile-yes.bin")?, file);
+        assert_eq!(path_below(&root, &file.to_string_lossy())?, file);
+        for outside in ["../Cargo.toml", "/etc/passwd", "missing"] {
+            assert_eq!(
+                path_below(&root, outside).unwrap_err().kind(),
+                io::ErrorKind::NotFound
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,284 @@
+// Copyright 2021 -- 2023 Erwin van Eijk
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included
+// in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+// TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+// SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use serde_yaml::Value;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// The name of the configuration file of a project, looked for in the current
+// directory and the directories above it.
+pub const PROJECT_FILE_NAME: &str = "fbhash.yaml";
+
+// Environment variables with this prefix give a setting, e.g. FBHASH_DATABASE.
+pub const ENVIRONMENT_PREFIX: &str = "FBHASH_";
+
+// The settings that name a file. In a configuration file they are relative to
+// the directory of that file.
+const PATH_SETTINGS: [&str; 7] = [
+    "database",
+    "state",
+    "log",
+    "known",
+    "known-state",
+    "alert-database",
+    "alert-state",
+];
+
+//
+// A default for an option of the command line, and where it came from.
+//
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Setting {
+    pub values: Vec<String>,
+    pub origin: String,
+    // Whether it was taken from the environment rather than from a file.
+    pub environment: bool,
+}
+
+//
+// The defaults for the options of the command line, by their long name. They
+// are taken from the configuration file of the user, then that of the
+// project, and then the environment, where every one overrides the ones
+// before it. The options on the command line override them all.
+//
+#[derive(Clone, Debug, Default)]
+pub struct Settings {
+    settings: BTreeMap<String, Setting>,
+}
+
+fn scalar_to_string(value: &Value) -> Option<String> {
+    match value {
+        Value::Bool(b) => Some(b.to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::String(s) => Some(s.clone()),
+        _ => None,
+    }
+}
+
+impl Settings {
+    pub fn get(&self, name: &str) -> Option<&Setting> {
+        self.settings.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Setting)> {
+        self.settings.iter()
+    }
+
+    //
+    // Read the settings of a configuration file in YAML, a mapping of the
+    // long names of the options to a value, or a list of values for the
+    // options that can be given more than once.
+    //
+    pub fn read_file(&mut self, path: &Path) -> io::Result<()> {
+        let invalid = |message: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), message),
+            )
+        };
+        let value: Value =
+            serde_yaml::from_reader(File::open(path)?).map_err(|e| invalid(e.to_string()))?;
+        let mapping = match value {
+            Value::Mapping(mapping) => mapping,
+            Value::Null => return Ok(()),
+            _ => return Err(invalid("expected a mapping of settings".to_string())),
+        };
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        for (name, value) in mapping {
+            let name = scalar_to_string(&name)
+                .ok_or_else(|| invalid("expected the name of a setting".to_string()))?;
+            let values = match &value {
+                Value::Sequence(sequence) => sequence.iter().map(scalar_to_string).collect(),
+                value => scalar_to_string(value).map(|value| vec![value]),
+            }
+            .ok_or_else(|| {
+                invalid(format!(
+                    "expected a value, or a list of values, for {}",
+                    name
+                ))
+            })?;
+            let values = if PATH_SETTINGS.contains(&name.as_str()) {
+                values
+                    .into_iter()
+                    .map(|value| directory.join(value).to_string_lossy().to_string())
+                    .collect()
+            } else {
+                values
+            };
+            self.settings.insert(
+                name,
+                Setting {
+                    values,
+                    origin: path.display().to_string(),
+                    environment: false,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    //
+    // The settings from the environment, FBHASH_MIN_SIZE for min-size.
+    //
+    pub fn read_environment<I: IntoIterator<Item = (String, String)>>(&mut self, variables: I) {
+        for (variable, value) in variables {
+            if let Some(name) = variable.strip_prefix(ENVIRONMENT_PREFIX) {
+                if name == "CONFIG" {
+                    continue;
+                }
+                self.settings.insert(
+                    name.to_lowercase().replace('_', "-"),
+                    Setting {
+                        values: vec![value],
+                        origin: variable.clone(),
+                        environment: true,
+                    },
+                );
+            }
+        }
+    }
+}
+
+// The configuration file of the user, in their configuration directory.
+fn user_file() -> Option<PathBuf> {
+    let directory = match env::var_os("XDG_CONFIG_HOME") {
+        Some(directory) if !directory.is_empty() => PathBuf::from(directory),
+        _ if cfg!(target_os = "windows") => PathBuf::from(env::var_os("APPDATA")?),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(directory.join("fbhash").join("config.yaml"))
+}
+
+fn project_file(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .map(|directory| directory.join(PROJECT_FILE_NAME))
+        .find(|path| path.is_file())
+}
+
+pub fn load_settings() -> io::Result<Settings> {
+    let mut settings = Settings::default();
+    // A file can be given instead, which then has to exist, and is the only
+    // file that is read.
+    match env::var_os("FBHASH_CONFIG") {
+        Some(path) => settings.read_file(Path::new(&path))?,
+        None => {
+            if let Some(path) = user_file().filter(|path| path.is_file()) {
+                settings.read_file(&path)?;
+            }
+            if let Some(path) = project_file(&env::current_dir()?) {
+                settings.read_file(&path)?;
+            }
+        }
+    }
+    settings.read_environment(env::vars());
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_read_file() -> io::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join(PROJECT_FILE_NAME);
+        fs::write(
+            &path,
+            "database: index/database.json\nbinary: true\nnumber: 10\nexclude:\n  - '*.tmp'\n  - node_modules\n",
+        )?;
+        let mut settings = Settings::default();
+        settings.read_file(&path)?;
+
+        let origin = path.display().to_string();
+        assert_eq!(
+            settings.get("database"),
+            Some(&Setting {
+                values: vec![dir
+                    .path()
+                    .join("index/database.json")
+                    .to_string_lossy()
+                    .to_string()],
+                origin: origin.clone(),
+                environment: false,
+            })
+        );
+        assert_eq!(settings.get("binary").unwrap().values, vec!["true"]);
+        assert_eq!(settings.get("number").unwrap().values, vec!["10"]);
+        assert_eq!(
+            settings.get("exclude").unwrap().values,
+            vec!["*.tmp", "node_modules"]
+        );
+
+        fs::write(&path, "walker:\n  exclude: x\n")?;
+        assert_eq!(
+            settings.read_file(&path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_environment_overrides() -> io::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join(PROJECT_FILE_NAME);
+        fs::write(&path, "threads: 2\nmin-size: 100\n")?;
+        let mut settings = Settings::default();
+        settings.read_file(&path)?;
+        settings.read_environment(vec![
+            ("FBHASH_MIN_SIZE".to_string(), "512".to_string()),
+            ("FBHASH_CONFIG".to_string(), "other.yaml".to_string()),
+            ("HOME".to_string(), "/home/someone".to_string()),
+        ]);
+
+        assert_eq!(settings.get("threads").unwrap().values, vec!["2"]);
+        assert_eq!(
+            settings.get("min-size"),
+            Some(&Setting {
+                values: vec!["512".to_string()],
+                origin: "FBHASH_MIN_SIZE".to_string(),
+                environment: true,
+            })
+        );
+        assert_eq!(settings.get("config"), None);
+        assert_eq!(settings.iter().count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_project_file() -> io::Result<()> {
+        let dir = tempdir()?;
+        let nested = dir.path().join("a").join("b");
+        fs::create_dir_all(&nested)?;
+        assert_eq!(project_file(&nested), None);
+        fs::write(dir.path().join(PROJECT_FILE_NAME), "")?;
+        assert_eq!(
+            project_file(&nested),
+            Some(dir.path().join(PROJECT_FILE_NAME))
+        );
+        Ok(())
+    }
+}
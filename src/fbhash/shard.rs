@@ -0,0 +1,160 @@
+// Copyright 2021 -- 2023 Erwin van Eijk
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included
+// in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+// TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+// SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+//
+// What decides the shard of a document: a hash of its path, which spreads
+// the documents evenly, or the evidence item it was found in, every path or
+// image given to the index, which keeps the documents of an item together.
+//
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShardKey {
+    Path,
+    Item,
+}
+
+impl ShardKey {
+    pub const NAMES: [&'static str; 2] = ["path", "item"];
+}
+
+impl FromStr for ShardKey {
+    type Err = io::Error;
+
+    fn from_str(name: &str) -> io::Result<ShardKey> {
+        match name {
+            "path" => Ok(ShardKey::Path),
+            "item" => Ok(ShardKey::Item),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown shard key {}", name),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ShardKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ShardKey::Path => "path",
+            ShardKey::Item => "item",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+//
+// The database is split in this many shards, each a database of its own,
+// e.g. `database.json.shard-0`. They share the collection in the state.
+//
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardOptions {
+    pub shards: usize,
+    pub key: ShardKey,
+}
+
+impl ShardOptions {
+    // The shard of a document, from its path and the position of its item.
+    pub fn shard_of(&self, file: &str, item: usize) -> usize {
+        let key = match self.key {
+            ShardKey::Path => path_hash(file),
+            ShardKey::Item => item as u64,
+        };
+        (key % self.shards as u64) as usize
+    }
+}
+
+//
+// FNV-1a, which unlike the hasher of the standard library gives the same
+// hash in every run and on every platform.
+//
+fn path_hash(file: &str) -> u64 {
+    file.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+pub fn shard_path(database_path: &Path, shard: usize) -> PathBuf {
+    let mut path = database_path.as_os_str().to_os_string();
+    path.push(format!(".shard-{}", shard));
+    PathBuf::from(path)
+}
+
+// The files that hold the documents: the database itself, or its shards.
+pub fn database_paths(database_path: &Path, shards: Option<ShardOptions>) -> Vec<PathBuf> {
+    match shards {
+        Some(shards) => (0..shards.shards)
+            .map(|shard| shard_path(database_path, shard))
+            .collect(),
+        None => vec![database_path.to_path_buf()],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_shard_of() {
+        let by_path = ShardOptions {
+            shards: 4,
+            key: ShardKey::Path,
+        };
+        let shard = by_path.shard_of("evidence/file.doc", 0);
+        assert!(shard < 4);
+        assert_eq!(by_path.shard_of("evidence/file.doc", 3), shard);
+        assert_eq!(path_hash(""), 0xcbf29ce484222325);
+        assert_eq!(path_hash("a"), 0xaf63dc4c8601ec8c);
+        let by_item = ShardOptions {
+            shards: 4,
+            key: ShardKey::Item,
+        };
+        assert_eq!(by_item.shard_of("evidence/file.doc", 6), 2);
+    }
+
+    #[test]
+    fn test_database_paths() -> io::Result<()> {
+        for name in ShardKey::NAMES {
+            assert_eq!(name.parse::<ShardKey>()?.to_string(), name);
+        }
+        assert!("size".parse::<ShardKey>().is_err());
+        let database = Path::new("database.json");
+        assert_eq!(database_paths(database, None), vec![database.to_path_buf()]);
+        assert_eq!(
+            database_paths(
+                database,
+                Some(ShardOptions {
+                    shards: 2,
+                    key: ShardKey::Path
+                })
+            ),
+            vec![
+                PathBuf::from("database.json.shard-0"),
+                PathBuf::from("database.json.shard-1")
+            ]
+        );
+        Ok(())
+    }
+}
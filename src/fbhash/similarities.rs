@@ -19,19 +19,44 @@
 // SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 use std::cmp::{Ordering, Reverse};
+use crate::fbhash::archive::{split_virtual_path, with_member};
 use crate::fbhash::chunker::ChunkIterator;
+use crate::fbhash::compact::Quantization;
+use crate::fbhash::metadata::{DocumentMetadata, HashingReader};
+use crate::fbhash::metrics::SimilarityMetric;
+use crate::fbhash::shard::ShardOptions;
 use hashbrown::HashMap;
 use indicatif::ProgressBar;
 use ordered_float::OrderedFloat;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, BTreeSet, BinaryHeap};
 use std::fs::File;
 use std::io;
+use std::io::{BufReader, Read};
+use std::path::Path;
 
-pub fn file_to_chunks(file: File) -> Vec<u64> {
-    let chunk_iterator = ChunkIterator::new(file);
-    let chunks: Vec<u64> = chunk_iterator.into_iter().map(|e| e.digest).collect();
-    chunks
+#[cfg(test)]
+pub fn file_to_chunks(file: File) -> io::Result<Vec<u64>> {
+    let chunk_iterator = ChunkIterator::new(BufReader::new(file));
+    chunk_iterator
+        .map(|e| e.map(|chunk| chunk.digest))
+        .collect()
+}
+
+//
+// Read all the chunks, and compute the size and the SHA-256 of the content
+// in the same pass. When reading fails there is no hash, as it would only be
+// of the part that was read.
+//
+pub fn reader_to_chunks_and_hash<R: Read>(reader: R) -> io::Result<(Vec<u64>, u64, String)> {
+    let mut chunk_iterator = ChunkIterator::new(BufReader::new(HashingReader::new(reader)));
+    let chunks = chunk_iterator
+        .by_ref()
+        .map(|e| e.map(|chunk| chunk.digest))
+        .collect::<io::Result<Vec<u64>>>()?;
+    let (size, sha256) = chunk_iterator.into_inner().into_inner().finish();
+    Ok((chunks, size, sha256))
 }
 
 pub fn compute_document_frequencies(doc: &[u64]) -> BTreeMap<&u64, usize> {
@@ -42,30 +67,111 @@ pub fn compute_document_frequencies(doc: &[u64]) -> BTreeMap<&u64, usize> {
     hmf
 }
 
-pub fn compute_document(file_name: &str) -> io::Result<(Document, HashMap<u64, usize>)> {
-    let file = File::open(file_name)?;
-    let chunks = file_to_chunks(file);
-    let mut file_frequencies: HashMap<u64, usize> = HashMap::new();
-    for chunk in chunks.clone() {
-        file_frequencies
-            .entry(chunk)
+//
+// Weigh every chunk by its frequency in the document alone, as if all chunks
+// were equally rare. This needs no collection, so the digest of a document
+// does not depend on what else was indexed.
+//
+pub fn compute_uniform_digest(doc: &[u64]) -> Vec<(u64, f64)> {
+    compute_document_frequencies(doc)
+        .iter()
+        .map(|(chunk, count)| (**chunk, (1.0_f64 + *count as f64).log10()))
+        .collect()
+}
+
+// How often every chunk occurs, as fed into the document collection.
+pub fn chunk_frequencies(chunks: &[u64]) -> HashMap<u64, usize> {
+    let mut frequencies: HashMap<u64, usize> = HashMap::new();
+    for chunk in chunks {
+        frequencies
+            .entry(*chunk)
             .and_modify(|e| *e += 1)
             .or_insert(1);
     }
+    frequencies
+}
+
+//
+// Compute the document for whatever the reader produces, under the given name.
+//
+pub fn compute_document_from_reader<R: Read>(
+    name: &str,
+    reader: R,
+    mut metadata: DocumentMetadata,
+) -> io::Result<(Document, HashMap<u64, usize>)> {
+    let (chunks, size, sha256) = reader_to_chunks_and_hash(reader)?;
+    metadata.size = size;
+    metadata.sha256 = sha256;
+    metadata.number_of_chunks = chunks.len();
+    let file_frequencies = chunk_frequencies(&chunks);
 
     let doc = Document {
-        file: file_name.to_string(),
+        file: name.to_string(),
         chunks,
         digest: vec![],
+        metadata,
     };
     Ok((doc, file_frequencies))
 }
 
+//
+// Like compute_document_from_reader, but only count how often every chunk
+// occurs, without keeping the chunks themselves.
+//
+pub fn compute_frequencies_from_reader<R: Read>(
+    reader: R,
+    mut metadata: DocumentMetadata,
+) -> io::Result<(HashMap<u64, usize>, DocumentMetadata)> {
+    let mut chunk_iterator = ChunkIterator::new(BufReader::new(HashingReader::new(reader)));
+    let mut frequencies: HashMap<u64, usize> = HashMap::new();
+    let mut number_of_chunks = 0;
+    for chunk in chunk_iterator.by_ref() {
+        *frequencies.entry(chunk?.digest).or_insert(0) += 1;
+        number_of_chunks += 1;
+    }
+    let (size, sha256) = chunk_iterator.into_inner().into_inner().finish();
+    metadata.size = size;
+    metadata.sha256 = sha256;
+    metadata.number_of_chunks = number_of_chunks;
+    Ok((frequencies, metadata))
+}
+
+//
+// The name is either a path to a file, or a virtual path to a member of an
+// archive, such as `evidence.zip!/dir/file.doc`.
+//
+pub fn compute_document(file_name: &str) -> io::Result<(Document, HashMap<u64, usize>)> {
+    if let Some((archive_name, member_name)) = split_virtual_path(file_name) {
+        if !Path::new(file_name).exists() {
+            return with_member(Path::new(archive_name), member_name, |member, reader| {
+                compute_document_from_reader(file_name, reader, member.metadata())
+            });
+        }
+    }
+    let file = File::open(file_name)?;
+    let metadata = DocumentMetadata::from_file_metadata(&file.metadata()?);
+    compute_document_from_reader(file_name, file, metadata)
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Document {
     pub file: String,
     pub chunks: Vec<u64>,
     pub digest: Vec<(u64, f64)>,
+    // JSON databases from before the metadata was recorded lack this field,
+    // binary ones are converted when they are read.
+    #[serde(default)]
+    pub metadata: DocumentMetadata,
+}
+
+impl Document {
+    //
+    // Byte-identical, as far as the cryptographic hash can tell. Documents
+    // from databases without the hash are never identical to anything.
+    //
+    pub fn is_identical_to(&self, other: &Document) -> bool {
+        !self.metadata.sha256.is_empty() && self.metadata.sha256 == other.metadata.sha256
+    }
 }
 
 impl PartialEq for Document {
@@ -85,6 +191,62 @@ impl std::hash::Hash for Document {
     }
 }
 
+//
+// A limit on the size of the digests, so the database of large files does not
+// end up larger than the files themselves. Only the chunks with the largest
+// weights are kept: at most a number of them, those with at least a weight, or
+// a fraction of them. The magnitude of the weights is used, as they can be
+// negative for chunks that are very common.
+//
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DigestCap {
+    TopK(usize),
+    Threshold(f64),
+    Fraction(f64),
+}
+
+impl DigestCap {
+    pub fn apply(&self, digest: Vec<(u64, f64)>) -> Vec<(u64, f64)> {
+        match *self {
+            DigestCap::TopK(k) => top_weighted(digest, k),
+            DigestCap::Threshold(threshold) => digest
+                .into_iter()
+                .filter(|(_, weight)| weight.abs() >= threshold)
+                .collect(),
+            DigestCap::Fraction(fraction) => {
+                let k = (digest.len() as f64 * fraction).ceil() as usize;
+                top_weighted(digest, k)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for DigestCap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DigestCap::TopK(k) => write!(f, "the {} chunks with the largest weights", k),
+            DigestCap::Threshold(threshold) => {
+                write!(f, "the chunks with a weight of at least {}", threshold)
+            }
+            DigestCap::Fraction(fraction) => write!(
+                f,
+                "the {}% of the chunks with the largest weights",
+                fraction * 100.
+            ),
+        }
+    }
+}
+
+// Keep the k chunks with the largest weights, sorted by chunk again.
+fn top_weighted(mut digest: Vec<(u64, f64)>, k: usize) -> Vec<(u64, f64)> {
+    if digest.len() > k {
+        digest.sort_by(|a, b| b.1.abs().total_cmp(&a.1.abs()).then(a.0.cmp(&b.0)));
+        digest.truncate(k);
+        digest.sort_by_key(|(chunk, _)| *chunk);
+    }
+    digest
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DocumentCollection {
     files: BTreeSet<String>,
@@ -95,6 +257,25 @@ pub struct DocumentCollection {
     // Determine if it's more beneficial to replace it with a strictly ordered data structure
     // in the first place, and take the overhead as a calculated downside.
     collection_digests: BTreeMap<u64, usize>,
+    // Chunks that carry no weight, e.g. because they are common in files that
+    // are known to be of no interest.
+    #[serde(default)]
+    stop_chunks: BTreeSet<u64>,
+    // Chunks with a lower inverse document frequency, or that occur more often
+    // than this, carry no weight either.
+    #[serde(default)]
+    min_idf: Option<f64>,
+    #[serde(default)]
+    max_chunk_count: Option<usize>,
+    // The limit on the size of the digests, if any.
+    #[serde(default)]
+    digest_cap: Option<DigestCap>,
+    // How the digests are stored in the database, if in the compact form.
+    #[serde(default)]
+    digest_encoding: Option<Quantization>,
+    // How the database is split in shards, if it is.
+    #[serde(default)]
+    shards: Option<ShardOptions>,
 }
 
 impl DocumentCollection {
@@ -102,6 +283,27 @@ impl DocumentCollection {
         DocumentCollection {
             files: BTreeSet::new(),
             collection_digests: BTreeMap::default(),
+            stop_chunks: BTreeSet::new(),
+            min_idf: None,
+            max_chunk_count: None,
+            digest_cap: None,
+            digest_encoding: None,
+            shards: None,
+        }
+    }
+
+    //
+    // A collection of the files, and how often every chunk occurs in them,
+    // with the default settings.
+    //
+    pub fn from_counts(
+        files: BTreeSet<String>,
+        collection_digests: BTreeMap<u64, usize>,
+    ) -> DocumentCollection {
+        DocumentCollection {
+            files,
+            collection_digests,
+            ..DocumentCollection::new()
         }
     }
 
@@ -117,12 +319,18 @@ impl DocumentCollection {
         DocumentCollection {
             files: self.files.clone(),
             collection_digests: self.collection_digests.clone(),
+            stop_chunks: self.stop_chunks.clone(),
+            min_idf: self.min_idf,
+            max_chunk_count: self.max_chunk_count,
+            digest_cap: self.digest_cap,
+            digest_encoding: self.digest_encoding,
+            shards: self.shards,
         }
     }
 
-    #[allow(dead_code)]
     pub fn extend(&mut self, other: &DocumentCollection) {
         self.files.extend(other.files.iter().cloned());
+        self.stop_chunks.extend(other.stop_chunks.iter().copied());
         for (k, v) in &other.collection_digests {
             self.collection_digests
                 .entry(*k)
@@ -153,12 +361,12 @@ impl DocumentCollection {
         }
     }
 
-    pub fn update_collection(
+    pub fn update_collection<'a, I: IntoIterator<Item = (&'a u64, &'a usize)>>(
         &mut self,
-        frequencies: &HashMap<u64, usize>,
+        frequencies: I,
         names: &[String],
     ) -> usize {
-        for (k, v) in frequencies.iter() {
+        for (k, v) in frequencies {
             self.collection_digests
                 .entry(*k)
                 .and_modify(|e| *e += v)
@@ -172,35 +380,198 @@ impl DocumentCollection {
         self.files.contains(name)
     }
 
+    //
+    // Forget about these files. Their chunks still count towards the weights
+    // of the others, as the frequencies per file are not kept.
+    //
+    pub fn remove_files(&mut self, names: &[String]) {
+        for name in names {
+            self.files.remove(name);
+        }
+    }
+
+    //
+    // Forget about a file of which the frequencies are known, so its chunks
+    // no longer count towards the weights of the others either.
+    //
+    pub fn remove_document(&mut self, name: &str, frequencies: &[(u64, usize)]) {
+        for (chunk, count) in frequencies {
+            if let Some(total) = self.collection_digests.get_mut(chunk) {
+                *total = total.saturating_sub(*count);
+                if *total == 0 {
+                    self.collection_digests.remove(chunk);
+                }
+            }
+        }
+        self.files.remove(name);
+    }
+
+    pub fn add_stop_chunks<I: IntoIterator<Item = u64>>(&mut self, chunks: I) {
+        self.stop_chunks.extend(chunks);
+    }
+
+    pub fn is_stop_chunk(&self, chunk: u64) -> bool {
+        self.stop_chunks.contains(&chunk)
+    }
+
+    pub fn set_cutoff(&mut self, min_idf: Option<f64>, max_chunk_count: Option<usize>) {
+        self.min_idf = min_idf;
+        self.max_chunk_count = max_chunk_count;
+    }
+
+    pub fn get_digest_cap(&self) -> Option<DigestCap> {
+        self.digest_cap
+    }
+
+    pub fn set_digest_cap(&mut self, digest_cap: Option<DigestCap>) {
+        self.digest_cap = digest_cap;
+    }
+
+    pub fn get_digest_encoding(&self) -> Option<Quantization> {
+        self.digest_encoding
+    }
+
+    pub fn set_digest_encoding(&mut self, digest_encoding: Option<Quantization>) {
+        self.digest_encoding = digest_encoding;
+    }
+
+    pub fn get_shards(&self) -> Option<ShardOptions> {
+        self.shards
+    }
+
+    pub fn set_shards(&mut self, shards: Option<ShardOptions>) {
+        self.shards = shards;
+    }
+
+    pub fn chunk_count(&self, chunk: u64) -> usize {
+        self.collection_digests.get(&chunk).copied().unwrap_or(0)
+    }
+
+    //
+    // The chunks that occur most often in the collection, with how often they
+    // do, the most frequent first. Such as the chunks of zero padding.
+    //
+    pub fn most_frequent_chunks(&self, number: usize) -> Vec<(u64, usize)> {
+        let mut chunks: Vec<(u64, usize)> = self
+            .collection_digests
+            .iter()
+            .map(|(chunk, count)| (*chunk, *count))
+            .collect();
+        chunks.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        chunks.truncate(number);
+        chunks
+    }
+
+    //
+    // Leave the chunks that carry no weight anymore out of a digest that was
+    // computed with this collection before. The weights of the other chunks
+    // do not change.
+    //
+    pub fn prune_digest(&self, digest: &[(u64, f64)]) -> Vec<(u64, f64)> {
+        digest
+            .iter()
+            .filter(|(chunk, _)| self.inverse_document_frequency(*chunk).is_some())
+            .copied()
+            .collect()
+    }
+
+    #[cfg(test)]
     pub fn compute_digest(&self, name: &str) -> io::Result<Vec<(u64, f64)>> {
         let file = File::open(name)?;
-        let document: Vec<u64> = file_to_chunks(file);
+        let document: Vec<u64> = file_to_chunks(file)?;
         Ok(self.compute_document_digest(&document))
     }
 
+    //
+    // Like compute_digest, but also returns the metadata of the file, such
+    // as the hash of its contents.
+    //
+    pub fn compute_query_document(&self, name: &str) -> io::Result<Document> {
+        let (document, _) = compute_document(name)?;
+        Ok(self.as_query_document(document))
+    }
+
+    //
+    // The query document of contents that are not in a file, such as an
+    // upload; the name is only used in the results.
+    //
+    pub fn compute_query_document_from_reader<R: Read>(
+        &self,
+        name: &str,
+        reader: R,
+    ) -> io::Result<Document> {
+        let (document, _) =
+            compute_document_from_reader(name, reader, DocumentMetadata::default())?;
+        Ok(self.as_query_document(document))
+    }
+
+    fn as_query_document(&self, document: Document) -> Document {
+        Document {
+            digest: self.compute_document_digest(&document.chunks),
+            chunks: Vec::new(),
+            ..document
+        }
+    }
+
     fn compute_chunk_weight(&self, chunk: u64, frequency: usize) -> Option<f64> {
         if frequency == 0 {
             None
         } else {
-            let entry = self.collection_digests.get(&chunk);
-            match entry {
-                None => None,
-                Some(value) => {
-                    let count = *value as f64;
-                    if *value > 0 && frequency > 0 {
-                        let n = self.collection_digests.len() as f64;
-                        let doc_weight = (n / count).log10();
-                        // Avoid getting infinity as an answer, as it will not serialize well with json
-                        let chunk_weight = (1.0_f64 + frequency as f64).log10();
-                        Some(doc_weight * chunk_weight)
-                    } else {
-                        None
-                    }
+            // Avoid getting infinity as an answer, as it will not serialize well with json
+            self.inverse_document_frequency(chunk)
+                .map(|doc_weight| doc_weight * (1.0_f64 + frequency as f64).log10())
+        }
+    }
+
+    fn inverse_document_frequency(&self, chunk: u64) -> Option<f64> {
+        if self.is_stop_chunk(chunk) {
+            return None;
+        }
+        match self.collection_digests.get(&chunk) {
+            Some(value) if *value > 0 => {
+                if matches!(self.max_chunk_count, Some(max) if *value > max) {
+                    return None;
+                }
+                let count = *value as f64;
+                let n = self.collection_digests.len() as f64;
+                let idf = (n / count).log10();
+                match self.min_idf {
+                    Some(min_idf) if idf < min_idf => None,
+                    _ => Some(idf),
                 }
             }
+            _ => None,
         }
     }
 
+    //
+    // Weigh a digest that was computed with another collection with this one
+    // instead. The frequency of every chunk in the document is recovered from
+    // its weight in the other collection, so the file is not read again.
+    // Chunks that carry no weight in either collection are left out. That
+    // loses the chunks that weigh nothing in the other collection, as how
+    // often they occur cannot be recovered, and the frequencies recovered from
+    // the quantized weights of a compact digest are only approximate.
+    //
+    pub fn reweigh_digest(
+        &self,
+        digest: &[(u64, f64)],
+        weighed_by: &DocumentCollection,
+    ) -> Vec<(u64, f64)> {
+        digest
+            .iter()
+            .filter_map(|(chunk, weight)| {
+                let old_weight = weighed_by.inverse_document_frequency(*chunk)?;
+                if old_weight == 0. {
+                    return None;
+                }
+                let chunk_weight = weight / old_weight;
+                self.inverse_document_frequency(*chunk)
+                    .map(|doc_weight| (*chunk, doc_weight * chunk_weight))
+            })
+            .collect()
+    }
+
     pub fn compute_document_digest(&self, doc: &[u64]) -> Vec<(u64, f64)> {
         // The following is correct according to the paper
         // let frequencies = compute_document_frequencies(doc.clone());
@@ -209,13 +580,28 @@ impl DocumentCollection {
         // Because hashed_doc gets a BTreeMap, it is in sorted order. And because of that
         // it will also have the same order as the internal state chunks.
         let hashed_doc = compute_document_frequencies(doc);
-        let digest = hashed_doc
+        let frequencies: Vec<(u64, usize)> = hashed_doc
             .iter()
-            .map(|(chunk, count)| (chunk, self.compute_chunk_weight(**chunk, *count)))
+            .map(|(chunk, count)| (**chunk, *count))
+            .collect();
+        self.compute_frequencies_digest(&frequencies)
+    }
+
+    //
+    // The digest of a document from how often every chunk occurs in it, sorted
+    // by chunk, so the chunks themselves do not have to be kept around.
+    //
+    pub fn compute_frequencies_digest(&self, frequencies: &[(u64, usize)]) -> Vec<(u64, f64)> {
+        let digest = frequencies
+            .iter()
+            .map(|(chunk, count)| (chunk, self.compute_chunk_weight(*chunk, *count)))
             .filter(|(_, v)| v.is_some())
-            .map(|(k, v)| (**k, v.unwrap()))
+            .map(|(k, v)| (*k, v.unwrap()))
             .collect();
-        digest
+        match &self.digest_cap {
+            Some(digest_cap) => digest_cap.apply(digest),
+            None => digest,
+        }
     }
 }
 
@@ -254,48 +640,108 @@ impl std::hash::Hash for DocumentCollection {
     }
 }
 
-struct DocumentScore {
+//
+// A document that scores higher is better. Of two documents with the same
+// score, the one with the greater path, and then the one that comes first in
+// the documents searched, is better. This makes the order total, so the
+// results do not depend on the order the documents are scored in.
+//
+struct DocumentScore<'a> {
     score: OrderedFloat<f64>,
-    document: Document,
+    document: &'a Document,
+    position: usize,
 }
 
-impl Eq for DocumentScore {}
+impl Eq for DocumentScore<'_> {}
 
-impl Ord for DocumentScore {
+impl Ord for DocumentScore<'_> {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.score.partial_cmp(&other.score).unwrap()
+        self.score
+            .cmp(&other.score)
+            .then_with(|| self.document.file.cmp(&other.document.file))
+            .then_with(|| other.position.cmp(&self.position))
     }
 }
 
-impl PartialEq for DocumentScore {
+impl PartialEq for DocumentScore<'_> {
     fn eq(&self, other: &Self) -> bool {
-        self.score == other.score
+        self.cmp(other) == Ordering::Equal
     }
 }
 
-impl PartialOrd for DocumentScore {
+impl PartialOrd for DocumentScore<'_> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         Some(self.cmp(other))
     }
 }
 
-pub fn ranked_search(
+// Keep the k best scores, the worst of them on top.
+fn push_bounded<'a>(
+    heap: &mut BinaryHeap<Reverse<DocumentScore<'a>>>,
+    score: DocumentScore<'a>,
+    k: usize,
+) {
+    heap.push(Reverse(score));
+    if heap.len() > k {
+        heap.pop();
+    }
+}
+
+//
+// The order of the results of a search, the best first: by score, then by
+// path, both from high to low.
+//
+pub fn compare_results(a: &(f64, Document), b: &(f64, Document)) -> Ordering {
+    OrderedFloat(b.0)
+        .cmp(&OrderedFloat(a.0))
+        .then_with(|| b.1.file.cmp(&a.1.file))
+}
+
+//
+// The k documents that score best against the digest, the best first. The
+// documents can be all of the database, or only the candidates that were
+// picked for the query. They are scored in parallel, every thread keeps its
+// own k best, which are merged at the end.
+//
+// The results are in a total order: by score, then by path, as by
+// compare_results, and then by their position in the documents, the first
+// first. The same documents always give the same results, also at the k-th
+// place, whatever the number of threads.
+//
+pub fn ranked_search<'a, I>(
     doc: &[(u64, f64)],
-    documents: &[Document],
+    documents: I,
     k: usize,
     progress: &ProgressBar,
-) -> Vec<(f64, Document)> {
-    let mut queue: BinaryHeap<Reverse<DocumentScore>> = BinaryHeap::with_capacity(documents.len());
-    documents
-        .iter()
-        .for_each(|other_doc| {
-            queue.push(Reverse(DocumentScore {
-                score: OrderedFloat(cosine_similarity(&other_doc.digest, doc)),
-                document: other_doc.clone(),
-            }));
+    metric: &dyn SimilarityMetric,
+) -> Vec<(f64, Document)>
+where
+    I: IntoParallelIterator<Item = &'a Document>,
+    I::Iter: IndexedParallelIterator,
+{
+    let best = documents
+        .into_par_iter()
+        .enumerate()
+        .fold(BinaryHeap::new, |mut heap, (position, other_doc)| {
+            let score = DocumentScore {
+                score: OrderedFloat(metric.score(doc, &other_doc.digest)),
+                document: other_doc,
+                position,
+            };
+            push_bounded(&mut heap, score, k);
             progress.inc(1);
+            heap
+        })
+        .reduce(BinaryHeap::new, |mut heap, other| {
+            for Reverse(score) in other {
+                push_bounded(&mut heap, score, k);
+            }
+            heap
         });
-    queue.into_sorted_vec().into_iter().take(k).map(|doc_score| (doc_score.0.score.0, doc_score.0.document)).collect::<Vec<_>>()
+    best.into_sorted_vec()
+        .into_iter()
+        .map(|Reverse(doc_score)| (doc_score.score.0, doc_score.document.clone()))
+        .collect()
 }
 
 //
@@ -334,9 +780,35 @@ pub fn cosine_similarity(vec1: &[(u64, f64)], vec2: &[(u64, f64)]) -> f64 {
     norm_prod / (norm_a.sqrt() * norm_b.sqrt())
 }
 
+//
+// The share of the weight of the chunks of part that is also present in
+// whole, between 0 and 1. Unlike the cosine similarity this is not symmetric:
+// a small fragment that is completely inside a large file is contained in it,
+// even though the two are not similar as a whole. The magnitude of the weights
+// is used, as they can be negative for chunks that are very common.
+// Both digests are assumed to be sorted by chunk.
+//
+pub fn containment(part: &[(u64, f64)], whole: &[(u64, f64)]) -> f64 {
+    let mut whole_chunks = whole.iter().map(|(k, _)| *k).peekable();
+    let (shared, total) = part.iter().fold((0_f64, 0_f64), |(shared, total), (k, v)| {
+        while whole_chunks.next_if(|other| other < k).is_some() {}
+        if whole_chunks.next_if_eq(k).is_some() {
+            (shared + v.abs(), total + v.abs())
+        } else {
+            (shared, total + v.abs())
+        }
+    });
+    if total > 0. {
+        shared / total
+    } else {
+        0.
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fbhash::compact::CompactDigest;
     use pretty_assertions::{assert_eq, assert_ne};
     use serde_test::{assert_de_tokens, assert_ser_tokens, assert_tokens, Token};
     use std::fs::File;
@@ -346,7 +818,7 @@ mod tests {
         let mut frequency_map: HashMap<u64, usize> = HashMap::new();
 
         // This is a roundabout way, because HashMapFrequency needs &u64
-        file_to_chunks(file).into_iter().for_each(|e| {
+        file_to_chunks(file).unwrap().into_iter().for_each(|e| {
             let _ = frequency_map.entry(e).and_modify(|e| *e += 1).or_insert(1);
         });
 
@@ -415,7 +887,7 @@ mod tests {
             Err(_) => HashMap::new(),
         };
         let mut document_collection = DocumentCollection::new();
-        document_collection.update_collection(&hash, &[name.clone()]);
+        document_collection.update_collection(&hash, std::slice::from_ref(&name));
 
         assert!(document_collection.exists_file(&name.clone()));
         assert!(!document_collection.collection_digests.is_empty());
@@ -424,6 +896,25 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_remove_document() {
+        let mut document_collection = DocumentCollection::new();
+        let first = [(1, 2), (2, 1)];
+        let second = [(2, 3), (3, 1)];
+        document_collection
+            .update_collection(first.iter().map(|(c, n)| (c, n)), &["a".to_string()]);
+        document_collection
+            .update_collection(second.iter().map(|(c, n)| (c, n)), &["b".to_string()]);
+
+        document_collection.remove_document("a", &first);
+        assert!(!document_collection.exists_file("a"));
+        assert!(document_collection.exists_file("b"));
+        assert_eq!(document_collection.chunk_count(1), 0);
+        assert_eq!(document_collection.chunk_count(2), 3);
+        assert_eq!(document_collection.chunk_count(3), 1);
+        assert_eq!(document_collection.collection_digests.len(), 2);
+    }
+
     #[test]
     fn test_document_collection() -> io::Result<()> {
         let name = String::from("testdata/testfile-yes.bin");
@@ -444,6 +935,190 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_compute_query_document() -> io::Result<()> {
+        let names = ["testdata/testfile-yes.bin", "testdata/testfile-zero.bin"];
+        let mut document_collection = DocumentCollection::new();
+        let yes = document_collection.add_file(names[0])?.unwrap();
+        let zero = document_collection.add_file(names[1])?.unwrap();
+        let query = document_collection.compute_query_document(names[0])?;
+        assert_eq!(query.digest, document_collection.compute_digest(names[0])?);
+        assert!(query.chunks.is_empty());
+        assert!(query.is_identical_to(&yes));
+        assert!(!query.is_identical_to(&zero));
+        assert!(!Document {
+            metadata: DocumentMetadata::default(),
+            ..yes.clone()
+        }
+        .is_identical_to(&Document {
+            metadata: DocumentMetadata::default(),
+            ..yes
+        }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reweigh_digest() -> io::Result<()> {
+        let names = ["testdata/testfile-yes.bin", "testdata/testfile-zero.bin"];
+        let mut own = DocumentCollection::new();
+        own.add_file(names[0])?;
+        let mut other = DocumentCollection::new();
+        other.add_file(names[1])?;
+        let mut merged = own.copy();
+        merged.extend(&other);
+
+        let reweighed = merged.reweigh_digest(&own.compute_digest(names[0])?, &own);
+        let expected = merged.compute_digest(names[0])?;
+        assert_eq!(reweighed.len(), expected.len());
+        for ((chunk, weight), (expected_chunk, expected_weight)) in reweighed.iter().zip(&expected)
+        {
+            assert_eq!(chunk, expected_chunk);
+            assert!(approx_eq!(f64, *weight, *expected_weight, epsilon = 1e-9));
+        }
+        // The chunks of the other file are not known in the own collection.
+        assert!(own
+            .reweigh_digest(&other.compute_digest(names[1])?, &other)
+            .is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reweigh_digest_loses() -> io::Result<()> {
+        // The only chunk of a collection of one document weighs nothing in
+        // it, so how often it occurs cannot be recovered.
+        let first = [(1, 1)];
+        let second = [(2, 1), (3, 1)];
+        let mut own = DocumentCollection::new();
+        own.update_collection(first.iter().map(|(c, n)| (c, n)), &["a".to_string()]);
+        let mut other = DocumentCollection::new();
+        other.update_collection(second.iter().map(|(c, n)| (c, n)), &["b".to_string()]);
+        let mut merged = own.copy();
+        merged.extend(&other);
+        let digest = own.compute_frequencies_digest(&first);
+        assert_eq!(digest, vec![(1, 0.)]);
+        assert_eq!(merged.compute_frequencies_digest(&first).len(), 1);
+        assert!(merged.reweigh_digest(&digest, &own).is_empty());
+
+        // A quantized weight only gives the frequency approximately.
+        let document: Vec<(u64, usize)> = (1..20).map(|chunk| (chunk, chunk as usize)).collect();
+        let unrelated: Vec<(u64, usize)> = (100..120).map(|chunk| (chunk, 1)).collect();
+        let mut own = DocumentCollection::new();
+        own.update_collection(document.iter().map(|(c, n)| (c, n)), &["a".to_string()]);
+        own.update_collection(unrelated.iter().map(|(c, n)| (c, n)), &["b".to_string()]);
+        let mut merged = own.copy();
+        merged.update_collection(second.iter().map(|(c, n)| (c, n)), &["c".to_string()]);
+        let digest = own.compute_frequencies_digest(&document);
+        let quantized = CompactDigest::encode(&digest, Quantization::U8).decode()?;
+        let (minimum, maximum) = digest.iter().fold(
+            (f64::INFINITY, f64::NEG_INFINITY),
+            |(minimum, maximum), (_, weight)| (minimum.min(*weight), maximum.max(*weight)),
+        );
+        let step = (maximum - minimum) / 255.;
+        let reweighed = merged.reweigh_digest(&quantized, &own);
+        let expected = merged.compute_frequencies_digest(&document);
+        assert_eq!(reweighed.len(), expected.len());
+        let mut exact = true;
+        for ((chunk, weight), (_, expected_weight)) in reweighed.iter().zip(&expected) {
+            let scale = merged.inverse_document_frequency(*chunk).unwrap()
+                / own.inverse_document_frequency(*chunk).unwrap();
+            let error = (weight - expected_weight).abs();
+            assert!(error <= scale * step / 2. + 1e-9);
+            exact &= error < 1e-9;
+        }
+        assert!(!exact);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stop_chunks_and_cutoff() -> io::Result<()> {
+        let names = ["testdata/testfile-yes.bin", "testdata/testfile-zero.bin"];
+        let mut document_collection = DocumentCollection::new();
+        document_collection.add_file(names[0])?;
+        document_collection.add_file(names[1])?;
+        let zero_digest = document_collection.compute_digest(names[1])?;
+        assert_eq!(
+            document_collection.most_frequent_chunks(2),
+            vec![(0, 506), (2879926931474365, 253)]
+        );
+
+        document_collection.set_cutoff(None, Some(300));
+        assert!(document_collection.compute_digest(names[1])?.is_empty());
+        assert_eq!(document_collection.compute_digest(names[0])?.len(), 2);
+        assert!(document_collection.prune_digest(&zero_digest).is_empty());
+
+        // The idf of the zero chunk is log10(3 / 506), that of the others log10(3 / 253).
+        document_collection.set_cutoff(Some(-2.0), None);
+        assert!(document_collection.compute_digest(names[1])?.is_empty());
+        assert_eq!(document_collection.compute_digest(names[0])?.len(), 2);
+
+        document_collection.set_cutoff(None, None);
+        document_collection.add_stop_chunks(vec![2879926931474365]);
+        assert_eq!(document_collection.compute_digest(names[0])?.len(), 1);
+        assert_eq!(document_collection.compute_digest(names[1])?, zero_digest);
+        Ok(())
+    }
+
+    #[test]
+    fn test_digest_cap() -> io::Result<()> {
+        let digest = vec![(1, 0.5), (2, -3.0), (3, 1.0), (4, 0.5)];
+        assert_eq!(
+            DigestCap::TopK(2).apply(digest.clone()),
+            vec![(2, -3.0), (3, 1.0)]
+        );
+        assert_eq!(
+            DigestCap::TopK(3).apply(digest.clone()),
+            vec![(1, 0.5), (2, -3.0), (3, 1.0)]
+        );
+        assert_eq!(DigestCap::TopK(10).apply(digest.clone()), digest);
+        assert_eq!(
+            DigestCap::Threshold(1.0).apply(digest.clone()),
+            vec![(2, -3.0), (3, 1.0)]
+        );
+        assert_eq!(
+            DigestCap::Fraction(0.25).apply(digest.clone()),
+            vec![(2, -3.0)]
+        );
+        assert!(DigestCap::Fraction(0.0).apply(digest).is_empty());
+        assert_eq!(
+            DigestCap::Fraction(0.25).to_string(),
+            "the 25% of the chunks with the largest weights"
+        );
+
+        let name = "testdata/testfile-yes.bin";
+        let mut document_collection = DocumentCollection::new();
+        document_collection.add_file(name)?;
+        document_collection.add_file("testdata/testfile-zero.bin")?;
+        document_collection.set_digest_cap(Some(DigestCap::TopK(1)));
+        assert_eq!(document_collection.compute_digest(name)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compute_document_in_archive() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let archive = dir.path().join("evidence.tar");
+        crate::fbhash::archive::tests::create_test_archive(&archive)?;
+        let name = format!("{}!/data/testfile-yes.bin", archive.to_string_lossy());
+        let (document, frequencies) = compute_document(&name)?;
+        let (expected, expected_frequencies) = compute_document("testdata/testfile-yes.bin")?;
+        assert_eq!(document.file, name);
+        assert_eq!(document.chunks, expected.chunks);
+        assert_eq!(frequencies, expected_frequencies);
+        assert!(document.is_identical_to(&expected));
+        dir.close()
+    }
+
+    #[test]
+    fn test_read_error_has_no_hash() -> io::Result<()> {
+        let reader = File::open("testdata/testfile-yes.bin")?
+            .chain(crate::fbhash::chunker::tests::BadSector);
+        assert!(reader_to_chunks_and_hash(reader).is_err());
+        let reader = File::open("testdata/testfile-yes.bin")?
+            .chain(crate::fbhash::chunker::tests::BadSector);
+        assert!(compute_frequencies_from_reader(reader, DocumentMetadata::default()).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_cosine_distance() {
         let vec1 = vec![(0, -1.0), (1, 0.1), (2, 0.2)];
@@ -462,16 +1137,139 @@ mod tests {
         ))
     }
 
+    #[test]
+    fn test_containment() {
+        let fragment = vec![(1, 0.5), (3, -0.5)];
+        let file = vec![(0, 1.0), (1, 0.1), (2, 0.2), (3, 0.3), (4, 2.0)];
+        let other = vec![(1, 0.1), (2, 0.2)];
+        assert!(approx_eq!(
+            f64,
+            containment(&fragment, &file),
+            1.0,
+            ulps = 2
+        ));
+        assert!(approx_eq!(
+            f64,
+            containment(&fragment, &other),
+            0.5,
+            ulps = 2
+        ));
+        assert!(approx_eq!(
+            f64,
+            containment(&file, &fragment),
+            0.4 / 3.6,
+            ulps = 2
+        ));
+        assert!(approx_eq!(f64, containment(&[], &file), 0.0, ulps = 2));
+        assert!(cosine_similarity(&fragment, &file) < 0.5);
+    }
+
+    #[test]
+    fn test_ranked_search() -> Result<(), rayon::ThreadPoolBuildError> {
+        // Half of the documents score the same, only the path decides between them.
+        let documents: Vec<Document> = (0..64)
+            .map(|i| Document {
+                file: format!("file-{:02}", (i * 37) % 64),
+                chunks: Vec::new(),
+                digest: vec![(i % 2, 1.0)],
+                metadata: Default::default(),
+            })
+            .collect();
+        let search = || -> Vec<(f64, String)> {
+            ranked_search(
+                &[(0, 1.0)],
+                &documents,
+                5,
+                &ProgressBar::hidden(),
+                &crate::fbhash::metrics::Cosine,
+            )
+            .into_iter()
+            .map(|(score, document)| (score, document.file))
+            .collect()
+        };
+        let sequential = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()?
+            .install(search);
+        let parallel = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()?
+            .install(search);
+        assert_eq!(sequential, parallel);
+        let files: Vec<&str> = sequential.iter().map(|(_, file)| file.as_str()).collect();
+        assert_eq!(
+            files,
+            vec!["file-62", "file-60", "file-58", "file-56", "file-54"]
+        );
+        assert!(approx_eq!(f64, sequential[0].0, 1.0, ulps = 2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_ranked_search_order() {
+        let document = |file: &str, weight: f64, source: &str| Document {
+            file: file.to_string(),
+            chunks: Vec::new(),
+            digest: vec![(0, weight), (1, 1.0)],
+            metadata: DocumentMetadata {
+                source: Some(source.to_string()),
+                ..Default::default()
+            },
+        };
+        let documents = vec![
+            document("b", 1.0, "first"),
+            document("a", 1.0, "first"),
+            document("c", 0.5, "first"),
+            document("b", 1.0, "second"),
+        ];
+        let results = ranked_search(
+            &[(0, 1.0)],
+            &documents,
+            3,
+            &ProgressBar::hidden(),
+            &crate::fbhash::metrics::Cosine,
+        );
+        let order: Vec<(&str, &str)> = results
+            .iter()
+            .map(|(_, document)| {
+                (
+                    document.file.as_str(),
+                    document.metadata.source.as_deref().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(order, vec![("b", "first"), ("b", "second"), ("a", "first")]);
+        let mut sorted = results.clone();
+        sorted.reverse();
+        sorted.sort_by(compare_results);
+        assert_eq!(
+            sorted
+                .iter()
+                .map(|(_, document)| document.file.as_str())
+                .collect::<Vec<_>>(),
+            vec!["b", "b", "a"]
+        );
+    }
+
     #[test]
     fn test_serialization_of_document() -> io::Result<()> {
         let name = String::from("testdata/testfile-yes.bin");
         let mut document_collection = DocumentCollection::new();
-        let chunks = document_collection.add_file(&name)?.unwrap().chunks;
+        let added = document_collection.add_file(&name)?.unwrap();
         let doc_vector = document_collection.compute_digest(&name)?;
+        assert_eq!(added.metadata.size, 512);
+        assert_eq!(added.metadata.number_of_chunks, 506);
         let doc = Document {
             file: name,
-            chunks,
+            chunks: added.chunks,
             digest: doc_vector,
+            metadata: DocumentMetadata {
+                // The file times depend on the checkout, so leave them out.
+                modified: None,
+                created: None,
+                source: Some("laptop".to_string()),
+                ..added.metadata
+            },
         };
 
         assert_tokens(
@@ -479,7 +1277,7 @@ mod tests {
             &[
                 Token::Struct {
                     name: "Document",
-                    len: 3,
+                    len: 4,
                 },
                 Token::String("file"),
                 Token::String("testdata/testfile-yes.bin"),
@@ -1003,6 +1801,25 @@ mod tests {
                 Token::F64(-5.055178171138189),
                 Token::TupleEnd,
                 Token::SeqEnd,
+                Token::String("metadata"),
+                Token::Struct {
+                    name: "DocumentMetadata",
+                    len: 6,
+                },
+                Token::String("size"),
+                Token::U64(512),
+                Token::String("modified"),
+                Token::None,
+                Token::String("created"),
+                Token::None,
+                Token::String("sha256"),
+                Token::String("2d683c15749fb8b9734e214afb78e40b6d90214f92a2c96bf655635a67daf70d"),
+                Token::String("number_of_chunks"),
+                Token::U64(506),
+                Token::String("source"),
+                Token::Some,
+                Token::String("laptop"),
+                Token::StructEnd,
                 Token::StructEnd,
             ],
         );
@@ -1012,7 +1829,7 @@ mod tests {
 
     #[test]
     fn test_serialization_document_set_state() {
-        let names = vec!["testdata/testfile-yes.bin", "testdata/testfile-zero.bin"];
+        let names = ["testdata/testfile-yes.bin", "testdata/testfile-zero.bin"];
         let mut document_collection = DocumentCollection::new();
         let _ = document_collection.add_file(names[0]);
         let _ = document_collection.add_file(names[1]);
@@ -1026,7 +1843,7 @@ mod tests {
             &[
                 Token::Struct {
                     name: "DocumentCollection",
-                    len: 2,
+                    len: 8,
                 },
                 Token::String("files"),
                 Token::Seq { len: Some(2) },
@@ -1042,6 +1859,19 @@ mod tests {
                 Token::U64(33279275454869446),
                 Token::U64(253),
                 Token::MapEnd,
+                Token::Str("stop_chunks"),
+                Token::Seq { len: Some(0) },
+                Token::SeqEnd,
+                Token::Str("min_idf"),
+                Token::None,
+                Token::Str("max_chunk_count"),
+                Token::None,
+                Token::Str("digest_cap"),
+                Token::None,
+                Token::Str("digest_encoding"),
+                Token::None,
+                Token::Str("shards"),
+                Token::None,
                 Token::StructEnd,
             ],
         );
@@ -1050,7 +1880,7 @@ mod tests {
             &[
                 Token::Struct {
                     name: "DocumentCollection",
-                    len: 2,
+                    len: 8,
                 },
                 Token::String("files"),
                 Token::Seq { len: Some(2) },
@@ -0,0 +1,299 @@
+// Copyright 2021 -- 2023 Erwin van Eijk
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included
+// in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+// TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+// SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter};
+use std::mem::size_of;
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+use crate::fbhash::metadata::DocumentMetadata;
+
+//
+// A document that waits for its digest until the collection is complete. It
+// holds how often every chunk occurs in it, sorted by chunk, instead of all
+// the chunks themselves.
+//
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PendingDocument {
+    pub file: String,
+    pub metadata: DocumentMetadata,
+    pub frequencies: Vec<(u64, usize)>,
+    // The position of the evidence item the document was found in.
+    pub item: usize,
+    // The position of the input of the evidence item the document was found
+    // in, the documents are handed back in the order of the inputs.
+    pub input: usize,
+}
+
+impl PendingDocument {
+    pub fn new(
+        file: String,
+        metadata: DocumentMetadata,
+        frequencies: HashMap<u64, usize>,
+    ) -> PendingDocument {
+        let mut frequencies: Vec<(u64, usize)> = frequencies.into_iter().collect();
+        frequencies.sort_unstable();
+        PendingDocument {
+            file,
+            metadata,
+            frequencies,
+            item: 0,
+            input: 0,
+        }
+    }
+
+    // Where the document belongs among the others.
+    fn order(&self) -> (usize, usize) {
+        (self.item, self.input)
+    }
+
+    // Roughly how much memory the document takes.
+    pub fn size(&self) -> usize {
+        size_of::<PendingDocument>()
+            + self.file.len()
+            + self.metadata.sha256.len()
+            + self.frequencies.len() * size_of::<(u64, usize)>()
+    }
+}
+
+//
+// Keeps the pending documents in memory, until they take more than the
+// budget. Then they are written to a temporary file, sorted by their inputs,
+// and once the collection is complete the files are merged back in the order
+// of the inputs. Without a budget, everything is kept in memory. The
+// documents may come in in any order, the documents of the same input in the
+// order they were found.
+//
+pub struct SpillBuffer {
+    budget: Option<usize>,
+    pending: Vec<PendingDocument>,
+    size: usize,
+    directory: Option<TempDir>,
+    // Every spilled file, with the number of documents in it.
+    spilled: Vec<(PathBuf, usize)>,
+}
+
+impl SpillBuffer {
+    pub fn new(budget: Option<usize>) -> SpillBuffer {
+        SpillBuffer {
+            budget,
+            pending: Vec::new(),
+            size: 0,
+            directory: None,
+            spilled: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len() + self.spilled.iter().map(|(_, count)| count).sum::<usize>()
+    }
+
+    pub fn number_of_spilled_files(&self) -> usize {
+        self.spilled.len()
+    }
+
+    pub fn push(&mut self, document: PendingDocument) -> io::Result<()> {
+        self.size += document.size();
+        self.pending.push(document);
+        match self.budget {
+            Some(budget) if self.size > budget => self.spill(),
+            _ => Ok(()),
+        }
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        if self.directory.is_none() {
+            self.directory = Some(tempfile::Builder::new().prefix("fbhash-").tempdir()?);
+        }
+        let path = self
+            .directory
+            .as_ref()
+            .unwrap()
+            .path()
+            .join(format!("pending-{}.bin", self.spilled.len()));
+        self.pending.sort_by_key(PendingDocument::order);
+        let mut output = BufWriter::new(File::create(&path)?);
+        for document in &self.pending {
+            bincode::serialize_into(&mut output, document).map_err(io::Error::other)?;
+        }
+        output.into_inner().map_err(|e| e.into_error())?;
+        self.spilled.push((path, self.pending.len()));
+        self.pending.clear();
+        self.size = 0;
+        Ok(())
+    }
+
+    //
+    // Hand the pending documents back in batches, in the order of the inputs.
+    // The spilled files are read one document at a time, and a batch takes
+    // about as much memory as the budget. The temporary files are removed
+    // afterwards.
+    //
+    pub fn for_each_batch<F>(mut self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(Vec<PendingDocument>) -> io::Result<()>,
+    {
+        if self.spilled.is_empty() {
+            self.pending.sort_by_key(PendingDocument::order);
+            return match self.pending.is_empty() {
+                true => Ok(()),
+                false => f(self.pending),
+            };
+        }
+        if !self.pending.is_empty() {
+            self.spill()?;
+        }
+        let mut runs = Vec::with_capacity(self.spilled.len());
+        for (path, count) in &self.spilled {
+            runs.push((BufReader::new(File::open(path)?), *count));
+        }
+        // The next document of every file, by its order and then by the file,
+        // so documents of the same input keep the order they came in.
+        let mut heads = BinaryHeap::new();
+        let mut next = Vec::with_capacity(runs.len());
+        for (run, (input, count)) in runs.iter_mut().enumerate() {
+            let document = read_next(input, count)?;
+            if let Some(document) = &document {
+                heads.push(Reverse((document.order(), run)));
+            }
+            next.push(document);
+        }
+        let budget = self.budget.unwrap_or(usize::MAX);
+        let mut batch = Vec::new();
+        let mut size = 0;
+        while let Some(Reverse((_, run))) = heads.pop() {
+            let (input, count) = &mut runs[run];
+            let following = read_next(input, count)?;
+            if let Some(document) = &following {
+                heads.push(Reverse((document.order(), run)));
+            }
+            let document = std::mem::replace(&mut next[run], following).unwrap();
+            size += document.size();
+            batch.push(document);
+            if size > budget {
+                f(std::mem::take(&mut batch))?;
+                size = 0;
+            }
+        }
+        if !batch.is_empty() {
+            f(batch)?;
+        }
+        drop(runs);
+        match self.directory.take() {
+            Some(directory) => directory.close(),
+            None => Ok(()),
+        }
+    }
+}
+
+fn read_next(
+    input: &mut BufReader<File>,
+    count: &mut usize,
+) -> io::Result<Option<PendingDocument>> {
+    if *count == 0 {
+        return Ok(None);
+    }
+    *count -= 1;
+    bincode::deserialize_from(input)
+        .map(Some)
+        .map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn document(file: &str) -> PendingDocument {
+        let frequencies: HashMap<u64, usize> = [(3, 1), (1, 2)].iter().copied().collect();
+        PendingDocument::new(file.to_string(), DocumentMetadata::default(), frequencies)
+    }
+
+    #[test]
+    fn test_pending_document() {
+        assert_eq!(document("a").frequencies, vec![(1, 2), (3, 1)]);
+        assert!(document("a").size() > document("").size());
+    }
+
+    #[test]
+    fn test_spill_buffer() -> io::Result<()> {
+        let names = ["a", "b", "c", "d", "e"];
+        let mut spill = SpillBuffer::new(Some(document("a").size() * 2));
+        for name in names {
+            spill.push(document(name))?;
+        }
+        assert_eq!(spill.len(), 5);
+        assert_eq!(spill.number_of_spilled_files(), 1);
+        let directory = spill.directory.as_ref().unwrap().path().to_path_buf();
+        let mut batches = Vec::new();
+        spill.for_each_batch(|batch| {
+            batches.push(batch);
+            Ok(())
+        })?;
+        assert_eq!(batches.len(), 2);
+        let files: Vec<String> = batches.concat().into_iter().map(|d| d.file).collect();
+        assert_eq!(files, names);
+        assert!(!directory.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_spill_buffer_restores_order() -> io::Result<()> {
+        let inputs = [(2, "c"), (0, "a"), (2, "d"), (3, "e"), (1, "b"), (0, "f")];
+        let mut spill = SpillBuffer::new(Some(document("a").size() * 2));
+        for (input, name) in inputs.iter().copied() {
+            spill.push(PendingDocument {
+                input,
+                ..document(name)
+            })?;
+        }
+        assert_eq!(spill.number_of_spilled_files(), 2);
+        let mut files = Vec::new();
+        spill.for_each_batch(|batch| {
+            files.extend(batch.into_iter().map(|d| d.file));
+            Ok(())
+        })?;
+        assert_eq!(files, ["a", "f", "b", "c", "d", "e"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_spill_buffer_without_budget() -> io::Result<()> {
+        let mut spill = SpillBuffer::new(None);
+        for name in ["a", "b", "c"] {
+            spill.push(document(name))?;
+        }
+        assert_eq!(spill.number_of_spilled_files(), 0);
+        let mut batches = 0;
+        spill.for_each_batch(|batch| {
+            assert_eq!(batch.len(), 3);
+            batches += 1;
+            Ok(())
+        })?;
+        assert_eq!(batches, 1);
+        Ok(())
+    }
+}
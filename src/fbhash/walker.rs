@@ -0,0 +1,470 @@
+// Copyright 2021 -- 2023 Erwin van Eijk
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included
+// in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+// TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+// SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::io;
+use std::path::{Path, PathBuf};
+use walkdir::{DirEntry, WalkDir};
+
+use crate::fbhash::archive::archive_kind;
+use crate::fbhash::report::{FileOutcome, Outcome};
+
+//
+// The knobs that determine which files are handed to the chunker.
+//
+#[derive(Clone, Debug, Default)]
+pub struct WalkerOptions {
+    // Only files matching one of these patterns are indexed, when not empty.
+    pub includes: Vec<String>,
+    // Files and directories matching one of these patterns are skipped.
+    pub excludes: Vec<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub follow_links: bool,
+    pub skip_hidden: bool,
+    pub same_file_system: bool,
+    pub max_depth: Option<usize>,
+    // Index the members of zip and tar archives instead of the archives.
+    pub expand_archives: bool,
+}
+
+#[derive(Debug)]
+pub struct Walker {
+    options: WalkerOptions,
+    includes: GlobSet,
+    excludes: GlobSet,
+}
+
+fn build_glob_set(patterns: &[String]) -> io::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))
+}
+
+// A pattern matches either the full path or just the name of the entry, so
+// both `node_modules` and `**/node_modules` do what one would expect.
+fn matches(set: &GlobSet, path: &Path) -> bool {
+    set.is_match(path) || path.file_name().is_some_and(|name| set.is_match(name))
+}
+
+fn is_hidden(entry: &DirEntry) -> bool {
+    entry.depth() > 0
+        && entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with('.'))
+}
+
+// What an entry that is neither a file nor a directory is.
+fn special_kind(entry: &DirEntry) -> &'static str {
+    let file_type = entry.file_type();
+    if file_type.is_symlink() {
+        return if entry.path().is_dir() {
+            "link to a directory, not followed"
+        } else {
+            "broken symbolic link"
+        };
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_fifo() {
+            return "named pipe";
+        } else if file_type.is_socket() {
+            return "socket";
+        } else if file_type.is_block_device() || file_type.is_char_device() {
+            return "device";
+        }
+    }
+    "special file"
+}
+
+impl Walker {
+    pub fn new(options: &WalkerOptions) -> io::Result<Walker> {
+        Ok(Walker {
+            options: options.clone(),
+            includes: build_glob_set(&options.includes)?,
+            excludes: build_glob_set(&options.excludes)?,
+        })
+    }
+
+    // Decide whether to descend into, or look any further at, this entry.
+    fn keep_entry(&self, entry: &DirEntry) -> bool {
+        if self.options.skip_hidden && is_hidden(entry) {
+            return false;
+        }
+        entry.depth() == 0 || !matches(&self.excludes, entry.path())
+    }
+
+    fn check_size(&self, path: &Path, size: u64) -> Result<(), FileOutcome> {
+        if self.options.min_size.is_some_and(|min| size < min) {
+            Err(FileOutcome::new(path, Outcome::TooSmall))
+        } else if self.options.max_size.is_some_and(|max| size > max) {
+            Err(FileOutcome::new(path, Outcome::TooLarge))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_file(&self, path: &Path) -> Result<(), FileOutcome> {
+        if !self.includes.is_empty() && !matches(&self.includes, path) {
+            return Err(FileOutcome::new(path, Outcome::SkippedByFilter));
+        }
+        if self.options.min_size.is_none() && self.options.max_size.is_none() {
+            return Ok(());
+        }
+        let size = std::fs::metadata(path)
+            .map_err(|e| FileOutcome::from_error(path, &e))?
+            .len();
+        self.check_size(path, size)
+    }
+
+    // Whether the members of this file are indexed, rather than the file.
+    pub fn expands(&self, path: &Path) -> bool {
+        self.options.expand_archives && archive_kind(path).is_some()
+    }
+
+    //
+    // The filters apply to the members of an archive just like they do to
+    // files, using the virtual path of the member.
+    //
+    pub fn check_member(&self, name: &str, size: u64) -> Result<(), FileOutcome> {
+        let path = Path::new(name);
+        if matches(&self.excludes, path)
+            || (!self.includes.is_empty() && !matches(&self.includes, path))
+        {
+            return Err(FileOutcome::new(path, Outcome::SkippedByFilter));
+        }
+        self.check_size(path, size)
+    }
+
+    //
+    // Collect the files to index below start_path. Everything that is not
+    // going to be indexed is returned as well, with the reason why.
+    //
+    pub fn get_files_from_dir(&self, start_path: &PathBuf) -> (Vec<PathBuf>, Vec<FileOutcome>) {
+        let mut walker = WalkDir::new(start_path)
+            .follow_links(self.options.follow_links)
+            .same_file_system(self.options.same_file_system);
+        if let Some(depth) = self.options.max_depth {
+            walker = walker.max_depth(depth);
+        }
+        let mut files = Vec::new();
+        let mut skipped = Vec::new();
+        let mut entries = walker.into_iter();
+        while let Some(entry) = entries.next() {
+            match entry {
+                Ok(entry) => {
+                    if !self.keep_entry(&entry) {
+                        if entry.file_type().is_dir() {
+                            entries.skip_current_dir();
+                        }
+                        skipped.push(FileOutcome::new(entry.path(), Outcome::SkippedByFilter));
+                    } else if entry.path().is_file() {
+                        // An archive is filtered like any other file, before
+                        // its members are.
+                        match self.check_file(entry.path()) {
+                            Ok(()) => files.push(entry.path().to_owned()),
+                            Err(outcome) => skipped.push(outcome),
+                        }
+                    } else if !entry.file_type().is_dir() {
+                        skipped.push(FileOutcome::with_message(
+                            entry.path(),
+                            Outcome::NotAFile,
+                            special_kind(&entry),
+                        ));
+                    }
+                }
+                // Symlink loops and unreadable directories end up here.
+                Err(err) => {
+                    let path = err.path().unwrap_or(start_path).to_owned();
+                    skipped.push(FileOutcome::from_error(&path, &err.into()));
+                }
+            }
+        }
+        (files, skipped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use tempfile::tempdir;
+
+    // TODO:
+    //   Move this to a separate testing toolkit?
+    fn eq_lists<T>(a: &[T], b: &[T]) -> bool
+    where
+        T: PartialEq + Ord,
+    {
+        let mut a: Vec<_> = a.iter().collect();
+        let mut b: Vec<_> = b.iter().collect();
+        a.sort();
+        b.sort();
+
+        a == b
+    }
+
+    fn file_names(paths: &[PathBuf]) -> Vec<String> {
+        let mut names: Vec<String> = paths
+            .iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn get_files_from_dir(start_path: &PathBuf) -> Vec<PathBuf> {
+        Walker::new(&WalkerOptions::default())
+            .unwrap()
+            .get_files_from_dir(start_path)
+            .0
+    }
+
+    #[test]
+    #[cfg(not(target_os = "windows"))]
+    fn test_get_files_from_path() {
+        let test_path = PathBuf::from("testdata");
+        let result = get_files_from_dir(&test_path);
+        assert!(eq_lists(
+            &[
+                Path::new("testdata/testfile-zero-length").to_owned(),
+                Path::new("testdata/testfile-yes.bin").to_owned(),
+                Path::new("testdata/testfile-zero.bin").to_owned(),
+            ],
+            &result[..]
+        ));
+        assert_eq!(result.len(), 3);
+    }
+
+    #[test]
+    #[cfg(target_os = "windows")]
+    fn test_get_files_from_path() {
+        let test_path = PathBuf::from("testdata");
+        let result = get_files_from_dir(&test_path);
+        assert!(eq_lists(
+            &[
+                Path::new("testdata\\testfile-yes.bin").to_owned(),
+                Path::new("testdata\\testfile-zero-length").to_owned(),
+                Path::new("testdata\\testfile-zero.bin").to_owned(),
+            ],
+            &result[..]
+        ));
+        assert_eq!(result.len(), 3);
+    }
+
+    #[test]
+    fn test_include_and_exclude() -> io::Result<()> {
+        let test_path = PathBuf::from("testdata");
+        let options = WalkerOptions {
+            includes: vec!["*.bin".to_string()],
+            excludes: vec!["*-zero.bin".to_string()],
+            ..Default::default()
+        };
+        let (result, skipped) = Walker::new(&options)?.get_files_from_dir(&test_path);
+        assert_eq!(file_names(&result), vec!["testfile-yes.bin"]);
+        assert_eq!(skipped.len(), 2);
+        assert!(skipped
+            .iter()
+            .all(|s| s.outcome == Outcome::SkippedByFilter));
+        Ok(())
+    }
+
+    #[test]
+    fn test_size_limits() -> io::Result<()> {
+        let test_path = PathBuf::from("testdata");
+        let options = WalkerOptions {
+            min_size: Some(1),
+            max_size: Some(512),
+            ..Default::default()
+        };
+        let (result, skipped) = Walker::new(&options)?.get_files_from_dir(&test_path);
+        assert_eq!(
+            file_names(&result),
+            vec!["testfile-yes.bin", "testfile-zero.bin"]
+        );
+        assert_eq!(
+            skipped,
+            vec![FileOutcome::new(
+                &test_path.join("testfile-zero-length"),
+                Outcome::TooSmall
+            )]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_hidden_excluded_directories_and_depth() -> io::Result<()> {
+        let dir = tempdir()?;
+        fs::create_dir_all(dir.path().join("node_modules"))?;
+        fs::create_dir_all(dir.path().join(".git"))?;
+        fs::create_dir_all(dir.path().join("sub"))?;
+        fs::write(dir.path().join("node_modules").join("a.js"), b"a")?;
+        fs::write(dir.path().join(".git").join("HEAD"), b"b")?;
+        fs::write(dir.path().join(".hidden"), b"c")?;
+        fs::write(dir.path().join("visible"), b"d")?;
+        fs::write(dir.path().join("sub").join("deeper"), b"e")?;
+        let start = dir.path().to_path_buf();
+
+        let options = WalkerOptions {
+            excludes: vec!["node_modules".to_string()],
+            skip_hidden: true,
+            ..Default::default()
+        };
+        let (result, skipped) = Walker::new(&options)?.get_files_from_dir(&start);
+        assert_eq!(file_names(&result), vec!["deeper", "visible"]);
+        // The pruned directories are reported, not the files below them.
+        assert_eq!(
+            file_names(
+                &skipped
+                    .iter()
+                    .map(|s| PathBuf::from(&s.file))
+                    .collect::<Vec<_>>()
+            ),
+            vec![".git", ".hidden", "node_modules"]
+        );
+
+        let options = WalkerOptions {
+            max_depth: Some(1),
+            ..Default::default()
+        };
+        let (result, _) = Walker::new(&options)?.get_files_from_dir(&start);
+        assert_eq!(file_names(&result), vec![".hidden", "visible"]);
+        dir.close()
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_symlink_loop() -> io::Result<()> {
+        let dir = tempdir()?;
+        fs::create_dir_all(dir.path().join("sub"))?;
+        fs::write(dir.path().join("sub").join("file"), b"a")?;
+        std::os::unix::fs::symlink(dir.path(), dir.path().join("sub").join("loop"))?;
+        let options = WalkerOptions {
+            follow_links: true,
+            ..Default::default()
+        };
+        let (result, skipped) =
+            Walker::new(&options)?.get_files_from_dir(&dir.path().to_path_buf());
+        assert_eq!(file_names(&result), vec!["file"]);
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].outcome, Outcome::IoError);
+        dir.close()
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_special_files() -> io::Result<()> {
+        let dir = tempdir()?;
+        fs::write(dir.path().join("file"), b"a")?;
+        let _listener = std::os::unix::net::UnixListener::bind(dir.path().join("socket"))?;
+        std::os::unix::fs::symlink(dir.path().join("gone"), dir.path().join("broken"))?;
+        let (result, mut skipped) =
+            Walker::new(&WalkerOptions::default())?.get_files_from_dir(&dir.path().to_path_buf());
+        assert_eq!(file_names(&result), vec!["file"]);
+        skipped.sort_by(|a, b| a.file.cmp(&b.file));
+        assert_eq!(
+            skipped,
+            vec![
+                FileOutcome::with_message(
+                    &dir.path().join("broken"),
+                    Outcome::NotAFile,
+                    "broken symbolic link"
+                ),
+                FileOutcome::with_message(&dir.path().join("socket"), Outcome::NotAFile, "socket"),
+            ]
+        );
+        dir.close()
+    }
+
+    #[test]
+    fn test_archive_members() -> io::Result<()> {
+        let options = WalkerOptions {
+            includes: vec!["*.doc".to_string()],
+            max_size: Some(100),
+            expand_archives: true,
+            ..Default::default()
+        };
+        let walker = Walker::new(&options)?;
+        assert!(walker.expands(Path::new("evidence.zip")));
+        assert!(!walker.expands(Path::new("evidence.doc")));
+        assert!(walker.check_member("evidence.zip!/a.doc", 10).is_ok());
+        assert_eq!(
+            walker.check_member("evidence.zip!/a.txt", 10),
+            Err(FileOutcome::new(
+                Path::new("evidence.zip!/a.txt"),
+                Outcome::SkippedByFilter
+            ))
+        );
+        assert_eq!(
+            walker.check_member("evidence.zip!/a.doc", 1000),
+            Err(FileOutcome::new(
+                Path::new("evidence.zip!/a.doc"),
+                Outcome::TooLarge
+            ))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_archives_are_filtered_as_files() -> io::Result<()> {
+        let dir = tempdir()?;
+        fs::create_dir_all(dir.path().join("directory.zip"))?;
+        fs::write(
+            dir.path().join("directory.zip").join("inside"),
+            b"large enough",
+        )?;
+        fs::write(dir.path().join("small.zip"), b"a")?;
+        let options = WalkerOptions {
+            min_size: Some(2),
+            expand_archives: true,
+            ..Default::default()
+        };
+        let (result, skipped) =
+            Walker::new(&options)?.get_files_from_dir(&dir.path().to_path_buf());
+        assert_eq!(file_names(&result), vec!["inside"]);
+        assert_eq!(
+            skipped,
+            vec![FileOutcome::new(
+                &dir.path().join("small.zip"),
+                Outcome::TooSmall
+            )]
+        );
+        dir.close()
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let options = WalkerOptions {
+            includes: vec!["[".to_string()],
+            ..Default::default()
+        };
+        let result = Walker::new(&options);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
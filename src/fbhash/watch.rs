@@ -0,0 +1,516 @@
+// Copyright 2021 -- 2023 Erwin van Eijk
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included
+// in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+// TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+// SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use notify::{RecursiveMode, Watcher};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::fbhash::format::{deserialize_from, expect_header, write_header};
+use crate::fbhash::index::{index_input, write_index, IndexOptions};
+use crate::fbhash::metrics::SimilarityMetric;
+use crate::fbhash::query::{IndexFiles, QueryIndex, QueryOptions, QueryResults};
+use crate::fbhash::report::Outcome;
+use crate::fbhash::similarities::*;
+use crate::fbhash::spill::{PendingDocument, SpillBuffer};
+use crate::fbhash::utils::*;
+use crate::fbhash::walker::Walker;
+
+//
+// How a directory is watched: how the index is built, how long the files
+// have to be left alone before they are indexed, how often the index is
+// written, and what new files are matched against, if anything.
+//
+#[derive(Clone, Debug)]
+pub struct WatchOptions {
+    pub index: IndexOptions,
+    pub settle: Duration,
+    pub persist_interval: Duration,
+    pub alert: Option<AlertOptions>,
+}
+
+//
+// Match every new or changed file against the reference index, and report
+// it when one of the results scores at least the threshold.
+//
+#[derive(Clone, Debug)]
+pub struct AlertOptions {
+    pub reference: IndexFiles,
+    pub threshold: f64,
+    pub query: QueryOptions,
+}
+
+//
+// An input of the watched directory as it was indexed, with the documents
+// that were found in it; more than one for an archive that is expanded.
+//
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct WatchedInput {
+    size: u64,
+    modified: Option<SystemTime>,
+    documents: Vec<PendingDocument>,
+}
+
+impl WatchedInput {
+    fn is_unchanged(&self, metadata: &fs::Metadata) -> bool {
+        self.size == metadata.len() && self.modified == metadata.modified().ok()
+    }
+}
+
+// The frequencies of the documents are kept next to the database, so the
+// next watch does not have to read all files again. The file starts with the
+// same header as the other binary files.
+fn watch_state_path(database_path: &Path) -> PathBuf {
+    let mut path = database_path.as_os_str().to_owned();
+    path.push(".watch");
+    PathBuf::from(path)
+}
+
+struct WatchedDirectory<'a> {
+    directory: &'a PathBuf,
+    state_path: &'a PathBuf,
+    database_path: &'a Path,
+    options: &'a WatchOptions,
+    config: &'a Configuration,
+    walker: Walker,
+    inputs: BTreeMap<PathBuf, WatchedInput>,
+    document_collection: DocumentCollection,
+    alert: Option<(QueryIndex, Box<dyn SimilarityMetric>)>,
+    changed_since_persist: bool,
+}
+
+impl<'a> WatchedDirectory<'a> {
+    fn load_inputs(&mut self) -> io::Result<()> {
+        let path = watch_state_path(self.database_path);
+        if !path.exists() {
+            return Ok(());
+        }
+        let file = File::open(&path)?;
+        let length = file.metadata()?.len();
+        let mut file = BufReader::new(file);
+        expect_header(&mut file)?;
+        self.inputs = deserialize_from(file, length)?;
+        for input in self.inputs.values() {
+            for pending in &input.documents {
+                self.document_collection.update_collection(
+                    pending
+                        .frequencies
+                        .iter()
+                        .map(|(chunk, count)| (chunk, count)),
+                    std::slice::from_ref(&pending.file),
+                );
+            }
+        }
+        if !self.config.quiet {
+            println!(
+                "Loaded {} files of the earlier watch from {}",
+                self.inputs.len(),
+                path.display()
+            );
+        }
+        Ok(())
+    }
+
+    //
+    // The index itself may be written into the watched directory, its files
+    // are not indexed: the state, and the database with everything that is
+    // named after it, like its shards, signatures and log.
+    //
+    fn is_index_file(&self, path: &Path) -> bool {
+        let Ok(path) = fs::canonicalize(path) else {
+            return false;
+        };
+        let output = |output: &Path| -> Option<(PathBuf, String)> {
+            let parent = match output.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            let name = output.file_name()?.to_string_lossy().to_string();
+            Some((fs::canonicalize(parent).ok()?, name))
+        };
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let in_directory = |directory: &Path| path.parent() == Some(directory);
+        matches!(output(self.database_path),
+            Some((directory, database)) if in_directory(&directory) && name.starts_with(&database))
+            || matches!(output(self.state_path),
+            Some((directory, state)) if in_directory(&directory) && name == state)
+    }
+
+    fn remove_input(&mut self, path: &Path) -> Option<WatchedInput> {
+        let input = self.inputs.remove(path)?;
+        for pending in &input.documents {
+            self.document_collection
+                .remove_document(&pending.file, &pending.frequencies);
+        }
+        self.changed_since_persist = true;
+        Some(input)
+    }
+
+    fn add_input(&mut self, path: PathBuf, input: WatchedInput) {
+        for pending in &input.documents {
+            self.document_collection.update_collection(
+                pending
+                    .frequencies
+                    .iter()
+                    .map(|(chunk, count)| (chunk, count)),
+                std::slice::from_ref(&pending.file),
+            );
+        }
+        self.inputs.insert(path, input);
+        self.changed_since_persist = true;
+    }
+
+    //
+    // Bring the index up to date with the directory: the inputs that are
+    // gone are removed, the new ones are added, and those that changed, or
+    // that the notifications mention, are indexed again. The files that were
+    // modified within the settle time may still be being written, they are
+    // returned to look at again later, together with the documents that were
+    // added or changed.
+    //
+    fn rescan(&mut self, touched: &BTreeSet<PathBuf>) -> (Vec<PendingDocument>, BTreeSet<PathBuf>) {
+        let (files, _) = self.walker.get_files_from_dir(self.directory);
+        let files: BTreeSet<PathBuf> = files
+            .into_iter()
+            .filter(|path| !self.is_index_file(path))
+            .collect();
+        let gone: Vec<PathBuf> = self
+            .inputs
+            .keys()
+            .filter(|path| !files.contains(*path))
+            .cloned()
+            .collect();
+        for path in &gone {
+            self.remove_input(path);
+        }
+        let mut unsettled = BTreeSet::new();
+        let to_index: Vec<(PathBuf, fs::Metadata)> = files
+            .into_iter()
+            .filter_map(|path| {
+                let metadata = fs::metadata(&path).ok()?;
+                let age = metadata.modified().ok().and_then(|m| m.elapsed().ok());
+                match self.inputs.get(&path) {
+                    Some(input) if input.is_unchanged(&metadata) && !touched.contains(&path) => {
+                        None
+                    }
+                    _ if age.is_some_and(|age| age < self.options.settle) => {
+                        unsettled.insert(path);
+                        None
+                    }
+                    _ => Some((path, metadata)),
+                }
+            })
+            .collect();
+        let walker = &self.walker;
+        let indexed: Vec<(PathBuf, fs::Metadata, Vec<PendingDocument>, bool)> = to_index
+            .into_par_iter()
+            .map(|(path, metadata)| {
+                let mut documents = Vec::new();
+                let mut failed = false;
+                for result in index_input(&path, walker) {
+                    match result {
+                        Ok(pending) => documents.push(pending),
+                        Err(outcome) => {
+                            failed |= matches!(
+                                outcome.outcome,
+                                Outcome::PermissionDenied | Outcome::IoError
+                            );
+                            eprintln!("{}: {}", outcome.file, outcome.outcome)
+                        }
+                    }
+                }
+                (path, metadata, documents, failed)
+            })
+            .collect();
+
+        let mut arrived = Vec::new();
+        for (path, metadata, documents, failed) in indexed {
+            let previous = self.remove_input(&path);
+            // An input that could not be read is left out, rather than kept
+            // with its size and time: a chmod does not change those, and the
+            // next scan has to try it again.
+            if failed {
+                continue;
+            }
+            let unchanged = |document: &PendingDocument| {
+                previous.as_ref().is_some_and(|previous| {
+                    previous.documents.iter().any(|other| {
+                        other.file == document.file
+                            && other.metadata.sha256 == document.metadata.sha256
+                    })
+                })
+            };
+            arrived.extend(documents.iter().filter(|d| !unchanged(d)).cloned());
+            self.add_input(
+                path,
+                WatchedInput {
+                    size: metadata.len(),
+                    modified: metadata.modified().ok(),
+                    documents,
+                },
+            );
+        }
+        if !self.config.quiet && (!gone.is_empty() || !arrived.is_empty()) {
+            println!(
+                "Documents added or changed: {}, inputs removed: {}",
+                arrived.len(),
+                gone.len()
+            );
+        }
+        (arrived, unsettled)
+    }
+
+    //
+    // Report the documents that match the reference index well enough, as
+    // a line of JSON each with the results that do.
+    //
+    fn alert(&self, arrived: &[PendingDocument]) {
+        let (Some(alert), Some((reference, metric))) = (&self.options.alert, &self.alert) else {
+            return;
+        };
+        for pending in arrived {
+            let query_document = Document {
+                file: pending.file.clone(),
+                chunks: Vec::new(),
+                digest: reference
+                    .document_collection()
+                    .compute_frequencies_digest(&pending.frequencies),
+                metadata: pending.metadata.clone(),
+            };
+            let results: Vec<(f64, Document)> = reference
+                .search(&query_document, metric.as_ref(), &alert.query)
+                .into_iter()
+                .filter(|(score, _)| *score >= alert.threshold)
+                .collect();
+            if !results.is_empty() {
+                let results =
+                    QueryResults::new(&pending.file, &query_document, &results, &alert.query);
+                println!("{}", serde_json::to_string(&results).unwrap());
+            }
+        }
+    }
+
+    //
+    // Write the database and the state of all documents as they are now,
+    // and the frequencies to pick up from the next time.
+    //
+    fn persist(&mut self) -> io::Result<()> {
+        let mut spill = SpillBuffer::new(self.options.index.memory_budget);
+        for input in self.inputs.values() {
+            for pending in &input.documents {
+                spill.push(pending.clone())?;
+            }
+        }
+        let quiet = Configuration {
+            quiet: true,
+            ..*self.config
+        };
+        write_index(
+            self.document_collection.copy(),
+            spill,
+            self.state_path,
+            self.database_path,
+            &self.options.index,
+            &quiet,
+        )?;
+        let mut file = BufWriter::new(File::create(watch_state_path(self.database_path))?);
+        write_header(&mut file)?;
+        bincode::serialize_into(&mut file, &self.inputs).map_err(io::Error::other)?;
+        file.flush()?;
+        self.changed_since_persist = false;
+        if !self.config.quiet {
+            println!(
+                "Wrote the index of {} documents to {}",
+                self.document_collection.number_of_files(),
+                self.database_path.display()
+            );
+        }
+        Ok(())
+    }
+}
+
+// How many times the settle time a stream of changes is waited for, before
+// the directory is looked at anyway.
+const MAX_SETTLE_ROUNDS: u32 = 10;
+
+//
+// Keep the index of a directory up to date while files appear, change and
+// disappear in it, until the process is stopped. The index is built, or
+// picked up from the last watch, and then brought up to date whenever the
+// notifications of the file system say something changed and things
+// settled down. The index is written at most every persist interval.
+//
+pub fn watch_directory(
+    directory: &PathBuf,
+    state_path: &PathBuf,
+    database_path: &Path,
+    options: &WatchOptions,
+    config: &Configuration,
+) -> io::Result<()> {
+    let alert = match &options.alert {
+        Some(alert) => {
+            let reference = QueryIndex::open(
+                &alert.reference.state,
+                &alert.reference.database,
+                &alert.query,
+                config,
+            )?;
+            let metric = reference.metric(alert.query.metric_kind);
+            Some((reference, metric))
+        }
+        None => None,
+    };
+    let mut watched = WatchedDirectory {
+        directory,
+        state_path,
+        database_path,
+        options,
+        config,
+        walker: Walker::new(&options.index.walker)?,
+        inputs: BTreeMap::new(),
+        document_collection: DocumentCollection::new(),
+        alert,
+        changed_since_persist: true,
+    };
+    watched.load_inputs()?;
+
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(io::Error::other)?;
+    watcher
+        .watch(directory, RecursiveMode::Recursive)
+        .map_err(io::Error::other)?;
+    if !config.quiet {
+        println!("Watching {}", directory.display());
+    }
+
+    let (_, mut touched) = watched.rescan(&BTreeSet::new());
+    watched.persist()?;
+    let mut first_change = if touched.is_empty() {
+        None
+    } else {
+        Some(Instant::now())
+    };
+    let mut last_persist = Instant::now();
+    loop {
+        let quiet = match receiver.recv_timeout(options.settle) {
+            Ok(Ok(event)) => {
+                let paths: Vec<PathBuf> = event
+                    .paths
+                    .into_iter()
+                    .filter(|path| !watched.is_index_file(path))
+                    .collect();
+                // Writing the index is no reason to look at the directory.
+                if paths.is_empty() {
+                    continue;
+                }
+                touched.extend(paths);
+                false
+            }
+            // Events may have been lost, the rescan finds what changed.
+            Ok(Err(e)) => {
+                eprintln!("{}", e);
+                false
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => true,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        if !quiet {
+            first_change.get_or_insert_with(Instant::now);
+        }
+        // Wait for the changes to settle, but not forever when they do not.
+        let settled = matches!(first_change, Some(first)
+            if quiet || first.elapsed() >= options.settle * MAX_SETTLE_ROUNDS);
+        if settled {
+            let (arrived, unsettled) = watched.rescan(&touched);
+            watched.alert(&arrived);
+            first_change = if unsettled.is_empty() {
+                None
+            } else {
+                Some(Instant::now())
+            };
+            touched = unsettled;
+        }
+        if watched.changed_since_persist && last_persist.elapsed() >= options.persist_interval {
+            watched.persist()?;
+            last_persist = Instant::now();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fbhash::walker::WalkerOptions;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_failed_input_is_not_recorded() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let directory = dir.path().join("drop");
+        fs::create_dir(&directory)?;
+        fs::copy(
+            "testdata/testfile-yes.bin",
+            directory.join("testfile-yes.bin"),
+        )?;
+        fs::write(directory.join("broken.tar.gz"), b"not an archive")?;
+        let state_path = dir.path().join("state");
+        let database_path = dir.path().join("database");
+        let options = WatchOptions {
+            index: IndexOptions {
+                walker: WalkerOptions {
+                    expand_archives: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            settle: Duration::ZERO,
+            persist_interval: Duration::ZERO,
+            alert: None,
+        };
+        let config = Configuration::new(OutputFormat::Binary, true);
+        let mut watched = WatchedDirectory {
+            directory: &directory,
+            state_path: &state_path,
+            database_path: &database_path,
+            options: &options,
+            config: &config,
+            walker: Walker::new(&options.index.walker)?,
+            inputs: BTreeMap::new(),
+            document_collection: DocumentCollection::new(),
+            alert: None,
+            changed_since_persist: false,
+        };
+
+        let (arrived, unsettled) = watched.rescan(&BTreeSet::new());
+        assert_eq!(arrived.len(), 1);
+        assert!(unsettled.is_empty());
+        let recorded: Vec<&PathBuf> = watched.inputs.keys().collect();
+        assert_eq!(recorded, vec![&directory.join("testfile-yes.bin")]);
+        dir.close()
+    }
+}
@@ -18,20 +18,34 @@
 // TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
 // SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-#[cfg(test)]
-#[macro_use]
-extern crate pretty_assertions;
 #[cfg(test)]
 #[macro_use]
 extern crate float_cmp;
 extern crate clap;
 mod fbhash;
 
+use clap::builder::RangedU64ValueParser;
+use clap::parser::ValueSource;
 use clap::{arg, value_parser, Arg, ArgAction, Command};
+use fbhash::compact::{compact_for_results, Quantization};
+use fbhash::compare::{compare_directories_for_results, compare_for_results};
+use fbhash::explain::explain_match;
 use fbhash::index::*;
+use fbhash::known::{KnownFilter, KnownOptions};
+use fbhash::lsh::LshOptions;
+use fbhash::metrics::MetricKind;
 use fbhash::query::*;
+use fbhash::report::read_report;
+use fbhash::serve::{serve, ServeOptions};
+use fbhash::settings::{load_settings, Settings};
+use fbhash::shard::{ShardKey, ShardOptions};
+use fbhash::similarities::DigestCap;
 use fbhash::utils::{Configuration, OutputFormat};
+use fbhash::walker::WalkerOptions;
+use fbhash::watch::{watch_directory, AlertOptions, WatchOptions};
+use std::collections::HashSet;
 use std::path::PathBuf;
+use std::time::Duration;
 
 fn file_arguments() -> Vec<clap::Arg> {
     vec![
@@ -44,8 +58,337 @@ fn file_arguments() -> Vec<clap::Arg> {
     ]
 }
 
+fn log_argument() -> clap::Arg {
+    arg!(-l --log <LOG_FILE> "Where the outcome for every file seen while indexing is kept [default: <DATABASE_FILE>.log]")
+        .required(false)
+        .value_parser(value_parser!(PathBuf))
+}
+
+// The index log lives next to the database, unless asked otherwise.
+fn log_file(matches: &clap::ArgMatches) -> PathBuf {
+    match matches.get_one::<PathBuf>("log") {
+        Some(log_file) => log_file.clone(),
+        None => {
+            let mut log_file = matches
+                .get_one::<PathBuf>("database")
+                .unwrap()
+                .clone()
+                .into_os_string();
+            log_file.push(".log");
+            PathBuf::from(log_file)
+        }
+    }
+}
+
+fn walker_arguments() -> Vec<clap::Arg> {
+    vec![
+        arg!(--include <PATTERN> "Only index files matching this glob pattern")
+            .required(false)
+            .action(ArgAction::Append),
+        arg!(--exclude <PATTERN> "Skip files and directories matching this glob pattern")
+            .required(false)
+            .action(ArgAction::Append),
+        arg!(--"min-size" <BYTES> "Skip files smaller than this")
+            .required(false)
+            .value_parser(value_parser!(u64)),
+        arg!(--"max-size" <BYTES> "Skip files larger than this")
+            .required(false)
+            .value_parser(value_parser!(u64)),
+        arg!(-L --"follow-links" "Follow symbolic links, loops are detected and skipped")
+            .action(ArgAction::SetTrue),
+        arg!(--"skip-hidden" "Skip hidden files and directories").action(ArgAction::SetTrue),
+        arg!(-x --"one-file-system" "Do not cross file system boundaries")
+            .action(ArgAction::SetTrue),
+        arg!(--"max-depth" <DEPTH> "Do not descend deeper than this into the directories")
+            .required(false)
+            .value_parser(value_parser!(usize)),
+        arg!(--"expand-archives" "Index the members of zip and tar archives as documents of their own")
+            .action(ArgAction::SetTrue),
+    ]
+}
+
+fn metric_argument() -> clap::Arg {
+    arg!(--metric <METRIC> "How to score the similarity of the documents")
+        .value_parser(MetricKind::NAMES)
+        .default_value("cosine")
+}
+
+fn metric_kind(matches: &clap::ArgMatches) -> std::io::Result<MetricKind> {
+    matches.get_one::<String>("metric").unwrap().parse()
+}
+
+fn known_arguments() -> Vec<clap::Arg> {
+    vec![
+        arg!(--known <DATABASE_FILE> "The database of an index of known files to leave out")
+            .required(false)
+            .requires("known-state")
+            .value_parser(value_parser!(PathBuf)),
+        arg!(--"known-state" <STATE_FILE> "The state of the index of known files")
+            .required(false)
+            .requires("known")
+            .value_parser(value_parser!(PathBuf)),
+        arg!(--"known-threshold" <SCORE> "Leave out the documents that match a known file at least this well")
+            .value_parser(parse_fraction)
+            .default_value("0.9"),
+        arg!(--"suppress-known" "Instead of leaving out documents, give no weight to the chunks of the known files")
+            .conflicts_with("known-threshold")
+            .action(ArgAction::SetTrue),
+        arg!(--"known-min-files" <COUNT> "With --suppress-known, only the chunks that are in at least this many known files")
+            .value_parser(value_parser!(usize))
+            .default_value("1"),
+    ]
+}
+
+fn cutoff_arguments() -> Vec<clap::Arg> {
+    vec![
+        arg!(--"min-idf" <IDF> "Leave the chunks with a lower inverse document frequency out of the digests")
+            .required(false)
+            .allow_negative_numbers(true)
+            .value_parser(value_parser!(f64)),
+        arg!(--"max-chunk-count" <COUNT> "Leave the chunks that occur more often than this out of the digests")
+            .required(false)
+            .value_parser(value_parser!(usize)),
+        arg!(--"digest-top" <NUMBER> "Only keep this many chunks with the largest weights in every digest")
+            .required(false)
+            .conflicts_with_all(["digest-min-weight", "digest-fraction"])
+            .value_parser(value_parser!(usize)),
+        arg!(--"digest-min-weight" <WEIGHT> "Only keep the chunks with at least this weight in every digest")
+            .required(false)
+            .conflicts_with("digest-fraction")
+            .value_parser(value_parser!(f64)),
+        arg!(--"digest-fraction" <FRACTION> "Only keep this fraction of the chunks, with the largest weights, in every digest")
+            .required(false)
+            .value_parser(parse_fraction),
+    ]
+}
+
+fn lsh_arguments() -> Vec<clap::Arg> {
+    vec![
+        arg!(--"lsh-bands" <BANDS> "Also write signatures with this many bands, for approximate queries")
+            .required(false)
+            .value_parser(RangedU64ValueParser::<usize>::new().range(1..)),
+        arg!(--"lsh-rows" <ROWS> "The number of rows in every band of the signatures")
+            .value_parser(RangedU64ValueParser::<usize>::new().range(1..))
+            .default_value("4"),
+    ]
+}
+
+fn lsh_options(matches: &clap::ArgMatches) -> Option<LshOptions> {
+    matches
+        .get_one::<usize>("lsh-bands")
+        .map(|bands| LshOptions {
+            bands: *bands,
+            rows: *matches.get_one::<usize>("lsh-rows").unwrap(),
+        })
+}
+
+fn compact_argument() -> clap::Arg {
+    arg!(--compact <QUANTIZATION> "Store the digests in the compact form, with the weights in this many bits")
+        .required(false)
+        .value_parser(Quantization::NAMES)
+}
+
+fn digest_encoding(matches: &clap::ArgMatches) -> std::io::Result<Option<Quantization>> {
+    matches
+        .get_one::<String>("compact")
+        .map(|name| name.parse())
+        .transpose()
+}
+
+fn shard_arguments() -> Vec<clap::Arg> {
+    vec![
+        arg!(--shards <SHARDS> "Split the database in this many shards")
+            .required(false)
+            .value_parser(value_parser!(u64).range(1..)),
+        arg!(--"shard-by" <KEY> "What decides the shard of a file: a hash of its path, or the path or image it was found in")
+            .value_parser(ShardKey::NAMES)
+            .default_value("path"),
+    ]
+}
+
+fn shard_options(matches: &clap::ArgMatches) -> std::io::Result<Option<ShardOptions>> {
+    matches
+        .get_one::<u64>("shards")
+        .map(|shards| {
+            Ok(ShardOptions {
+                shards: *shards as usize,
+                key: matches.get_one::<String>("shard-by").unwrap().parse()?,
+            })
+        })
+        .transpose()
+}
+
+fn threads_argument() -> clap::Arg {
+    arg!(--threads <THREADS> "How many threads to use [default: one per core]")
+        .required(false)
+        .value_parser(value_parser!(u64).range(1..))
+}
+
+fn set_threads(matches: &clap::ArgMatches) -> std::io::Result<()> {
+    if let Ok(Some(threads)) = matches.try_get_one::<u64>("threads") {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(*threads as usize)
+            .build_global()
+            .map_err(std::io::Error::other)?;
+    }
+    Ok(())
+}
+
+fn memory_budget_argument() -> clap::Arg {
+    arg!(--"memory-budget" <MB> "Move the pending documents to temporary files when they take more memory than this; only they are counted, not the collection or the known files")
+        .required(false)
+        .value_parser(value_parser!(usize))
+}
+
+fn memory_budget(matches: &clap::ArgMatches) -> Option<usize> {
+    matches
+        .get_one::<usize>("memory-budget")
+        .map(|megabytes| megabytes.saturating_mul(1024 * 1024))
+}
+
+fn parse_fraction(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(fraction) if fraction > 0. && fraction <= 1. => Ok(fraction),
+        _ => Err(format!("{} is not a fraction between 0 and 1", value)),
+    }
+}
+
+fn digest_cap(matches: &clap::ArgMatches) -> Option<DigestCap> {
+    if let Some(k) = matches.get_one::<usize>("digest-top") {
+        Some(DigestCap::TopK(*k))
+    } else if let Some(threshold) = matches.get_one::<f64>("digest-min-weight") {
+        Some(DigestCap::Threshold(*threshold))
+    } else {
+        matches
+            .get_one::<f64>("digest-fraction")
+            .map(|fraction| DigestCap::Fraction(*fraction))
+    }
+}
+
+fn parse_chunk(value: &str) -> Result<u64, std::num::ParseIntError> {
+    u64::from_str_radix(value.trim_start_matches("0x"), 16)
+}
+
+fn known_options(matches: &clap::ArgMatches) -> Option<KnownOptions> {
+    let database = matches.get_one::<PathBuf>("known")?;
+    let filter = if matches.get_flag("suppress-known") {
+        KnownFilter::Suppress {
+            min_files: *matches.get_one::<usize>("known-min-files").unwrap(),
+        }
+    } else {
+        KnownFilter::Drop {
+            threshold: *matches.get_one::<f64>("known-threshold").unwrap(),
+        }
+    };
+    Some(KnownOptions {
+        index: IndexFiles {
+            state: matches.get_one::<PathBuf>("known-state").unwrap().clone(),
+            database: database.clone(),
+        },
+        filter,
+    })
+}
+
+fn walker_options(matches: &clap::ArgMatches) -> WalkerOptions {
+    let patterns = |name: &str| -> Vec<String> {
+        matches
+            .get_many::<String>(name)
+            .map(|values| values.cloned().collect())
+            .unwrap_or_default()
+    };
+    WalkerOptions {
+        includes: patterns("include"),
+        excludes: patterns("exclude"),
+        min_size: matches.get_one::<u64>("min-size").copied(),
+        max_size: matches.get_one::<u64>("max-size").copied(),
+        follow_links: matches.get_flag("follow-links"),
+        skip_hidden: matches.get_flag("skip-hidden"),
+        same_file_system: matches.get_flag("one-file-system"),
+        max_depth: matches.get_one::<usize>("max-depth").copied(),
+        expand_archives: matches.get_flag("expand-archives"),
+    }
+}
+
+//
+// The long names of the options of the command and all its subcommands.
+//
+fn long_names(command: &Command, names: &mut HashSet<String>) {
+    names.extend(
+        command
+            .get_arguments()
+            .filter_map(|arg| arg.get_long())
+            .map(|long| long.to_string()),
+    );
+    for subcommand in command.get_subcommands() {
+        long_names(subcommand, names);
+    }
+}
+
+//
+// The options that share their long name with those of other subcommands, but
+// mean something else. A setting does not apply to them: a state would make
+// compare weigh the chunks instead of treating them all the same, and the
+// number of results is not the number of chunks explain lists.
+//
+const NOT_SETTABLE: [(&str, &str); 3] = [
+    ("compare", "state"),
+    ("compare-dirs", "state"),
+    ("explain", "number"),
+];
+
+fn with_defaults(mut command: Command, settings: &Settings) -> Command {
+    let defaults: Vec<(String, Vec<String>)> = command
+        .get_arguments()
+        .filter_map(|arg| {
+            let long = arg.get_long()?;
+            if NOT_SETTABLE.contains(&(command.get_name(), long)) {
+                return None;
+            }
+            let setting = settings.get(long)?;
+            Some((arg.get_id().to_string(), setting.values.clone()))
+        })
+        .collect();
+    for (id, values) in defaults {
+        command = command.mut_arg(id, |arg| arg.default_values(values));
+    }
+    let subcommands: Vec<String> = command
+        .get_subcommands()
+        .map(|subcommand| subcommand.get_name().to_string())
+        .collect();
+    for name in subcommands {
+        command = command.mut_subcommand(name, |subcommand| with_defaults(subcommand, settings));
+    }
+    command
+}
+
+//
+// The settings of the configuration files and the environment become the
+// defaults of the options with the same long name, in every subcommand but
+// the ones in NOT_SETTABLE.
+//
+fn apply_settings(command: Command, settings: &Settings) -> std::io::Result<Command> {
+    let mut names = HashSet::new();
+    long_names(&command, &mut names);
+    // Other programs may use variables with the same prefix, so a variable
+    // without an option is only warned about. A file names options only.
+    let (variables, files): (Vec<_>, Vec<_>) = settings
+        .iter()
+        .filter(|(name, _)| !names.contains(*name))
+        .partition(|(_, setting)| setting.environment);
+    if let Some((name, setting)) = files.first() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{}: there is no option --{}", setting.origin, name),
+        ));
+    }
+    for (name, setting) in variables {
+        eprintln!("Ignoring {}: there is no option --{}", setting.origin, name);
+    }
+    Ok(with_defaults(command, settings))
+}
+
 fn main() -> std::io::Result<()> {
-    let matches = Command::new("fbhash")
+    let command = Command::new("fbhash")
         .version("0.1.0")
         .author("Erwin van Eijk")
         .about("Find near duplicates of files")
@@ -62,13 +405,111 @@ fn main() -> std::io::Result<()> {
         )
         .arg(arg!(-q --quiet "Suppress all output but the end result").action(ArgAction::SetTrue))
         .subcommand(
-            Command::new("index").args(file_arguments()).arg(
-                arg!(<INPUT> ... "Path to directories to process")
-                    .required(true)
-                    .value_parser(value_parser!(PathBuf))
-                    .num_args(1..)
-                    .action(ArgAction::Append),
-            ),
+            Command::new("index")
+                .args(file_arguments())
+                .arg(log_argument())
+                .args(walker_arguments())
+                .args(known_arguments())
+                .args(cutoff_arguments())
+                .args(lsh_arguments())
+                .arg(compact_argument())
+                .arg(memory_budget_argument())
+                .args(shard_arguments())
+                .arg(threads_argument())
+                .arg(
+                    arg!(--source <LABEL> "The evidence item or source the files are taken from")
+                        .required(false),
+                )
+                .arg(
+                    arg!(<INPUT> ... "Path to directories to process")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .num_args(1..)
+                        .action(ArgAction::Append),
+                ),
+        )
+        .subcommand(
+            Command::new("index-blocks")
+                .about("Index raw images in fixed size blocks, a document per block")
+                .args(file_arguments())
+                .arg(log_argument())
+                .arg(
+                    arg!(--"block-size" <BYTES> "The size of the blocks, e.g. the sector size")
+                        .value_parser(RangedU64ValueParser::<usize>::new().range(7..))
+                        .default_value("4096"),
+                )
+                .args(known_arguments())
+                .args(cutoff_arguments())
+                .args(lsh_arguments())
+                .arg(compact_argument())
+                .arg(memory_budget_argument())
+                .args(shard_arguments())
+                .arg(threads_argument())
+                .arg(
+                    arg!(--source <LABEL> "The evidence item or source the images are taken from")
+                        .required(false),
+                )
+                .arg(
+                    arg!(<INPUT> ... "Path to the images to process")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .num_args(1..)
+                        .action(ArgAction::Append),
+                ),
+        )
+        .subcommand(
+            Command::new("watch")
+                .about("Keep the index of a directory up to date while files appear, change and disappear in it")
+                .args(file_arguments())
+                .args(walker_arguments())
+                .args(known_arguments())
+                .args(cutoff_arguments())
+                .args(lsh_arguments())
+                .arg(compact_argument())
+                .args(shard_arguments())
+                .arg(threads_argument())
+                .arg(
+                    arg!(--source <LABEL> "The evidence item or source the files are taken from")
+                        .required(false),
+                )
+                .arg(
+                    arg!(--settle <SECONDS> "How long the files have to be left alone before they are indexed")
+                        .value_parser(value_parser!(u64).range(1..))
+                        .default_value("2"),
+                )
+                .arg(
+                    arg!(--"persist-interval" <SECONDS> "How often at most the index is written")
+                        .value_parser(value_parser!(u64))
+                        .default_value("60"),
+                )
+                .arg(
+                    arg!(--"alert-database" <DATABASE_FILE> "Match every new file against this index, and report the matches")
+                        .required(false)
+                        .requires("alert-state")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--"alert-state" <STATE_FILE> "The state of the index to match the new files against")
+                        .required(false)
+                        .requires("alert-database")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--"alert-threshold" <SCORE> "Report the matches that score at least this")
+                        .value_parser(value_parser!(f64))
+                        .default_value("0.5"),
+                )
+                .arg(
+                    arg!(-n --number <RESULT_SIZE> "How many matches to report for a file at most")
+                        .value_parser(value_parser!(usize))
+                        .default_value("5"),
+                )
+                .arg(metric_argument())
+                .arg(
+                    arg!(<DIRECTORY> "The directory to watch")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                ),
         )
         .subcommand(
             Command::new("query")
@@ -82,19 +523,240 @@ fn main() -> std::io::Result<()> {
                         .action(ArgAction::Append),
                 )
                 .args(file_arguments())
+                .arg(
+                    arg!(-m --metadata "Show the metadata of the matching documents")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(metric_argument())
+                .arg(
+                    arg!(-c --containment "Rank by how much of the query is contained in a document, to find fragments in larger files, short for --metric containment")
+                        .conflicts_with("metric")
+                        .action(ArgAction::SetTrue),
+                )
+                .args(known_arguments())
+                .arg(
+                    arg!(--approximate "Only score the documents that the signatures of the index pick as candidates")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--"min-band-matches" <BANDS> "How many bands of the signatures a candidate shares with the query, more is faster but finds less")
+                        .value_parser(value_parser!(usize))
+                        .default_value("1"),
+                )
+                .arg(
+                    arg!(--verify "Also search all documents, and show how many of their results the approximate search found")
+                        .requires("approximate")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--"from-file" <LIST_FILE> "Also query the files listed in this file, one per line")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--"from-stdin" "Also query the files listed on standard input, one per line")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--interactive "Load the index once, and answer the requests on standard input with a line of JSON each")
+                        .conflicts_with_all(["from-file", "from-stdin", "FILE_TO_QUERY", "verify"])
+                        .action(ArgAction::SetTrue),
+                )
                 .arg(
                     Arg::new("FILE_TO_QUERY")
-                        .required(true)
-                        .help("The file to query in the index")
-                        .required(true)
+                        .help("The files, or directories with files, to query in the index")
+                        .required_unless_present_any(["from-file", "from-stdin", "interactive"])
                         .value_parser(value_parser!(PathBuf))
                         .action(ArgAction::Append)
                         .num_args(1..),
+                )
+                .arg(threads_argument()),
+        )
+        .subcommand(
+            Command::new("serve")
+                .about("Keep the index loaded and answer queries over HTTP with JSON")
+                .args(file_arguments())
+                .arg(
+                    arg!(--address <ADDRESS> "Where to listen, port 0 picks a free port")
+                        .default_value("127.0.0.1:8080"),
+                )
+                .arg(
+                    arg!(--root <DIRECTORY> "Allow querying the files below this directory by their path")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--workers <COUNT> "How many requests are answered at the same time")
+                        .value_parser(value_parser!(u64).range(1..))
+                        .default_value("4"),
+                )
+                .arg(
+                    arg!(--"reload-interval" <SECONDS> "How often to check whether the index changed, to load it again")
+                        .value_parser(value_parser!(u64).range(1..))
+                        .default_value("2"),
+                )
+                .arg(
+                    arg!(-n --number <RESULT_SIZE> "How many results to return, unless a request asks otherwise")
+                        .value_parser(value_parser!(usize))
+                        .default_value("5"),
+                )
+                .arg(metric_argument())
+                .args(known_arguments())
+                .arg(
+                    arg!(--approximate "Only score the documents that the signatures of the index pick as candidates")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--"min-band-matches" <BANDS> "How many bands of the signatures a candidate shares with the query, more is faster but finds less")
+                        .value_parser(value_parser!(usize))
+                        .default_value("1"),
+                )
+                .arg(threads_argument()),
+        )
+        .subcommand(
+            Command::new("cross-query")
+                .about("Find the best matches in the reference index for every document in the target index")
+                .arg(
+                    arg!(--reference <DATABASE_FILE> "The database of the reference index")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--"reference-state" <STATE_FILE> "The state of the reference index")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--target <DATABASE_FILE> "The database of the target index")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--"target-state" <STATE_FILE> "The state of the target index")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--weights <STATE_FILE> "Weigh the chunks by this collection [default: both collections merged]")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-n --number <RESULT_SIZE> "How many results to return")
+                        .value_parser(value_parser!(usize))
+                        .default_value("5"),
+                )
+                .arg(metric_argument())
+                .arg(
+                    arg!(-m --metadata "Show the metadata of the matching documents")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(threads_argument()),
+        )
+        .subcommand(
+            Command::new("compare")
+                .about("Compare two files directly, without an index")
+                .arg(
+                    arg!(-s --state <STATE_FILE> "Weigh the chunks by this collection, instead of all the same")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(metric_argument())
+                .arg(arg!(<FILE_A>).value_parser(value_parser!(PathBuf)))
+                .arg(arg!(<FILE_B>).value_parser(value_parser!(PathBuf))),
+        )
+        .subcommand(
+            Command::new("compare-dirs")
+                .about("Compare every file in one directory to every file in another, without an index")
+                .arg(
+                    arg!(-s --state <STATE_FILE> "Weigh the chunks by this collection, instead of all the same")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(metric_argument())
+                .arg(arg!(<DIRECTORY_A>).value_parser(value_parser!(PathBuf)))
+                .arg(arg!(<DIRECTORY_B>).value_parser(value_parser!(PathBuf))),
+        )
+        .subcommand(
+            Command::new("explain")
+                .about("Show which chunks and regions two files share, and how much they add to their similarity")
+                .arg(
+                    arg!(-s --state <STATE_FILE>)
+                        .value_parser(value_parser!(PathBuf))
+                        .default_value("state.json"),
+                )
+                .arg(
+                    arg!(-n --number <NUMBER> "How many chunks and regions to list")
+                        .value_parser(value_parser!(usize))
+                        .default_value("10"),
+                )
+                .arg(arg!(<FILE> "The queried file").value_parser(value_parser!(PathBuf)))
+                .arg(arg!(<MATCH> "The file it matched").value_parser(value_parser!(PathBuf))),
+        )
+        .subcommand(
+            Command::new("duplicates")
+                .about("List the groups of byte-identical files in the index")
+                .args(file_arguments()),
+        )
+        .subcommand(
+            Command::new("stop-chunks")
+                .about("Give no weight to the most frequent chunks, or the given ones, in an existing index")
+                .args(file_arguments())
+                .arg(
+                    arg!(--top <NUMBER> "How many of the most frequent chunks to add")
+                        .value_parser(value_parser!(usize))
+                        .default_value("10"),
+                )
+                .arg(
+                    arg!(--chunk <CHUNK> "Also add this chunk, in hexadecimal")
+                        .required(false)
+                        .value_parser(parse_chunk)
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    arg!(--"dry-run" "Only list the chunks, do not change the index")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("compact")
+                .about("Store the digests of an existing index in the compact form, and show how the results change")
+                .args(file_arguments())
+                .arg(
+                    arg!(--quantization <QUANTIZATION> "How many bits to keep of every weight")
+                        .value_parser(Quantization::NAMES)
+                        .default_value("u8"),
+                )
+                .arg(
+                    arg!(--sample <NUMBER> "How many documents to compare the results for")
+                        .value_parser(value_parser!(usize))
+                        .default_value("100"),
+                )
+                .arg(
+                    arg!(--"dry-run" "Only show the effect, do not change the index")
+                        .action(ArgAction::SetTrue),
                 ),
         )
-        .get_matches();
+        .subcommand(
+            Command::new("report")
+                .about("Show which files were indexed, skipped or failed")
+                .arg(
+                    arg!(-d --database <DATABASE_FILE>)
+                        .value_parser(value_parser!(PathBuf))
+                        .default_value("database.json"),
+                )
+                .arg(log_argument())
+                .arg(
+                    arg!(-a --all "Also list the files that were indexed")
+                        .action(ArgAction::SetTrue),
+                ),
+        );
+    let matches = apply_settings(command, &load_settings()?)?.get_matches();
 
-    let output_format = if matches.get_flag("binary") {
+    // A format on the command line wins over one from the settings.
+    let output_format = if matches.value_source("json") == Some(ValueSource::CommandLine) {
+        OutputFormat::Json
+    } else if matches.get_flag("binary") {
         OutputFormat::Binary
     } else {
         OutputFormat::Json
@@ -102,6 +764,9 @@ fn main() -> std::io::Result<()> {
     let quiet =
         matches.get_flag("quiet") || !console::user_attended() || !console::user_attended_stderr();
     let config = Configuration::new(output_format, quiet);
+    if let Some((_, subcommand_matches)) = matches.subcommand() {
+        set_threads(subcommand_matches)?;
+    }
 
     if let Some(subcommand_matches) = matches.subcommand_matches("index") {
         let paths: Vec<&PathBuf> = subcommand_matches
@@ -115,12 +780,128 @@ fn main() -> std::io::Result<()> {
             .get_one::<PathBuf>("database")
             .expect("database.json");
 
-        index_paths(paths.as_slice(), output_state_file, results_file, &config)?;
-    } else if let Some(query_subcommand_matches) = matches.subcommand_matches("query") {
-        let files: Vec<&PathBuf> = query_subcommand_matches
-            .get_many::<PathBuf>("FILE_TO_QUERY")
+        let report = index_paths(
+            paths.as_slice(),
+            output_state_file,
+            results_file,
+            &log_file(subcommand_matches),
+            &IndexOptions {
+                walker: walker_options(subcommand_matches),
+                source: subcommand_matches.get_one::<String>("source").cloned(),
+                known: known_options(subcommand_matches),
+                min_idf: subcommand_matches.get_one::<f64>("min-idf").copied(),
+                max_chunk_count: subcommand_matches
+                    .get_one::<usize>("max-chunk-count")
+                    .copied(),
+                digest_cap: digest_cap(subcommand_matches),
+                lsh: lsh_options(subcommand_matches),
+                digest_encoding: digest_encoding(subcommand_matches)?,
+                memory_budget: memory_budget(subcommand_matches),
+                shards: shard_options(subcommand_matches)?,
+            },
+            &config,
+        )?;
+        if !config.quiet {
+            report.print(false);
+        }
+    } else if let Some(blocks_subcommand_matches) = matches.subcommand_matches("index-blocks") {
+        let paths: Vec<&PathBuf> = blocks_subcommand_matches
+            .get_many::<PathBuf>("INPUT")
             .unwrap()
             .collect::<Vec<&PathBuf>>();
+        let report = index_blocks(
+            paths.as_slice(),
+            blocks_subcommand_matches
+                .get_one::<PathBuf>("state")
+                .unwrap(),
+            blocks_subcommand_matches
+                .get_one::<PathBuf>("database")
+                .unwrap(),
+            &log_file(blocks_subcommand_matches),
+            *blocks_subcommand_matches
+                .get_one::<usize>("block-size")
+                .unwrap(),
+            &IndexOptions {
+                source: blocks_subcommand_matches
+                    .get_one::<String>("source")
+                    .cloned(),
+                known: known_options(blocks_subcommand_matches),
+                min_idf: blocks_subcommand_matches.get_one::<f64>("min-idf").copied(),
+                max_chunk_count: blocks_subcommand_matches
+                    .get_one::<usize>("max-chunk-count")
+                    .copied(),
+                digest_cap: digest_cap(blocks_subcommand_matches),
+                lsh: lsh_options(blocks_subcommand_matches),
+                digest_encoding: digest_encoding(blocks_subcommand_matches)?,
+                memory_budget: memory_budget(blocks_subcommand_matches),
+                shards: shard_options(blocks_subcommand_matches)?,
+                ..Default::default()
+            },
+            &config,
+        )?;
+        if !config.quiet {
+            report.print(false);
+        }
+    } else if let Some(watch_subcommand_matches) = matches.subcommand_matches("watch") {
+        let seconds = |name: &str| {
+            Duration::from_secs(*watch_subcommand_matches.get_one::<u64>(name).unwrap())
+        };
+        let alert = match (
+            watch_subcommand_matches.get_one::<PathBuf>("alert-state"),
+            watch_subcommand_matches.get_one::<PathBuf>("alert-database"),
+        ) {
+            (Some(state), Some(database)) => Some(AlertOptions {
+                reference: IndexFiles {
+                    state: state.clone(),
+                    database: database.clone(),
+                },
+                threshold: *watch_subcommand_matches
+                    .get_one::<f64>("alert-threshold")
+                    .unwrap(),
+                query: QueryOptions {
+                    number_of_results: *watch_subcommand_matches
+                        .get_one::<usize>("number")
+                        .unwrap(),
+                    metric_kind: metric_kind(watch_subcommand_matches)?,
+                    ..Default::default()
+                },
+            }),
+            _ => None,
+        };
+        watch_directory(
+            watch_subcommand_matches
+                .get_one::<PathBuf>("DIRECTORY")
+                .unwrap(),
+            watch_subcommand_matches
+                .get_one::<PathBuf>("state")
+                .unwrap(),
+            watch_subcommand_matches
+                .get_one::<PathBuf>("database")
+                .unwrap(),
+            &WatchOptions {
+                index: IndexOptions {
+                    walker: walker_options(watch_subcommand_matches),
+                    source: watch_subcommand_matches
+                        .get_one::<String>("source")
+                        .cloned(),
+                    known: known_options(watch_subcommand_matches),
+                    min_idf: watch_subcommand_matches.get_one::<f64>("min-idf").copied(),
+                    max_chunk_count: watch_subcommand_matches
+                        .get_one::<usize>("max-chunk-count")
+                        .copied(),
+                    digest_cap: digest_cap(watch_subcommand_matches),
+                    lsh: lsh_options(watch_subcommand_matches),
+                    digest_encoding: digest_encoding(watch_subcommand_matches)?,
+                    memory_budget: None,
+                    shards: shard_options(watch_subcommand_matches)?,
+                },
+                settle: seconds("settle"),
+                persist_interval: seconds("persist-interval"),
+                alert,
+            },
+            &config,
+        )?;
+    } else if let Some(query_subcommand_matches) = matches.subcommand_matches("query") {
         let database_path = query_subcommand_matches
             .get_one::<PathBuf>("database")
             .unwrap();
@@ -130,13 +911,202 @@ fn main() -> std::io::Result<()> {
         let number_of_results = *query_subcommand_matches
             .get_one::<usize>("RESULT_SIZE")
             .unwrap_or(&(5_usize));
-        query_for_results(
-            state_path,
-            database_path,
-            &files,
+        let options = QueryOptions {
             number_of_results,
+            show_metadata: query_subcommand_matches.get_flag("metadata"),
+            metric_kind: if query_subcommand_matches.get_flag("containment") {
+                MetricKind::Containment
+            } else {
+                metric_kind(query_subcommand_matches)?
+            },
+            known: known_options(query_subcommand_matches),
+            approximate: if query_subcommand_matches.get_flag("approximate") {
+                Some(ApproximateOptions {
+                    min_band_matches: *query_subcommand_matches
+                        .get_one::<usize>("min-band-matches")
+                        .unwrap(),
+                    verify: query_subcommand_matches.get_flag("verify"),
+                })
+            } else {
+                None
+            },
+        };
+        if query_subcommand_matches.get_flag("interactive") {
+            interactive_query(state_path, database_path, &options, &config)?;
+        } else {
+            let paths: Vec<PathBuf> = query_subcommand_matches
+                .get_many::<PathBuf>("FILE_TO_QUERY")
+                .map(|values| values.cloned().collect())
+                .unwrap_or_default();
+            let files = collect_query_files(
+                &paths,
+                query_subcommand_matches.get_one::<PathBuf>("from-file"),
+                query_subcommand_matches.get_flag("from-stdin"),
+            )?;
+            query_for_results(state_path, database_path, &files, &options, &config)?;
+        }
+    } else if let Some(serve_subcommand_matches) = matches.subcommand_matches("serve") {
+        serve(
+            serve_subcommand_matches
+                .get_one::<PathBuf>("state")
+                .unwrap(),
+            serve_subcommand_matches
+                .get_one::<PathBuf>("database")
+                .unwrap(),
+            &ServeOptions {
+                address: serve_subcommand_matches
+                    .get_one::<String>("address")
+                    .unwrap()
+                    .clone(),
+                workers: *serve_subcommand_matches.get_one::<u64>("workers").unwrap() as usize,
+                reload_interval: Duration::from_secs(
+                    *serve_subcommand_matches
+                        .get_one::<u64>("reload-interval")
+                        .unwrap(),
+                ),
+                query: QueryOptions {
+                    number_of_results: *serve_subcommand_matches
+                        .get_one::<usize>("number")
+                        .unwrap(),
+                    show_metadata: false,
+                    metric_kind: metric_kind(serve_subcommand_matches)?,
+                    known: known_options(serve_subcommand_matches),
+                    approximate: if serve_subcommand_matches.get_flag("approximate") {
+                        Some(ApproximateOptions {
+                            min_band_matches: *serve_subcommand_matches
+                                .get_one::<usize>("min-band-matches")
+                                .unwrap(),
+                            verify: false,
+                        })
+                    } else {
+                        None
+                    },
+                },
+                root: serve_subcommand_matches.get_one::<PathBuf>("root").cloned(),
+            },
+            &config,
+        )?;
+    } else if let Some(cross_subcommand_matches) = matches.subcommand_matches("cross-query") {
+        let path = |name: &str| -> PathBuf {
+            cross_subcommand_matches
+                .get_one::<PathBuf>(name)
+                .unwrap()
+                .clone()
+        };
+        cross_query_for_results(
+            &IndexFiles {
+                state: path("reference-state"),
+                database: path("reference"),
+            },
+            &IndexFiles {
+                state: path("target-state"),
+                database: path("target"),
+            },
+            cross_subcommand_matches.get_one::<PathBuf>("weights"),
+            &QueryOptions {
+                number_of_results: *cross_subcommand_matches.get_one::<usize>("number").unwrap(),
+                show_metadata: cross_subcommand_matches.get_flag("metadata"),
+                metric_kind: metric_kind(cross_subcommand_matches)?,
+                known: None,
+                approximate: None,
+            },
+            &config,
+        )?;
+    } else if let Some(compare_subcommand_matches) = matches.subcommand_matches("compare") {
+        compare_for_results(
+            compare_subcommand_matches.get_one::<PathBuf>("state"),
+            compare_subcommand_matches
+                .get_one::<PathBuf>("FILE_A")
+                .unwrap()
+                .to_string_lossy()
+                .as_ref(),
+            compare_subcommand_matches
+                .get_one::<PathBuf>("FILE_B")
+                .unwrap()
+                .to_string_lossy()
+                .as_ref(),
+            metric_kind(compare_subcommand_matches)?,
+            &config,
+        )?;
+    } else if let Some(compare_dirs_subcommand_matches) = matches.subcommand_matches("compare-dirs")
+    {
+        compare_directories_for_results(
+            compare_dirs_subcommand_matches.get_one::<PathBuf>("state"),
+            compare_dirs_subcommand_matches
+                .get_one::<PathBuf>("DIRECTORY_A")
+                .unwrap(),
+            compare_dirs_subcommand_matches
+                .get_one::<PathBuf>("DIRECTORY_B")
+                .unwrap(),
+            metric_kind(compare_dirs_subcommand_matches)?,
+            &config,
+        )?;
+    } else if let Some(explain_subcommand_matches) = matches.subcommand_matches("explain") {
+        explain_match(
+            explain_subcommand_matches
+                .get_one::<PathBuf>("state")
+                .unwrap(),
+            explain_subcommand_matches
+                .get_one::<PathBuf>("FILE")
+                .unwrap()
+                .to_string_lossy()
+                .as_ref(),
+            explain_subcommand_matches
+                .get_one::<PathBuf>("MATCH")
+                .unwrap()
+                .to_string_lossy()
+                .as_ref(),
+            *explain_subcommand_matches
+                .get_one::<usize>("number")
+                .unwrap(),
+            &config,
+        )?;
+    } else if let Some(duplicates_subcommand_matches) = matches.subcommand_matches("duplicates") {
+        duplicates_for_results(
+            duplicates_subcommand_matches
+                .get_one::<PathBuf>("state")
+                .unwrap(),
+            duplicates_subcommand_matches
+                .get_one::<PathBuf>("database")
+                .unwrap(),
+            &config,
+        )?;
+    } else if let Some(stop_subcommand_matches) = matches.subcommand_matches("stop-chunks") {
+        let chunks: Vec<u64> = stop_subcommand_matches
+            .get_many::<u64>("chunk")
+            .map(|values| values.copied().collect())
+            .unwrap_or_default();
+        stop_chunks_for_results(
+            stop_subcommand_matches.get_one::<PathBuf>("state").unwrap(),
+            stop_subcommand_matches
+                .get_one::<PathBuf>("database")
+                .unwrap(),
+            &chunks,
+            *stop_subcommand_matches.get_one::<usize>("top").unwrap(),
+            stop_subcommand_matches.get_flag("dry-run"),
+            &config,
+        )?;
+    } else if let Some(compact_subcommand_matches) = matches.subcommand_matches("compact") {
+        compact_for_results(
+            compact_subcommand_matches
+                .get_one::<PathBuf>("state")
+                .unwrap(),
+            compact_subcommand_matches
+                .get_one::<PathBuf>("database")
+                .unwrap(),
+            compact_subcommand_matches
+                .get_one::<String>("quantization")
+                .unwrap()
+                .parse()?,
+            *compact_subcommand_matches
+                .get_one::<usize>("sample")
+                .unwrap(),
+            compact_subcommand_matches.get_flag("dry-run"),
             &config,
         )?;
+    } else if let Some(report_subcommand_matches) = matches.subcommand_matches("report") {
+        read_report(&log_file(report_subcommand_matches), &config)?
+            .print(report_subcommand_matches.get_flag("all"));
     }
     Ok(())
 }
@@ -19,19 +19,38 @@
 // SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 use assert_cmd::prelude::*;
+use predicates::prelude::*;
 use std::process::Command;
 use tempfile::tempdir;
 
+//
+// Runs fbhash without the settings of whoever runs the tests: only the
+// configuration file of the tests is read, and no FBHASH_ variables.
+//
+fn fbhash() -> Result<Command, assert_cmd::cargo::CargoError> {
+    let mut command = Command::cargo_bin("fbhash")?;
+    for (variable, _) in std::env::vars_os() {
+        if variable.to_string_lossy().starts_with("FBHASH_") {
+            command.env_remove(variable);
+        }
+    }
+    command.env(
+        "FBHASH_CONFIG",
+        concat!(env!("CARGO_MANIFEST_DIR"), "/tests/config.yaml"),
+    );
+    Ok(command)
+}
+
 #[test]
 fn test_testdata_integration() -> Result<(), Box<dyn std::error::Error>> {
     let dir = tempdir()?;
     let output_state_file = dir.path().join("output_state_file.json");
     let database_file = dir.path().join("database.json");
-    let paths = vec!["testdata"];
-    let files = vec!["testdata/testfile-yes.bin"];
+    let paths = ["testdata"];
+    let files = ["testdata/testfile-yes.bin"];
     let number_of_results = 5;
 
-    let mut index_command = Command::cargo_bin("fbhash")?;
+    let mut index_command = fbhash()?;
     index_command
         .arg("index")
         .arg("--state")
@@ -41,7 +60,7 @@ fn test_testdata_integration() -> Result<(), Box<dyn std::error::Error>> {
         .arg(paths[0]);
     index_command.assert().success();
 
-    let mut query_command = Command::cargo_bin("fbhash")?;
+    let mut query_command = fbhash()?;
     query_command
         .arg("query")
         .arg(format!("-n={}", number_of_results))
@@ -55,7 +74,7 @@ fn test_testdata_integration() -> Result<(), Box<dyn std::error::Error>> {
     query_command.assert().success().stdout(format!(
         "Similarities for {}\n\
 Results: 3\n\
-testdata/testfile-yes.bin => (0.9999999999999999) testdata/testfile-yes.bin\n\
+testdata/testfile-yes.bin => (0.9999999999999999) testdata/testfile-yes.bin [identical]\n\
 testdata/testfile-yes.bin => (0) testdata/testfile-zero.bin\n\
 testdata/testfile-yes.bin => (0) testdata/testfile-zero-length\n\n",
         files[0]
@@ -65,7 +84,7 @@ testdata/testfile-yes.bin => (0) testdata/testfile-zero-length\n\n",
     query_command.assert().success().stdout(format!(
         "Similarities for {}\n\
 Results: 3\n\
-testdata/testfile-yes.bin => (0.9999999999999999) testdata\\testfile-yes.bin\n\
+testdata/testfile-yes.bin => (0.9999999999999999) testdata\\testfile-yes.bin [identical]\n\
 testdata/testfile-yes.bin => (0) testdata\\testfile-zero.bin\n\
 testdata/testfile-yes.bin => (0) testdata\\testfile-zero-length\n\n",
         files[0]
@@ -80,11 +99,11 @@ fn test_testdata_integration_single_result() -> Result<(), Box<dyn std::error::E
     let dir = tempdir()?;
     let output_state_file = dir.path().join("output_state_file.json");
     let database_file = dir.path().join("database.json");
-    let paths = vec!["testdata"];
-    let files = vec!["testdata/testfile-yes.bin"];
+    let paths = ["testdata"];
+    let files = ["testdata/testfile-yes.bin"];
     let number_of_results = 1;
 
-    let mut index_command = Command::cargo_bin("fbhash")?;
+    let mut index_command = fbhash()?;
     index_command
         .arg("index")
         .arg("--state")
@@ -94,7 +113,7 @@ fn test_testdata_integration_single_result() -> Result<(), Box<dyn std::error::E
         .arg(paths[0]);
     index_command.assert().success();
 
-    let mut query_command = Command::cargo_bin("fbhash")?;
+    let mut query_command = fbhash()?;
     query_command
         .arg("query")
         .arg(format!("-n={}", number_of_results))
@@ -108,7 +127,7 @@ fn test_testdata_integration_single_result() -> Result<(), Box<dyn std::error::E
     query_command.assert().success().stdout(format!(
         "Similarities for {}\n\
 Results: {}\n\
-testdata/testfile-yes.bin => (0.9999999999999999) testdata/testfile-yes.bin\n\n",
+testdata/testfile-yes.bin => (0.9999999999999999) testdata/testfile-yes.bin [identical]\n\n",
         files[0], number_of_results
     ));
 
@@ -116,7 +135,7 @@ testdata/testfile-yes.bin => (0.9999999999999999) testdata/testfile-yes.bin\n\n"
     query_command.assert().success().stdout(format!(
         "Similarities for {}\n\
 Results: {}\n\
-testdata/testfile-yes.bin => (0.9999999999999999) testdata\\testfile-yes.bin\n\n",
+testdata/testfile-yes.bin => (0.9999999999999999) testdata\\testfile-yes.bin [identical]\n\n",
         files[0], number_of_results
     ));
 
@@ -130,11 +149,11 @@ fn test_testdata_integration_binary() -> Result<(), Box<dyn std::error::Error>>
     let dir = tempdir()?;
     let output_state_file = dir.path().join("output_state_file.bin");
     let database_file = dir.path().join("database.bin");
-    let paths = vec!["testdata"];
-    let files = vec!["testdata/testfile-yes.bin"];
+    let paths = ["testdata"];
+    let files = ["testdata/testfile-yes.bin"];
     let number_of_results = 5;
 
-    let mut index_command = Command::cargo_bin("fbhash")?;
+    let mut index_command = fbhash()?;
     index_command
         .arg("--binary")
         .arg("index")
@@ -144,7 +163,7 @@ fn test_testdata_integration_binary() -> Result<(), Box<dyn std::error::Error>>
         .arg(paths[0]);
     index_command.assert().success();
 
-    let mut query_command = Command::cargo_bin("fbhash")?;
+    let mut query_command = fbhash()?;
     query_command
         .arg("--binary")
         .arg("query")
@@ -159,7 +178,7 @@ fn test_testdata_integration_binary() -> Result<(), Box<dyn std::error::Error>>
     query_command.assert().success().stdout(format!(
         "Similarities for {}\n\
 Results: 3\n\
-testdata/testfile-yes.bin => (0.9999999999999999) testdata/testfile-yes.bin\n\
+testdata/testfile-yes.bin => (0.9999999999999999) testdata/testfile-yes.bin [identical]\n\
 testdata/testfile-yes.bin => (0) testdata/testfile-zero.bin\n\
 testdata/testfile-yes.bin => (0) testdata/testfile-zero-length\n\n",
         files[0]
@@ -169,7 +188,7 @@ testdata/testfile-yes.bin => (0) testdata/testfile-zero-length\n\n",
     query_command.assert().success().stdout(format!(
         "Similarities for {}\n\
 Results: 3\n\
-testdata/testfile-yes.bin => (0.9999999999999999) testdata\\testfile-yes.bin\n\
+testdata/testfile-yes.bin => (0.9999999999999999) testdata\\testfile-yes.bin [identical]\n\
 testdata/testfile-yes.bin => (0) testdata\\testfile-zero.bin\n\
 testdata/testfile-yes.bin => (0) testdata\\testfile-zero-length\n\n",
         files[0]
@@ -184,11 +203,11 @@ fn test_quiet_integration() -> Result<(), Box<dyn std::error::Error>> {
     let dir = tempdir()?;
     let output_state_file = dir.path().join("output_state_file.json");
     let database_file = dir.path().join("database.json");
-    let paths = vec!["testdata"];
-    let files = vec!["testdata/testfile-yes.bin"];
+    let paths = ["testdata"];
+    let files = ["testdata/testfile-yes.bin"];
     let number_of_results = 5;
 
-    let mut index_command = Command::cargo_bin("fbhash")?;
+    let mut index_command = fbhash()?;
     index_command
         .arg("--quiet")
         .arg("index")
@@ -199,7 +218,7 @@ fn test_quiet_integration() -> Result<(), Box<dyn std::error::Error>> {
         .arg(paths[0]);
     index_command.assert().success().stdout("");
 
-    let mut query_command = Command::cargo_bin("fbhash")?;
+    let mut query_command = fbhash()?;
     query_command
         .arg("--quiet")
         .arg("query")
@@ -214,7 +233,7 @@ fn test_quiet_integration() -> Result<(), Box<dyn std::error::Error>> {
     query_command.assert().success().stdout(format!(
         "Similarities for {}\n\
 Results: 3\n\
-testdata/testfile-yes.bin => (0.9999999999999999) testdata/testfile-yes.bin\n\
+testdata/testfile-yes.bin => (0.9999999999999999) testdata/testfile-yes.bin [identical]\n\
 testdata/testfile-yes.bin => (0) testdata/testfile-zero.bin\n\
 testdata/testfile-yes.bin => (0) testdata/testfile-zero-length\n\n",
         files[0]
@@ -224,7 +243,7 @@ testdata/testfile-yes.bin => (0) testdata/testfile-zero-length\n\n",
     query_command.assert().success().stdout(format!(
         "Similarities for {}\n\
 Results: 3\n\
-testdata/testfile-yes.bin => (0.9999999999999999) testdata\\testfile-yes.bin\n\
+testdata/testfile-yes.bin => (0.9999999999999999) testdata\\testfile-yes.bin [identical]\n\
 testdata/testfile-yes.bin => (0) testdata\\testfile-zero.bin\n\
 testdata/testfile-yes.bin => (0) testdata\\testfile-zero-length\n\n",
         files[0]
@@ -239,11 +258,11 @@ fn test_testdata_format_wrong() -> Result<(), Box<dyn std::error::Error>> {
     let dir = tempdir()?;
     let output_state_file = dir.path().join("output_state_file.bin");
     let database_file = dir.path().join("database.bin");
-    let paths = vec!["testdata"];
-    let files = vec!["testdata/testfile-yes.bin"];
+    let paths = ["testdata"];
+    let files = ["testdata/testfile-yes.bin"];
     let number_of_results = 5;
 
-    let mut index_command = Command::cargo_bin("fbhash")?;
+    let mut index_command = fbhash()?;
     index_command
         .arg("--binary")
         .arg("index")
@@ -254,7 +273,7 @@ fn test_testdata_format_wrong() -> Result<(), Box<dyn std::error::Error>> {
         .arg(paths[0]);
     index_command.assert().success();
 
-    let mut query_command = Command::cargo_bin("fbhash")?;
+    let mut query_command = fbhash()?;
     query_command
         .arg("query")
         .arg(format!("-n={}", number_of_results))
@@ -279,11 +298,11 @@ fn test_testdata_format_wrong_json_to_binary() -> Result<(), Box<dyn std::error:
     let dir = tempdir()?;
     let output_state_file = dir.path().join("output_state_file.bin");
     let database_file = dir.path().join("database.bin");
-    let paths = vec!["testdata"];
-    let files = vec!["testdata/testfile-yes.bin"];
+    let paths = ["testdata"];
+    let files = ["testdata/testfile-yes.bin"];
     let number_of_results = 5;
 
-    let mut index_command = Command::cargo_bin("fbhash")?;
+    let mut index_command = fbhash()?;
     index_command
         .arg("index")
         .arg("--state")
@@ -292,7 +311,7 @@ fn test_testdata_format_wrong_json_to_binary() -> Result<(), Box<dyn std::error:
         .arg(paths[0]);
     index_command.assert().success();
 
-    let mut query_command = Command::cargo_bin("fbhash")?;
+    let mut query_command = fbhash()?;
     query_command
         .arg("--binary")
         .arg("query")
@@ -307,13 +326,13 @@ fn test_testdata_format_wrong_json_to_binary() -> Result<(), Box<dyn std::error:
     query_command
         .assert()
         .failure()
-        .stderr("memory allocation of 2308757952953217893 bytes failed\n");
+        .stderr(predicate::str::contains("Invalid state"));
 
     #[cfg(target_os = "windows")]
     query_command
         .assert()
         .failure()
-        .stderr("memory allocation of 2308757952953217893 bytes failed\n");
+        .stderr(predicate::str::contains("Invalid state"));
 
     dir.close()?;
     Ok(())
@@ -326,11 +345,11 @@ fn test_testdata_wrong_combo() -> Result<(), Box<dyn std::error::Error>> {
     let second_output_state_file = dir.path().join("second_output_state_file.bin");
     let database_file = dir.path().join("database.bin");
     let second_database_file = dir.path().join("second_database.bin");
-    let paths = vec!["testdata"];
-    let files = vec!["testdata/testfile-yes.bin"];
+    let paths = ["testdata"];
+    let files = ["testdata/testfile-yes.bin"];
     let number_of_results = 5;
 
-    let mut index_command = Command::cargo_bin("fbhash")?;
+    let mut index_command = fbhash()?;
     index_command
         .arg("--binary")
         .arg("index")
@@ -346,7 +365,7 @@ fn test_testdata_wrong_combo() -> Result<(), Box<dyn std::error::Error>> {
         panic!("Cannot convert path {:?}", dir.path());
     }
 
-    let mut second_index_command = Command::cargo_bin("fbhash")?;
+    let mut second_index_command = fbhash()?;
     second_index_command
         .arg("--binary")
         .arg("index")
@@ -357,7 +376,7 @@ fn test_testdata_wrong_combo() -> Result<(), Box<dyn std::error::Error>> {
         .arg(temp_path_str.unwrap());
     second_index_command.assert().success();
 
-    let mut query_command = Command::cargo_bin("fbhash")?;
+    let mut query_command = fbhash()?;
     query_command
         .arg("--binary")
         .arg("query")
@@ -388,3 +407,1463 @@ fn test_testdata_wrong_combo() -> Result<(), Box<dyn std::error::Error>> {
     dir.close()?;
     Ok(())
 }
+
+#[test]
+fn test_index_report() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let output_state_file = dir.path().join("output_state_file.json");
+    let database_file = dir.path().join("database.json");
+    let paths = ["testdata"];
+
+    let mut index_command = fbhash()?;
+    index_command
+        .arg("index")
+        .arg("--state")
+        .arg(output_state_file.clone())
+        .arg("--database")
+        .arg(database_file.to_str().unwrap())
+        .arg("--min-size=1")
+        .arg(paths[0]);
+    index_command.assert().success();
+
+    let mut report_command = fbhash()?;
+    report_command
+        .arg("report")
+        .arg("--database")
+        .arg(database_file.clone());
+
+    #[cfg(not(target_os = "windows"))]
+    report_command.assert().success().stdout(
+        "Files seen: 3\n  \
+indexed: 2\n  \
+too small: 1\n\
+too small: testdata/testfile-zero-length\n",
+    );
+
+    #[cfg(target_os = "windows")]
+    report_command.assert().success().stdout(
+        "Files seen: 3\n  \
+indexed: 2\n  \
+too small: 1\n\
+too small: testdata\\testfile-zero-length\n",
+    );
+
+    dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn test_query_metadata() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let output_state_file = dir.path().join("output_state_file.json");
+    let database_file = dir.path().join("database.json");
+    let paths = ["testdata"];
+    let files = ["testdata/testfile-yes.bin"];
+
+    let mut index_command = fbhash()?;
+    index_command
+        .arg("index")
+        .arg("--state")
+        .arg(output_state_file.clone())
+        .arg("--database")
+        .arg(database_file.to_str().unwrap())
+        .arg("--source=case-42")
+        .arg(paths[0]);
+    index_command.assert().success();
+
+    let mut query_command = fbhash()?;
+    query_command
+        .arg("query")
+        .arg("-n=1")
+        .arg("--metadata")
+        .arg("--database")
+        .arg(database_file.clone())
+        .arg("--state")
+        .arg(output_state_file.clone())
+        .arg(files[0]);
+
+    query_command
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "    size: 512, chunks: 506, \
+sha256: 2d683c15749fb8b9734e214afb78e40b6d90214f92a2c96bf655635a67daf70d, ",
+        ))
+        .stdout(predicate::str::contains("source: case-42\n"));
+
+    dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn test_duplicates() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let evidence = dir.path().join("evidence");
+    std::fs::create_dir(&evidence)?;
+    std::fs::copy("testdata/testfile-yes.bin", evidence.join("a.bin"))?;
+    std::fs::copy("testdata/testfile-yes.bin", evidence.join("b.bin"))?;
+    std::fs::copy("testdata/testfile-zero.bin", evidence.join("c.bin"))?;
+    let output_state_file = dir.path().join("output_state_file.json");
+    let database_file = dir.path().join("database.json");
+
+    let mut index_command = fbhash()?;
+    index_command
+        .arg("index")
+        .arg("--state")
+        .arg(output_state_file.clone())
+        .arg("--database")
+        .arg(database_file.to_str().unwrap())
+        .arg(evidence.clone());
+    index_command.assert().success();
+
+    let mut duplicates_command = fbhash()?;
+    duplicates_command
+        .arg("duplicates")
+        .arg("--database")
+        .arg(database_file.clone())
+        .arg("--state")
+        .arg(output_state_file.clone());
+
+    duplicates_command.assert().success().stdout(format!(
+        "Groups of identical files: 1\n\
+2d683c15749fb8b9734e214afb78e40b6d90214f92a2c96bf655635a67daf70d\n    \
+{}\n    \
+{}\n",
+        evidence.join("a.bin").to_str().unwrap(),
+        evidence.join("b.bin").to_str().unwrap()
+    ));
+
+    dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn test_expand_archives() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let evidence = dir.path().join("evidence");
+    std::fs::create_dir(&evidence)?;
+    let archive = evidence.join("evidence.tar");
+    let mut builder = tar::Builder::new(std::fs::File::create(&archive)?);
+    builder.append_path_with_name("testdata/testfile-yes.bin", "data/yes.bin")?;
+    builder.append_path_with_name("testdata/testfile-zero.bin", "data/zero.bin")?;
+    builder.finish()?;
+    let output_state_file = dir.path().join("output_state_file.json");
+    let database_file = dir.path().join("database.json");
+
+    let mut index_command = fbhash()?;
+    index_command
+        .arg("index")
+        .arg("--state")
+        .arg(output_state_file.clone())
+        .arg("--database")
+        .arg(database_file.to_str().unwrap())
+        .arg("--expand-archives")
+        .arg(evidence.clone());
+    index_command.assert().success();
+
+    let member = format!("{}!/data/yes.bin", archive.to_str().unwrap());
+    let mut query_command = fbhash()?;
+    query_command
+        .arg("query")
+        .arg("-n=1")
+        .arg("--database")
+        .arg(database_file.clone())
+        .arg("--state")
+        .arg(output_state_file.clone())
+        .arg(&member);
+
+    query_command.assert().success().stdout(format!(
+        "Similarities for {}\n\
+Results: 1\n\
+{} => (0.9999999999999999) {} [identical]\n\n",
+        member, member, member
+    ));
+
+    dir.close()?;
+    Ok(())
+}
+
+#[test]
+#[cfg(not(target_os = "windows"))]
+fn test_index_blocks() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    // A known file at a block boundary, surrounded by other data.
+    let image = dir.path().join("disk.img");
+    let mut content: Vec<u8> = (0..512_u32).map(|i| (i * 7 % 251) as u8).collect();
+    content.extend(std::fs::read("testdata/testfile-yes.bin")?);
+    content.extend(std::fs::read("testdata/testfile-zero.bin")?);
+    std::fs::write(&image, &content)?;
+    let output_state_file = dir.path().join("output_state_file.json");
+    let database_file = dir.path().join("database.json");
+
+    let mut index_command = fbhash()?;
+    index_command
+        .arg("index-blocks")
+        .arg("--state")
+        .arg(output_state_file.clone())
+        .arg("--database")
+        .arg(database_file.to_str().unwrap())
+        .arg("--block-size=512")
+        .arg(image.clone());
+    index_command.assert().success();
+
+    let mut query_command = fbhash()?;
+    query_command
+        .arg("query")
+        .arg("-n=1")
+        .arg("--database")
+        .arg(database_file.clone())
+        .arg("--state")
+        .arg(output_state_file.clone())
+        .arg("testdata/testfile-yes.bin");
+
+    query_command
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "{}@512 [identical]",
+            image.to_str().unwrap()
+        )));
+
+    dir.close()?;
+    Ok(())
+}
+
+#[test]
+#[cfg(not(target_os = "windows"))]
+fn test_query_containment() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let evidence = dir.path().join("evidence");
+    std::fs::create_dir(&evidence)?;
+    // A large file that has a known file somewhere in the middle.
+    let large = evidence.join("large.bin");
+    let mut content: Vec<u8> = (0..4096_u32).map(|i| (i * 7 % 251) as u8).collect();
+    content.extend(std::fs::read("testdata/testfile-yes.bin")?);
+    content.extend((0..8192_u32).map(|i| (i * 13 % 241) as u8));
+    std::fs::write(&large, &content)?;
+    let output_state_file = dir.path().join("output_state_file.json");
+    let database_file = dir.path().join("database.json");
+
+    let mut index_command = fbhash()?;
+    index_command
+        .arg("index")
+        .arg("--state")
+        .arg(output_state_file.clone())
+        .arg("--database")
+        .arg(database_file.to_str().unwrap())
+        .arg(evidence.clone());
+    index_command.assert().success();
+
+    let mut query_command = fbhash()?;
+    query_command
+        .arg("query")
+        .arg("-n=1")
+        .arg("--containment")
+        .arg("--database")
+        .arg(database_file.clone())
+        .arg("--state")
+        .arg(output_state_file.clone())
+        .arg("testdata/testfile-yes.bin");
+
+    query_command
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "testdata/testfile-yes.bin => (1) {} (cosine: ",
+            large.to_str().unwrap()
+        )));
+
+    dir.close()?;
+    Ok(())
+}
+
+#[test]
+#[cfg(not(target_os = "windows"))]
+fn test_query_metric() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let output_state_file = dir.path().join("output_state_file.json");
+    let database_file = dir.path().join("database.json");
+
+    let mut index_command = fbhash()?;
+    index_command
+        .arg("index")
+        .arg("--state")
+        .arg(output_state_file.clone())
+        .arg("--database")
+        .arg(database_file.to_str().unwrap())
+        .arg("testdata");
+    index_command.assert().success();
+
+    let mut query_command = fbhash()?;
+    query_command
+        .arg("query")
+        .arg("-n=1")
+        .arg("--metric=jaccard")
+        .arg("--database")
+        .arg(database_file.clone())
+        .arg("--state")
+        .arg(output_state_file.clone())
+        .arg("testdata/testfile-yes.bin");
+
+    query_command.assert().success().stdout(
+        "Similarities for testdata/testfile-yes.bin\n\
+Results: 1\n\
+testdata/testfile-yes.bin => (1) testdata/testfile-yes.bin (cosine: 0.9999999999999999) [identical]\n\n",
+    );
+
+    let mut unknown_command = fbhash()?;
+    unknown_command
+        .arg("query")
+        .arg("--metric=euclid")
+        .arg("testdata/testfile-yes.bin");
+    unknown_command.assert().failure();
+
+    dir.close()?;
+    Ok(())
+}
+
+#[test]
+#[cfg(not(target_os = "windows"))]
+fn test_explain() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let evidence = dir.path().join("evidence");
+    std::fs::create_dir(&evidence)?;
+    let large = evidence.join("large.bin");
+    let mut content: Vec<u8> = (0..4096_u32).map(|i| (i * 7 % 251) as u8).collect();
+    content.extend(std::fs::read("testdata/testfile-yes.bin")?);
+    std::fs::write(&large, &content)?;
+    let output_state_file = dir.path().join("output_state_file.json");
+    let database_file = dir.path().join("database.json");
+
+    let mut index_command = fbhash()?;
+    index_command
+        .arg("index")
+        .arg("--state")
+        .arg(output_state_file.clone())
+        .arg("--database")
+        .arg(database_file.to_str().unwrap())
+        .arg(evidence.clone());
+    index_command.assert().success();
+
+    let mut explain_command = fbhash()?;
+    explain_command
+        .arg("explain")
+        .arg("--state")
+        .arg(output_state_file.clone())
+        .arg("testdata/testfile-yes.bin")
+        .arg(large.clone());
+
+    explain_command
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "Shared chunks: 2 (of 506 chunks in",
+        ))
+        .stdout(predicate::str::contains(
+            "Regions: 1\n  0..512 <=> 4096..4608 (512 bytes, ",
+        ));
+
+    dir.close()?;
+    Ok(())
+}
+
+#[test]
+#[cfg(not(target_os = "windows"))]
+fn test_compare() -> Result<(), Box<dyn std::error::Error>> {
+    let mut compare_command = fbhash()?;
+    compare_command
+        .arg("compare")
+        .arg("testdata/testfile-yes.bin")
+        .arg("testdata/testfile-zero.bin");
+    compare_command
+        .assert()
+        .success()
+        .stdout("testdata/testfile-yes.bin => (0) testdata/testfile-zero.bin\n");
+
+    let mut compare_dirs_command = fbhash()?;
+    compare_dirs_command
+        .arg("compare-dirs")
+        .arg("--metric=jaccard")
+        .arg("testdata")
+        .arg("testdata");
+    compare_dirs_command.assert().success().stdout(
+        "\ttestdata/testfile-yes.bin\ttestdata/testfile-zero-length\ttestdata/testfile-zero.bin\n\
+testdata/testfile-yes.bin\t1\t0\t0\n\
+testdata/testfile-zero-length\t0\t1\t0.11128617741227337\n\
+testdata/testfile-zero.bin\t0\t0.11128617741227337\t1\n",
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(not(target_os = "windows"))]
+fn test_batch_query() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let output_state_file = dir.path().join("output_state_file.json");
+    let database_file = dir.path().join("database.json");
+
+    let mut index_command = fbhash()?;
+    index_command
+        .arg("index")
+        .arg("--state")
+        .arg(output_state_file.clone())
+        .arg("--database")
+        .arg(database_file.to_str().unwrap())
+        .arg("testdata");
+    index_command.assert().success();
+
+    let mut query_command = assert_cmd::Command::from_std(fbhash()?);
+    query_command
+        .arg("query")
+        .arg("-n=1")
+        .arg("--database")
+        .arg(database_file.clone())
+        .arg("--state")
+        .arg(output_state_file.clone())
+        .arg("--from-stdin")
+        .write_stdin("testdata/testfile-yes.bin\ntestdata/testfile-zero.bin\n");
+
+    query_command
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "Similarities for testdata/testfile-yes.bin\n\
+Results: 1\n\
+testdata/testfile-yes.bin => (0.9999999999999999) testdata/testfile-yes.bin [identical]\n\n",
+        ))
+        .stdout(predicate::str::contains(
+            "Similarities for testdata/testfile-zero.bin\n",
+        ));
+
+    // A query that fails does not stop the others.
+    let mut failing_command = fbhash()?;
+    failing_command
+        .arg("query")
+        .arg("-n=1")
+        .arg("--database")
+        .arg(database_file.clone())
+        .arg("--state")
+        .arg(output_state_file.clone())
+        .arg("testdata/missing.bin")
+        .arg("testdata/testfile-yes.bin");
+    failing_command
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "Similarities for testdata/testfile-yes.bin\n",
+        ))
+        .stderr(predicate::str::contains("testdata/missing.bin: "));
+
+    dir.close()?;
+    Ok(())
+}
+
+#[test]
+#[cfg(not(target_os = "windows"))]
+fn test_cross_query() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let target = dir.path().join("target");
+    std::fs::create_dir(&target)?;
+    std::fs::copy("testdata/testfile-yes.bin", target.join("copy.bin"))?;
+    std::fs::copy("testdata/testfile-zero.bin", target.join("another.bin"))?;
+    let reference_state = dir.path().join("reference_state.json");
+    let reference_database = dir.path().join("reference.json");
+    let target_state = dir.path().join("target_state.json");
+    let target_database = dir.path().join("target.json");
+
+    for (state, database, path) in [
+        (
+            &reference_state,
+            &reference_database,
+            std::path::Path::new("testdata"),
+        ),
+        (&target_state, &target_database, target.as_path()),
+    ] {
+        let mut index_command = fbhash()?;
+        index_command
+            .arg("index")
+            .arg("--state")
+            .arg(state)
+            .arg("--database")
+            .arg(database)
+            .arg(path);
+        index_command.assert().success();
+    }
+
+    let mut cross_command = fbhash()?;
+    cross_command
+        .arg("cross-query")
+        .arg("-n=1")
+        .arg("--reference")
+        .arg(&reference_database)
+        .arg("--reference-state")
+        .arg(&reference_state)
+        .arg("--target")
+        .arg(&target_database)
+        .arg("--target-state")
+        .arg(&target_state);
+
+    // The target documents come out in order.
+    let another = target.join("another.bin");
+    let copy = target.join("copy.bin");
+    cross_command.assert().success().stdout(format!(
+        "Similarities for {}\n\
+Results: 1\n\
+{} => (1) testdata/testfile-zero.bin [identical]\n\n\
+Similarities for {}\n\
+Results: 1\n\
+{} => (1) testdata/testfile-yes.bin [identical]\n\n",
+        another.to_str().unwrap(),
+        another.to_str().unwrap(),
+        copy.to_str().unwrap(),
+        copy.to_str().unwrap()
+    ));
+
+    dir.close()?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+#[test]
+fn test_known_files() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let known = dir.path().join("known");
+    std::fs::create_dir(&known)?;
+    std::fs::copy("testdata/testfile-yes.bin", known.join("copy.bin"))?;
+    let known_state = dir.path().join("known_state.json");
+    let known_database = dir.path().join("known.json");
+    let state = dir.path().join("state.json");
+    let database = dir.path().join("database.json");
+
+    let mut known_command = fbhash()?;
+    known_command
+        .arg("index")
+        .arg("--state")
+        .arg(&known_state)
+        .arg("--database")
+        .arg(&known_database)
+        .arg(&known);
+    known_command.assert().success();
+
+    let mut index_command = fbhash()?;
+    index_command
+        .arg("index")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("--known")
+        .arg(&known_database)
+        .arg("--known-state")
+        .arg(&known_state)
+        .arg("testdata");
+    index_command.assert().success();
+
+    let mut report_command = fbhash()?;
+    report_command
+        .arg("report")
+        .arg("--database")
+        .arg(&database);
+    report_command
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("  known file: 1\n"))
+        .stdout(predicate::str::contains(format!(
+            "known file: testdata/testfile-yes.bin (matches {}",
+            known.join("copy.bin").to_str().unwrap()
+        )));
+
+    let mut query_command = fbhash()?;
+    query_command
+        .arg("query")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("testdata/testfile-yes.bin");
+    query_command
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("testdata/testfile-yes.bin [identical]").not());
+
+    let mut suppress_command = fbhash()?;
+    suppress_command
+        .arg("index")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("--known")
+        .arg(&known_database)
+        .arg("--known-state")
+        .arg(&known_state)
+        .arg("--suppress-known")
+        .arg("testdata");
+    suppress_command.assert().success();
+    let state_contents = std::fs::read_to_string(&state)?;
+    assert!(state_contents.contains("\"stop_chunks\": [\n    2879926931474365,"));
+
+    let mut plain_command = fbhash()?;
+    plain_command
+        .arg("index")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("testdata");
+    plain_command.assert().success();
+    let mut known_query_command = fbhash()?;
+    known_query_command
+        .arg("query")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("--known")
+        .arg(&known_database)
+        .arg("--known-state")
+        .arg(&known_state)
+        .arg("testdata/testfile-yes.bin");
+    known_query_command
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Results: 2\n"))
+        .stdout(predicate::str::contains("testdata/testfile-yes.bin [identical]").not());
+
+    dir.close()?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+#[test]
+fn test_stop_chunks() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let state = dir.path().join("state.json");
+    let database = dir.path().join("database.json");
+    let zero_digest = "\"file\":\"testdata/testfile-zero.bin\",\"chunks\":[],\"digest\":[]";
+
+    let mut index_command = fbhash()?;
+    index_command
+        .arg("index")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("testdata");
+    index_command.assert().success();
+    assert!(!std::fs::read_to_string(&database)?.contains(zero_digest));
+
+    let mut dry_run_command = fbhash()?;
+    dry_run_command
+        .arg("stop-chunks")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("--top=1")
+        .arg("--dry-run");
+    dry_run_command
+        .assert()
+        .success()
+        .stdout("Stop chunks to add: 1\n  0000000000000000 (507 times)\n");
+    assert!(!std::fs::read_to_string(&database)?.contains(zero_digest));
+
+    let mut stop_command = fbhash()?;
+    stop_command
+        .arg("stop-chunks")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("--top=0")
+        .arg("--chunk=0");
+    stop_command
+        .assert()
+        .success()
+        .stdout("Stop chunks added: 1\n  0000000000000000 (507 times)\n");
+    assert!(std::fs::read_to_string(&database)?.contains(zero_digest));
+    assert!(std::fs::read_to_string(&state)?.contains("\"stop_chunks\": [\n    0\n  ]"));
+
+    let mut cutoff_command = fbhash()?;
+    cutoff_command
+        .arg("index")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("--max-chunk-count=300")
+        .arg("testdata");
+    cutoff_command.assert().success();
+    assert!(std::fs::read_to_string(&database)?.contains(zero_digest));
+
+    dir.close()?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+#[test]
+fn test_digest_cap() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let state = dir.path().join("state.json");
+    let database = dir.path().join("database.json");
+
+    let mut index_command = fbhash()?;
+    index_command
+        .arg("index")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("--digest-top=1")
+        .arg("testdata");
+    index_command.assert().success();
+    assert!(std::fs::read_to_string(&state)?.contains("\"digest_cap\": {\n    \"TopK\": 1\n  }"));
+    for line in std::fs::read_to_string(&database)?.lines() {
+        assert!(!line.contains("],["), "{}", line);
+    }
+
+    let mut query_command = fbhash()?;
+    query_command
+        .arg("query")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("-n=1")
+        .arg("testdata/testfile-yes.bin");
+    query_command.assert().success().stdout(
+        "Similarities for testdata/testfile-yes.bin\n\
+Results: 1\n\
+testdata/testfile-yes.bin => (1) testdata/testfile-yes.bin [identical]\n\n",
+    );
+
+    let mut wrong_command = fbhash()?;
+    wrong_command
+        .arg("index")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("--digest-fraction=2")
+        .arg("testdata");
+    wrong_command.assert().failure();
+
+    dir.close()?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+#[test]
+fn test_approximate_query() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let state = dir.path().join("state.json");
+    let database = dir.path().join("database.json");
+
+    let mut index_command = fbhash()?;
+    index_command
+        .arg("index")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("--lsh-bands=8")
+        .arg("--lsh-rows=2")
+        .arg("testdata");
+    index_command.assert().success();
+    assert!(dir.path().join("database.json.lsh").exists());
+
+    let mut query_command = fbhash()?;
+    query_command
+        .arg("query")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("-n=2")
+        .arg("--approximate")
+        .arg("--verify")
+        .arg("testdata/testfile-yes.bin");
+    query_command.assert().success().stdout(
+        "Similarities for testdata/testfile-yes.bin\n\
+Results: 1\n\
+testdata/testfile-yes.bin => (0.9999999999999999) testdata/testfile-yes.bin [identical]\n\
+Recall against the exact search: 1/2\n\n",
+    );
+
+    // Indexing again without signatures removes the old ones.
+    let mut plain_command = fbhash()?;
+    plain_command
+        .arg("index")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("testdata");
+    plain_command.assert().success();
+    assert!(!dir.path().join("database.json.lsh").exists());
+
+    let mut missing_command = fbhash()?;
+    missing_command
+        .arg("query")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("--approximate")
+        .arg("testdata/testfile-yes.bin");
+    missing_command
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("index with --lsh-bands"));
+
+    dir.close()?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+#[test]
+fn test_compact_digests() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let state = dir.path().join("state.json");
+    let database = dir.path().join("database.json");
+
+    let mut index_command = fbhash()?;
+    index_command
+        .arg("index")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("--compact=u8")
+        .arg("testdata");
+    index_command.assert().success();
+    assert!(std::fs::read_to_string(&state)?.contains("\"digest_encoding\": \"U8\""));
+    assert!(std::fs::read_to_string(&database)?.contains("\"quantization\":\"U8\""));
+
+    let mut query_command = fbhash()?;
+    query_command
+        .arg("query")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("-n=1")
+        .arg("testdata/testfile-yes.bin");
+    query_command.assert().success().stdout(
+        "Similarities for testdata/testfile-yes.bin\n\
+Results: 1\n\
+testdata/testfile-yes.bin => (0.9999999999999999) testdata/testfile-yes.bin [identical]\n\n",
+    );
+
+    let mut compact_command = fbhash()?;
+    compact_command
+        .arg("compact")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("--quantization=u16");
+    compact_command
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("Quantization: u16\n"))
+        .stdout(predicate::str::contains(
+            "Agreement of the top 5 results: 1 (over 3 documents)\n",
+        ));
+    assert!(std::fs::read_to_string(&database)?.contains("\"quantization\":\"U16\""));
+
+    dir.close()?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+#[test]
+fn test_memory_budget() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let mut outputs = Vec::new();
+    for budget in [None, Some("--memory-budget=0")] {
+        let state = dir.path().join("state.bin");
+        let database = dir.path().join("database.bin");
+
+        let mut index_command = fbhash()?;
+        index_command
+            .arg("--binary")
+            .arg("index")
+            .arg("--state")
+            .arg(&state)
+            .arg("--database")
+            .arg(&database)
+            .args(budget)
+            .arg("testdata");
+        index_command.assert().success();
+
+        let mut query_command = fbhash()?;
+        query_command
+            .arg("--binary")
+            .arg("query")
+            .arg("--state")
+            .arg(&state)
+            .arg("--database")
+            .arg(&database)
+            .arg("testdata/testfile-yes.bin")
+            .arg("testdata/testfile-zero.bin");
+        outputs.push(query_command.assert().success().get_output().stdout.clone());
+    }
+    assert_eq!(outputs[0], outputs[1]);
+    assert!(String::from_utf8(outputs[1].clone())?
+        .contains("testdata/testfile-yes.bin => (0.9999999999999999) testdata/testfile-yes.bin"));
+
+    dir.close()?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+#[test]
+fn test_sharded_database() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let state = dir.path().join("state.json");
+    let database = dir.path().join("database.json");
+
+    let mut index_command = fbhash()?;
+    index_command
+        .arg("index")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("--shards=3")
+        .arg("--lsh-bands=8")
+        .arg("testdata");
+    index_command.assert().success();
+    assert!(!database.exists());
+    for shard in 0..3 {
+        assert!(dir
+            .path()
+            .join(format!("database.json.shard-{}", shard))
+            .exists());
+        assert!(dir
+            .path()
+            .join(format!("database.json.shard-{}.lsh", shard))
+            .exists());
+    }
+
+    let mut query_command = fbhash()?;
+    query_command
+        .arg("query")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("testdata/testfile-yes.bin");
+    query_command.assert().success().stdout(
+        "Similarities for testdata/testfile-yes.bin\n\
+Results: 3\n\
+testdata/testfile-yes.bin => (0.9999999999999999) testdata/testfile-yes.bin [identical]\n\
+testdata/testfile-yes.bin => (0) testdata/testfile-zero.bin\n\
+testdata/testfile-yes.bin => (0) testdata/testfile-zero-length\n\n",
+    );
+
+    let mut approximate_command = fbhash()?;
+    approximate_command
+        .arg("query")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("--approximate")
+        .arg("-n=1")
+        .arg("testdata/testfile-yes.bin");
+    approximate_command
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+        "testdata/testfile-yes.bin => (0.9999999999999999) testdata/testfile-yes.bin [identical]\n",
+    ));
+
+    // Without shards, the shards of the earlier index are removed.
+    let mut reindex_command = fbhash()?;
+    reindex_command
+        .arg("index")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("testdata");
+    reindex_command.assert().success();
+    assert!(database.exists());
+    assert!(!dir.path().join("database.json.shard-0").exists());
+
+    dir.close()?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+#[test]
+fn test_threads() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let state = dir.path().join("state.json");
+    let database = dir.path().join("database.json");
+
+    let mut index_command = fbhash()?;
+    index_command
+        .arg("index")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("--threads=2")
+        .arg("testdata");
+    index_command.assert().success();
+
+    let mut query_command = fbhash()?;
+    query_command
+        .arg("query")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("--threads=1")
+        .arg("testdata/testfile-yes.bin");
+    query_command.assert().success().stdout(
+        "Similarities for testdata/testfile-yes.bin\n\
+Results: 3\n\
+testdata/testfile-yes.bin => (0.9999999999999999) testdata/testfile-yes.bin [identical]\n\
+testdata/testfile-yes.bin => (0) testdata/testfile-zero.bin\n\
+testdata/testfile-yes.bin => (0) testdata/testfile-zero-length\n\n",
+    );
+
+    dir.close()?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+fn http_request(
+    address: &str,
+    request_line: &str,
+    body: &[u8],
+) -> Result<String, Box<dyn std::error::Error>> {
+    use std::io::{Read, Write};
+
+    let mut stream = std::net::TcpStream::connect(address)?;
+    write!(
+        stream,
+        "{}\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        request_line,
+        address,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+#[cfg(not(target_os = "windows"))]
+#[test]
+fn test_serve() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::BufRead;
+
+    let dir = tempdir()?;
+    let state = dir.path().join("state.json");
+    let database = dir.path().join("database.json");
+
+    let mut index_command = fbhash()?;
+    index_command
+        .arg("index")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("testdata");
+    index_command.assert().success();
+
+    let mut server = fbhash()?
+        .arg("serve")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("--address=127.0.0.1:0")
+        .arg("--reload-interval=1")
+        .arg("--root")
+        .arg(std::env::current_dir()?.join("testdata"))
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+    let mut output = std::io::BufReader::new(server.stdout.take().unwrap());
+    let mut first_line = String::new();
+    output.read_line(&mut first_line)?;
+    let address = first_line
+        .trim()
+        .trim_start_matches("Listening on http://")
+        .to_string();
+
+    let health = http_request(&address, "GET /health HTTP/1.1", b"")?;
+    let by_path = http_request(
+        &address,
+        "GET /query?path=testfile-yes.bin&n=1 HTTP/1.1",
+        b"",
+    )?;
+    let by_upload = http_request(
+        &address,
+        "POST /query?name=upload HTTP/1.1",
+        &std::fs::read("testdata/testfile-yes.bin")?,
+    )?;
+    let missing = http_request(&address, "GET /query?path=missing HTTP/1.1", b"")?;
+    let outside = http_request(&address, "GET /query?path=..%2FCargo.toml HTTP/1.1", b"")?;
+    let unknown = http_request(&address, "GET /unknown HTTP/1.1", b"")?;
+
+    let mut reindex_command = fbhash()?;
+    reindex_command
+        .arg("index")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("testdata/testfile-yes.bin");
+    reindex_command.assert().success();
+    let mut stats = String::new();
+    for _ in 0..20 {
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        stats = http_request(&address, "GET /stats HTTP/1.1", b"")?;
+        if stats.contains("\"version\":1") {
+            break;
+        }
+    }
+    server.kill()?;
+    server.wait()?;
+
+    assert!(health.contains("200 OK"));
+    assert!(health.ends_with("{\"status\":\"ok\"}"));
+    assert!(by_path.contains(
+        "{\"query\":\"testfile-yes.bin\",\"results\":[{\"file\":\"testdata/testfile-yes.bin\",\"score\":0.9999999999999999,\"identical\":true}]}"
+    ));
+    assert!(by_upload
+        .contains("{\"query\":\"upload\",\"results\":[{\"file\":\"testdata/testfile-yes.bin\""));
+    assert!(by_upload.contains("\"identical\":true"));
+    assert!(missing.contains("404 Not Found"));
+    assert!(outside.contains("404 Not Found"));
+    assert!(unknown.contains("404 Not Found"));
+    assert!(stats.contains("\"documents\":1"));
+    assert!(stats.contains("\"version\":1"));
+
+    dir.close()?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+#[test]
+fn test_interactive_query() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let state = dir.path().join("state.json");
+    let database = dir.path().join("database.json");
+
+    let mut index_command = fbhash()?;
+    index_command
+        .arg("index")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("testdata");
+    index_command.assert().success();
+
+    let mut query_command = assert_cmd::Command::from_std(fbhash()?);
+    query_command
+        .arg("query")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("--interactive")
+        .write_stdin(
+            "testdata/testfile-yes.bin\n\
+{\"data\":\"eWVz\",\"name\":\"upload\",\"n\":1}\n\
+{\"path\":\"testdata/missing\"}\n\
+{\"path\":\"testdata/testfile-yes.bin\",\"n\":1,\"metadata\":false}\n",
+        );
+    query_command.assert().success().stdout(
+        "{\"query\":\"testdata/testfile-yes.bin\",\"results\":[\
+{\"file\":\"testdata/testfile-yes.bin\",\"score\":0.9999999999999999,\"identical\":true},\
+{\"file\":\"testdata/testfile-zero.bin\",\"score\":0.0,\"identical\":false},\
+{\"file\":\"testdata/testfile-zero-length\",\"score\":0.0,\"identical\":false}]}\n\
+{\"query\":\"upload\",\"results\":[{\"file\":\"testdata/testfile-zero.bin\",\"score\":0.0,\"identical\":false}]}\n\
+{\"error\":\"testdata/missing: No such file or directory (os error 2)\"}\n\
+{\"query\":\"testdata/testfile-yes.bin\",\"results\":[\
+{\"file\":\"testdata/testfile-yes.bin\",\"score\":0.9999999999999999,\"identical\":true}]}\n",
+    );
+
+    dir.close()?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+#[test]
+fn test_watch() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let reference_state = dir.path().join("reference-state.json");
+    let reference_database = dir.path().join("reference-database.json");
+    let drop_folder = dir.path().join("drop");
+    let state = dir.path().join("state.json");
+    let database = dir.path().join("database.json");
+    std::fs::create_dir(&drop_folder)?;
+    std::fs::copy(
+        "testdata/testfile-zero.bin",
+        drop_folder.join("testfile-zero.bin"),
+    )?;
+
+    let mut index_command = fbhash()?;
+    index_command
+        .arg("index")
+        .arg("--state")
+        .arg(&reference_state)
+        .arg("--database")
+        .arg(&reference_database)
+        .arg("testdata/testfile-yes.bin");
+    index_command.assert().success();
+
+    let mut watcher = fbhash()?
+        .arg("watch")
+        .arg("--state")
+        .arg(&state)
+        .arg("--database")
+        .arg(&database)
+        .arg("--settle=1")
+        .arg("--persist-interval=0")
+        .arg("--alert-state")
+        .arg(&reference_state)
+        .arg("--alert-database")
+        .arg(&reference_database)
+        .arg(&drop_folder)
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+    let wait_for = |text: &str| -> bool {
+        for _ in 0..40 {
+            std::thread::sleep(std::time::Duration::from_millis(250));
+            if std::fs::read_to_string(&database).is_ok_and(|contents| contents.contains(text)) {
+                return true;
+            }
+        }
+        false
+    };
+    let found_first = wait_for("testfile-zero.bin");
+    std::fs::copy(
+        "testdata/testfile-yes.bin",
+        drop_folder.join("testfile-yes.bin"),
+    )?;
+    let found_second = wait_for("testfile-yes.bin");
+    std::fs::remove_file(drop_folder.join("testfile-zero.bin"))?;
+    let mut removed = false;
+    for _ in 0..40 {
+        std::thread::sleep(std::time::Duration::from_millis(250));
+        if !std::fs::read_to_string(&database)?.contains("testfile-zero.bin") {
+            removed = true;
+            break;
+        }
+    }
+    // The state of the watch is written after the database.
+    let watch_state = dir.path().join("database.json.watch");
+    let mut has_header = false;
+    for _ in 0..40 {
+        if std::fs::read(&watch_state).is_ok_and(|contents| contents.starts_with(b"FBHASH\x01\0")) {
+            has_header = true;
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(250));
+    }
+    watcher.kill()?;
+    let output = watcher.wait_with_output()?;
+    let alerts = String::from_utf8(output.stdout)?;
+
+    assert!(found_first);
+    assert!(found_second);
+    assert!(removed);
+    assert!(state.exists());
+    assert!(has_header);
+    assert!(
+        alerts.contains("/testfile-yes.bin\",\"results\":[{\"file\":\"testdata/testfile-yes.bin\"")
+    );
+    assert!(!alerts.contains("/testfile-zero.bin\""));
+
+    dir.close()?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+#[test]
+fn test_settings() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let project = dir.path().join("project");
+    let nested = project.join("nested");
+    std::fs::create_dir_all(&nested)?;
+    std::fs::write(
+        project.join("fbhash.yaml"),
+        "database: index/database.json\nstate: index/state.json\nnumber: 1\n",
+    )?;
+    std::fs::create_dir(project.join("index"))?;
+    let testdata = std::env::current_dir()?.join("testdata");
+    let query_file = testdata.join("testfile-yes.bin");
+    // Without a configuration file of the user, the project file is found.
+    let in_project = || -> Result<assert_cmd::Command, Box<dyn std::error::Error>> {
+        let mut command = fbhash()?;
+        command
+            .current_dir(&nested)
+            .env("XDG_CONFIG_HOME", dir.path().join("config"))
+            .env_remove("FBHASH_CONFIG");
+        Ok(assert_cmd::Command::from_std(command))
+    };
+
+    in_project()?.arg("index").arg(&testdata).assert().success();
+    assert!(project.join("index").join("database.json").exists());
+    assert!(project.join("index").join("state.json").exists());
+
+    in_project()?
+        .arg("query")
+        .arg(&query_file)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Results: 1\n"));
+    in_project()?
+        .env("FBHASH_NUMBER", "2")
+        .arg("query")
+        .arg(&query_file)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Results: 2\n"));
+    in_project()?
+        .env("FBHASH_NUMBER", "2")
+        .arg("query")
+        .arg("-n=3")
+        .arg(&query_file)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Results: 3\n"));
+    in_project()?
+        .env("FBHASH_NUMBR", "2")
+        .arg("query")
+        .arg(&query_file)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Results: 1\n"))
+        .stderr(predicate::str::contains(
+            "Ignoring FBHASH_NUMBR: there is no option --numbr",
+        ));
+
+    // The state of the project does not make compare weigh the chunks, and
+    // the number of results is not the number of chunks explain lists.
+    let pair = dir.path().join("pair");
+    std::fs::create_dir(&pair)?;
+    let a = pair.join("a");
+    let b = pair.join("b");
+    std::fs::write(&a, "the same beginning, then something else")?;
+    std::fs::write(&b, "the same beginning, and another ending")?;
+    in_project()?
+        .arg("compare")
+        .arg(&a)
+        .arg(&b)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("(0.4308202184276645)"));
+    let uniform = fbhash()?
+        .arg("compare-dirs")
+        .arg(&pair)
+        .arg(&pair)
+        .output()?;
+    in_project()?
+        .arg("compare-dirs")
+        .arg(&pair)
+        .arg(&pair)
+        .assert()
+        .success()
+        .stdout(uniform.stdout);
+    let explained = fbhash()?
+        .arg("explain")
+        .arg("--state")
+        .arg(project.join("index").join("state.json"))
+        .arg(&query_file)
+        .arg(&query_file)
+        .output()?;
+    in_project()?
+        .arg("explain")
+        .arg(&query_file)
+        .arg(&query_file)
+        .assert()
+        .success()
+        .stdout(explained.stdout);
+
+    std::fs::write(project.join("fbhash.yaml"), "numbr: 2\n")?;
+    in_project()?
+        .arg("query")
+        .arg(&query_file)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "fbhash.yaml: there is no option --numbr",
+        ));
+
+    dir.close()?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+#[test]
+fn test_cross_query_requires_both_indexes() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cross_command = fbhash()?;
+    cross_command
+        .arg("cross-query")
+        .arg("--reference")
+        .arg("database.json");
+    cross_command
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "the following required arguments were not provided",
+        ));
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+#[test]
+fn test_index_rejects_empty_lsh_bands() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let mut index_command = fbhash()?;
+    index_command
+        .arg("index")
+        .arg("--state")
+        .arg(dir.path().join("state.json"))
+        .arg("--database")
+        .arg(dir.path().join("database.json"))
+        .arg("--lsh-bands=4")
+        .arg("--lsh-rows=0")
+        .arg("testdata");
+    index_command
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "invalid value '0' for '--lsh-rows <ROWS>'",
+        ));
+    dir.close()?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+#[test]
+fn test_index_blocks_rejects_small_blocks() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let mut index_command = fbhash()?;
+    index_command
+        .arg("index-blocks")
+        .arg("--state")
+        .arg(dir.path().join("state.json"))
+        .arg("--database")
+        .arg(dir.path().join("database.json"))
+        .arg("--block-size=6")
+        .arg("testdata/testfile-yes.bin");
+    index_command
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "invalid value '6' for '--block-size <BYTES>'",
+        ));
+    dir.close()?;
+    Ok(())
+}